@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::borrow::Cow;
 use std::io::BufRead;
+use std::net::SocketAddr;
 
 use url::Url;
 
@@ -12,6 +13,15 @@ pub enum HttpProtocolVersion {
     H1_1,
 }
 
+impl HttpProtocolVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpProtocolVersion::H1_0 => "HTTP/1.0",
+            HttpProtocolVersion::H1_1 => "HTTP/1.1",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Method {
     Get,
@@ -54,11 +64,20 @@ pub enum Header {
     ContentLength,
     Authorization,
     CacheControl,
+    Location,
+    Forwarded,
+    XForwardedFor,
+    XForwardedProto,
+    XForwardedHost,
+    XRequestId,
+    TraceParent,
+    ContentEncoding,
+    GitProtocol,
     Other(Cow<'static, [u8]>),
 }
 
 impl Header {
-    pub fn as_header_string(&self) -> Cow<[u8]> {
+    pub fn as_header_string(&self) -> Cow<'_, [u8]> {
         match self {
             Header::Host => Cow::Borrowed(b"Host"),
             Header::UserAgent => Cow::Borrowed(b"User-Agent"),
@@ -67,6 +86,15 @@ impl Header {
             Header::Accept => Cow::Borrowed(b"Accept"),
             Header::Authorization => Cow::Borrowed(b"Authorization"),
             Header::CacheControl => Cow::Borrowed(b"Cache-Control"),
+            Header::Location => Cow::Borrowed(b"Location"),
+            Header::Forwarded => Cow::Borrowed(b"Forwarded"),
+            Header::XForwardedFor => Cow::Borrowed(b"X-Forwarded-For"),
+            Header::XForwardedProto => Cow::Borrowed(b"X-Forwarded-Proto"),
+            Header::XForwardedHost => Cow::Borrowed(b"X-Forwarded-Host"),
+            Header::XRequestId => Cow::Borrowed(b"X-Request-Id"),
+            Header::TraceParent => Cow::Borrowed(b"traceparent"),
+            Header::ContentEncoding => Cow::Borrowed(b"Content-Encoding"),
+            Header::GitProtocol => Cow::Borrowed(b"Git-Protocol"),
             Header::Other(s) => s.clone(),
         }
     }
@@ -106,9 +134,16 @@ pub trait Request {
     fn method(&self) -> Method;
     fn path(&self) -> &str;
     fn query_string(&self) -> Option<&str>;
-    fn query_pairs(&self) -> Vec<(Cow<str>, Cow<str>)>;
-    fn query_first_value(&self, key: &str) -> Option<Cow<str>>;
+    fn query_pairs(&self) -> Vec<(Cow<'_, str>, Cow<'_, str>)>;
+    fn query_first_value(&self, key: &str) -> Option<Cow<'_, str>>;
     fn headers(&self) -> &Headers;
     fn read_body(&mut self) -> Result<Option<Vec<u8>>, std::io::Error>;
     fn take_body(&mut self) -> Option<Box<dyn BufRead + Send>>;
+    /// The bind address (as given to `serve_all`) of the listener this request arrived on.
+    fn listener(&self) -> &str;
+    /// Address of the peer on the other end of the connection. When running behind a
+    /// reverse proxy this is the proxy, not the client.
+    fn remote_addr(&self) -> Option<SocketAddr>;
+    /// Address of the local socket the request was received on.
+    fn local_addr(&self) -> Option<SocketAddr>;
 }
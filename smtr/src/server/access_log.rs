@@ -0,0 +1,66 @@
+use std::{
+    fmt,
+    io::{self, Write},
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+
+use crate::Method;
+
+/// One line of the access log, handed to an `AccessLog` once a response is finished.
+#[derive(Debug, Clone)]
+pub struct AccessRecord {
+    /// When the connection was accepted.
+    pub time: SystemTime,
+    pub client_addr: Option<IpAddr>,
+    /// `None` when the request couldn't be parsed far enough to tell.
+    pub method: Option<Method>,
+    /// Path including any query string.
+    pub path: Option<String>,
+    pub protocol: Option<&'static str>,
+    pub user_agent: Option<String>,
+    pub principal: Option<String>,
+    pub status: Option<u16>,
+    /// Body bytes written (or everything written, for raw responses).
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+/// Somewhere to send access log records. Set one on `ServerConfig::access_log`.
+pub trait AccessLog: Send + Sync {
+    fn log(&self, record: &AccessRecord);
+}
+
+impl fmt::Debug for dyn AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AccessLog")
+    }
+}
+
+/// Counts the bytes that make it into the underlying stream.
+pub(crate) struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W> CountingWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        CountingWriter { inner, count: 0 }
+    }
+
+    pub(crate) fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
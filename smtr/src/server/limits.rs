@@ -0,0 +1,103 @@
+use std::{
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use super::{AccessLog, Connection, ServerStats};
+
+/// Limits applied to every connection. Each maps onto a distinct response:
+///  * `header_read_timeout` - the request line and headers must arrive within this (408)
+///  * `body_idle_timeout` - the longest we'll wait for the next chunk of body (408)
+///  * `request_deadline` - the whole request, body included, must arrive within this (408)
+///  * `write_timeout` - the longest a single write of the response may block
+///  * `max_header_bytes` - total size of the request line and headers (431)
+///  * `max_body_bytes` - largest `Content-Length` we'll accept (413)
+///
+/// Every finished response is also reported to `access_log`, if one is set, and connection
+/// counts are kept in `stats`.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub header_read_timeout: Duration,
+    pub body_idle_timeout: Duration,
+    pub request_deadline: Duration,
+    pub write_timeout: Duration,
+    pub max_header_bytes: usize,
+    pub max_body_bytes: u64,
+    pub access_log: Option<Arc<dyn AccessLog>>,
+    pub stats: Arc<ServerStats>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            header_read_timeout: Duration::from_secs(10),
+            body_idle_timeout: Duration::from_secs(30),
+            request_deadline: Duration::from_secs(300),
+            write_timeout: Duration::from_secs(30),
+            max_header_bytes: 16 * 1024,
+            max_body_bytes: 10_000,
+            access_log: None,
+            stats: Arc::default(),
+        }
+    }
+}
+
+/// Wraps a connection so that reads honour the header and request deadlines from
+/// `ServerConfig`. Any timeout surfaces as an `io::ErrorKind::TimedOut` error, including
+/// those hit while a handler is reading the body.
+pub(crate) struct DeadlineStream {
+    conn: Connection,
+    started: Instant,
+    header_read_timeout: Duration,
+    body_idle_timeout: Duration,
+    request_deadline: Duration,
+    headers_read: Arc<AtomicBool>,
+}
+
+impl DeadlineStream {
+    pub(crate) fn new(conn: Connection, config: &ServerConfig) -> (Self, Arc<AtomicBool>) {
+        let headers_read = Arc::new(AtomicBool::new(false));
+        let stream = DeadlineStream {
+            conn,
+            started: Instant::now(),
+            header_read_timeout: config.header_read_timeout,
+            body_idle_timeout: config.body_idle_timeout,
+            request_deadline: config.request_deadline,
+            headers_read: headers_read.clone(),
+        };
+        (stream, headers_read)
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (deadline, idle, what) = if self.headers_read.load(Ordering::Relaxed) {
+            (self.request_deadline, self.body_idle_timeout, "request body")
+        } else {
+            (self.header_read_timeout, self.header_read_timeout, "request headers")
+        };
+
+        let remaining = deadline
+            .checked_sub(self.started.elapsed())
+            .filter(|r| !r.is_zero())
+            .ok_or_else(|| timed_out(what))?;
+
+        self.conn.set_read_timeout(Some(remaining.min(idle)))?;
+        self.conn.read(buf).map_err(|e| match e.kind() {
+            // Platforms disagree on which of these a socket timeout produces
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => timed_out(what),
+            _ => e,
+        })
+    }
+}
+
+fn timed_out(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("Timed out reading {}", what),
+    )
+}
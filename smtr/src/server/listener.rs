@@ -0,0 +1,292 @@
+use std::{
+    env,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::os::unix::{
+    fs::FileTypeExt,
+    io::{FromRawFd, IntoRawFd, RawFd},
+    net::{UnixListener, UnixStream},
+};
+
+use url::Url;
+
+use super::BindError;
+
+/// Where to listen for connections:
+///  * `host:port` - a TCP socket
+///  * `unix:/path/to/socket` - a Unix domain socket
+///  * `systemd` - a socket handed over by systemd (`LISTEN_FDS`/`LISTEN_PID`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindAddress {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    #[cfg(unix)]
+    Systemd,
+}
+
+impl BindAddress {
+    pub fn parse(address: &str) -> Result<Self, BindError> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return Ok(BindAddress::Unix(PathBuf::from(path)));
+            #[cfg(not(unix))]
+            return Err(BindError::UnsupportedBindAddress(path.to_string()));
+        }
+        if address == "systemd" {
+            #[cfg(unix)]
+            return Ok(BindAddress::Systemd);
+            #[cfg(not(unix))]
+            return Err(BindError::UnsupportedBindAddress(address.to_string()));
+        }
+        Ok(BindAddress::Tcp(address.to_string()))
+    }
+}
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds `address`. This usually yields exactly one listener, but systemd may hand us
+    /// several sockets at once.
+    pub(crate) fn bind(address: &BindAddress) -> Result<Vec<Self>, BindError> {
+        match address {
+            BindAddress::Tcp(addr) => TcpListener::bind(addr)
+                .map(|l| vec![Listener::Tcp(l)])
+                .map_err(BindError::InvalidBindAddress),
+            #[cfg(unix)]
+            BindAddress::Unix(path) => {
+                // A socket file left behind by a previous run would stop us binding
+                if let Ok(meta) = std::fs::symlink_metadata(path) {
+                    if meta.file_type().is_socket() {
+                        log::debug!("Removing stale socket at {}", path.to_string_lossy());
+                        std::fs::remove_file(path).map_err(BindError::InvalidBindAddress)?;
+                    }
+                }
+                UnixListener::bind(path)
+                    .map(|l| vec![Listener::Unix(l)])
+                    .map_err(BindError::InvalidBindAddress)
+            }
+            #[cfg(unix)]
+            BindAddress::Systemd => {
+                let fds = systemd_listen_fds()?;
+                log::debug!("Using {} socket(s) passed in by systemd", fds.len());
+                // Safety: systemd hands these file descriptors to us, and we take ownership
+                // of each exactly once (the environment is cleared after reading it).
+                Ok(fds
+                    .into_iter()
+                    .map(|fd| unsafe { Listener::from_raw_fd(fd) })
+                    .collect())
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        let unix = UnixListener::from_raw_fd(fd);
+        if unix.local_addr().is_ok() {
+            return Listener::Unix(unix);
+        }
+        // local_addr() fails on anything that isn't AF_UNIX, so this must be an inet socket
+        Listener::Tcp(TcpListener::from_raw_fd(unix.into_raw_fd()))
+    }
+
+    /// What this listener is bound to, as `host:port` or `unix:/path`.
+    pub(crate) fn local_address(&self) -> String {
+        match self {
+            Listener::Tcp(l) => l
+                .local_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| String::from("unknown")),
+            #[cfg(unix)]
+            Listener::Unix(l) => match l.local_addr().ok().and_then(|a| a.as_pathname().map(|p| p.to_path_buf())) {
+                Some(path) => format!("unix:{}", path.to_string_lossy()),
+                None => String::from("unix:"),
+            },
+        }
+    }
+
+    /// The url that request paths are resolved against.
+    pub(crate) fn base_url(&self) -> Result<Url, BindError> {
+        match self {
+            Listener::Tcp(l) => {
+                let addr = l.local_addr().map_err(BindError::HttpListenError)?;
+                Url::parse(&format!("http://{}", addr)).map_err(BindError::InvalidBindUrl)
+            }
+            #[cfg(unix)]
+            Listener::Unix(_) => Url::parse("http://localhost").map_err(BindError::InvalidBindUrl),
+        }
+    }
+
+    pub(crate) fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(l) => l.accept().map(|(s, _)| Connection::Tcp(s)),
+            #[cfg(unix)]
+            Listener::Unix(l) => l.accept().map(|(s, _)| Connection::Unix(s)),
+        }
+    }
+}
+
+/// Reads the sockets passed by systemd socket activation (see `sd_listen_fds(3)`).
+#[cfg(unix)]
+fn systemd_listen_fds() -> Result<Vec<RawFd>, BindError> {
+    const SD_LISTEN_FDS_START: RawFd = 3;
+
+    let pid = env::var("LISTEN_PID").ok().and_then(|p| p.parse::<u32>().ok());
+    let count = env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<RawFd>().ok());
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    match (pid, count) {
+        (Some(pid), Some(count)) if pid == std::process::id() && count > 0 => {
+            Ok((SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count).collect())
+        }
+        _ => Err(BindError::NoSystemdSockets),
+    }
+}
+
+/// An accepted connection from any of the supported listener types.
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Connection {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Connection::Tcp(s) => s.try_clone().map(Connection::Tcp),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.try_clone().map(Connection::Unix),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(s) => s.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(s) => s.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.set_write_timeout(timeout),
+        }
+    }
+
+    /// The peer's address; Unix domain socket peers don't have one.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Connection::Tcp(s) => s.peer_addr().ok(),
+            #[cfg(unix)]
+            Connection::Unix(_) => None,
+        }
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Connection::Tcp(s) => s.local_addr().ok(),
+            #[cfg(unix)]
+            Connection::Unix(_) => None,
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Connection::Unix(s) => s.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_bind_addresses() {
+        assert_eq!(
+            BindAddress::parse("127.0.0.1:8080").unwrap(),
+            BindAddress::Tcp("127.0.0.1:8080".to_string())
+        );
+        assert_eq!(
+            BindAddress::parse("unix:/run/rotterdam.sock").unwrap(),
+            BindAddress::Unix(PathBuf::from("/run/rotterdam.sock"))
+        );
+        assert_eq!(BindAddress::parse("systemd").unwrap(), BindAddress::Systemd);
+    }
+
+    #[test]
+    fn serves_requests_over_unix_socket() {
+        let dir = env::temp_dir().join(format!("smtr-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.sock");
+
+        let listener = Listener::bind(&BindAddress::Unix(path.clone()))
+            .unwrap()
+            .remove(0);
+        let mut client = UnixStream::connect(&path).unwrap();
+        let mut conn = listener.accept().unwrap();
+
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        conn.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        assert_eq!(conn.peer_addr(), None);
+        assert_eq!(listener.local_address(), format!("unix:{}", path.to_string_lossy()));
+
+        // Binding again replaces the stale socket rather than failing
+        drop(listener);
+        Listener::bind(&BindAddress::Unix(path)).unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn base_url_brackets_ipv6_addresses() {
+        let listener = match Listener::bind(&BindAddress::Tcp("[::1]:0".to_string())) {
+            Ok(mut l) => l.remove(0),
+            Err(_) => return, // no IPv6 loopback in this environment
+        };
+
+        let url = listener.base_url().unwrap();
+
+        assert_eq!(url.host_str(), Some("[::1]"));
+        assert_eq!(url.join("/hello").unwrap().path(), "/hello");
+        assert!(listener.local_address().starts_with("[::1]:"));
+    }
+}
@@ -1,76 +1,211 @@
-use core::panic;
 use std::{
     io::{BufRead, BufReader, BufWriter, Cursor},
     marker::PhantomData,
-    net::TcpStream,
-    sync::mpsc,
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::Ordering, mpsc, Arc},
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 use thiserror::Error;
 
-use std::{
-    io::{self, Read, Write},
-    net::TcpListener,
-};
+use std::io::{self, Read, Write};
 
 use super::*;
 
-pub type TcpResponseWriter = ResponseWriter<'static, BufWriter<TcpStream>>;
+mod access_log;
+mod limits;
+mod listener;
+mod stats;
+
+pub use access_log::{AccessLog, AccessRecord};
+pub use limits::ServerConfig;
+pub use listener::{BindAddress, Connection};
+pub use stats::ServerStats;
+use access_log::CountingWriter;
+use limits::DeadlineStream;
+use listener::Listener;
+use stats::ConnectionGuard;
 
+pub type ConnectionResponseWriter = ResponseWriter<'static, BufWriter<Connection>>;
+
+/// Starts listening on `bind_address`, which may be a `host:port`, a `unix:/path` or
+/// `systemd` to pick up a socket passed in through systemd socket activation.
 pub fn serve(
     bind_address: &str,
-) -> Result<mpsc::Receiver<(impl Request, TcpResponseWriter)>, BindError> {
-    let base_url = Url::parse(&format!("http://{}", bind_address))
-        .map_err(BindError::InvalidBindUrl)?;
-    let listener = TcpListener::bind(bind_address.to_string())
-        .map_err(BindError::InvalidBindAddress)?;
-
-    let (tx, rx) = mpsc::channel();
-
-    thread::spawn(move || {
-        for stream in listener.incoming() {
-            let stream = stream.expect("Listener thread has died");
-            stream
-                .set_read_timeout(Some(Duration::from_millis(500)))
-                .expect("Setting socket read timeout");
-
-            let response = match stream.try_clone() {
-                Err(e) => {
-                    log::error!("Unable to clone stream objects for response: {:?}", e);
-                    continue;
-                }
-                Ok(s) => new_response_writer_1_0(s),
-            };
+) -> Result<mpsc::Receiver<(impl Request, ConnectionResponseWriter)>, BindError> {
+    serve_all(&[bind_address])
+}
 
-            let request = parse_request(&base_url, stream);
+/// Like `serve`, but listens on each of `bind_addresses` at once, feeding requests from all
+/// of them into a single channel. `Request::listener` tells them apart.
+pub fn serve_all(
+    bind_addresses: &[&str],
+) -> Result<mpsc::Receiver<(impl Request, ConnectionResponseWriter)>, BindError> {
+    serve_all_with_config(bind_addresses, ServerConfig::default())
+}
 
-            let handle_error = |mut responder: ResponseWriter<_>, code: u16| {
-                let _ = responder.send_response(Response::err(code));
-            };
+/// Like `serve_all`, with explicit timeouts and size limits.
+pub fn serve_all_with_config(
+    bind_addresses: &[&str],
+    config: ServerConfig,
+) -> Result<mpsc::Receiver<(impl Request, ConnectionResponseWriter)>, BindError> {
+    Ok(bind_all(bind_addresses)?.serve(config))
+}
 
-            match request {
-                Err(HttpError::StreamError(e)) => {
-                    log::error!("Got error reading http request: {:?}", e);
-                }
-                Err(HttpError::ServerError(e)) => {
-                    log::error!("Server error reading http request: {:?}", e);
-                    handle_error(response, 500);
-                }
-                Err(HttpError::ClientError(code, reason)) => {
-                    log::debug!(
-                        "Should respond to client with {} (reason: {})",
-                        code,
-                        reason
-                    );
-                    handle_error(response, code);
-                }
-                Ok(req) => tx.send((req, response)).expect("Request handler has died"),
+/// Binds each of `bind_addresses` without accepting any connections yet, so the caller can
+/// find out what was actually bound (a port picked by the OS, sockets from systemd) first.
+pub fn bind_all(bind_addresses: &[&str]) -> Result<Bound, BindError> {
+    let mut listeners = Vec::new();
+    for &bind_address in bind_addresses {
+        let name: Arc<str> = Arc::from(bind_address);
+        for listener in Listener::bind(&BindAddress::parse(bind_address)?)? {
+            let base_url = listener.base_url()?;
+            listeners.push((name.clone(), listener, base_url));
+        }
+    }
+    Ok(Bound { listeners })
+}
+
+/// Listeners bound by `bind_all`, waiting to be served.
+pub struct Bound {
+    listeners: Vec<(Arc<str>, Listener, Url)>,
+}
+
+impl Bound {
+    /// What each listener is bound to, along with the bind address it came from: a
+    /// `host:port` for TCP sockets, `unix:/path` for Unix domain sockets.
+    pub fn local_addresses(&self) -> Vec<(&str, String)> {
+        self.listeners
+            .iter()
+            .map(|(name, listener, _)| (&**name, listener.local_address()))
+            .collect()
+    }
+
+    /// Starts accepting connections on every listener, feeding requests from all of them into
+    /// a single channel.
+    pub fn serve(
+        self,
+        config: ServerConfig,
+    ) -> mpsc::Receiver<(impl Request, ConnectionResponseWriter)> {
+        let (tx, rx) = mpsc::channel();
+        let config = Arc::new(config);
+
+        for (name, listener, base_url) in self.listeners {
+            let tx = tx.clone();
+            let config = config.clone();
+            thread::spawn(move || accept_loop(name, listener, base_url, config, tx));
+        }
+
+        rx
+    }
+}
+
+type RequestSender = mpsc::Sender<(ReceivedRequest, ConnectionResponseWriter)>;
+
+/// How long to wait after failing to accept a connection before trying again.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+fn accept_loop(
+    name: Arc<str>,
+    listener: Listener,
+    base_url: Url,
+    config: Arc<ServerConfig>,
+    tx: RequestSender,
+) {
+    let base_url = Arc::new(base_url);
+    loop {
+        // Accepting fails for reasons that pass (out of file descriptors, a connection reset
+        // before we got to it), so wait a moment and carry on rather than stop listening
+        let stream = match listener.accept() {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::error!("Unable to accept a connection on {}: {}", name, e);
+                thread::sleep(ACCEPT_BACKOFF);
+                continue;
             }
+        };
+
+        // Headers are read on a thread per connection so a slow client can't hold up the
+        // accept loop; the header read timeout bounds how long each of these lives.
+        let (name, base_url, config, tx) =
+            (name.clone(), base_url.clone(), config.clone(), tx.clone());
+        thread::spawn(move || read_request(&name, stream, &base_url, &config, &tx));
+    }
+}
+
+fn read_request(
+    name: &Arc<str>,
+    stream: Connection,
+    base_url: &Url,
+    config: &ServerConfig,
+    tx: &RequestSender,
+) {
+    let response = match stream
+        .set_write_timeout(Some(config.write_timeout))
+        .and_then(|_| stream.try_clone())
+    {
+        Err(e) => {
+            log::error!("Unable to clone stream objects for response: {:?}", e);
+            return;
         }
+        Ok(s) => new_response_writer_1_0(s),
+    };
+    let mut response = response;
+    response.connection = Some(config.stats.connection_opened());
+
+    let remote_addr = stream.peer_addr();
+    let local_addr = stream.local_addr();
+    response.access_log = config.access_log.clone();
+    response.record.client_addr = remote_addr.map(|a| a.ip());
+
+    let (stream, headers_read) = DeadlineStream::new(stream, config);
+    let request = parse_request(base_url, config, stream).map(|mut req| {
+        req.listener = name.clone();
+        req.remote_addr = remote_addr;
+        req.local_addr = local_addr;
+        req
     });
+    headers_read.store(true, Ordering::Relaxed);
+
+    if let Ok(req) = &request {
+        response.record.method = Some(req.method);
+        response.record.path = Some(match req.url.query() {
+            Some(q) => format!("{}?{}", req.url.path(), q),
+            None => req.url.path().to_string(),
+        });
+        response.record.protocol = Some(req.http_version.as_str());
+        response.record.user_agent = req
+            .headers
+            .get(Header::UserAgent)
+            .map(|ua| String::from_utf8_lossy(ua).to_string());
+    }
 
-    Ok(rx)
+    let handle_error = |mut responder: ResponseWriter<_>, code: u16| {
+        let _ = responder.send_response(Response::err(code));
+    };
+
+    match request {
+        Err(HttpError::StreamError(e)) if e.kind() == io::ErrorKind::TimedOut => {
+            log::debug!("Should respond to client with 408 (reason: {})", e);
+            handle_error(response, 408);
+        }
+        Err(HttpError::StreamError(e)) => {
+            log::error!("Got error reading http request: {:?}", e);
+        }
+        Err(HttpError::ServerError(e)) => {
+            log::error!("Server error reading http request: {:?}", e);
+            handle_error(response, 500);
+        }
+        Err(HttpError::ClientError(code, reason)) => {
+            log::debug!(
+                "Should respond to client with {} (reason: {})",
+                code,
+                reason
+            );
+            handle_error(response, code);
+        }
+        Ok(req) => tx.send((req, response)).expect("Request handler has died"),
+    }
 }
 
 #[derive(Debug, Error)]
@@ -81,6 +216,10 @@ pub enum BindError {
     InvalidBindUrl(#[from] url::ParseError),
     #[error("Bind address cannot be base for http url")]
     InvalidBindAddress(#[source] io::Error),
+    #[error("Bind address not supported on this platform: {0}")]
+    UnsupportedBindAddress(String),
+    #[error("No sockets passed in from systemd (LISTEN_FDS/LISTEN_PID not set for this process)")]
+    NoSystemdSockets,
 }
 
 #[derive(Debug, Error)]
@@ -95,9 +234,13 @@ pub enum HttpError {
 
 struct ReceivedRequest {
     method: Method,
+    http_version: HttpProtocolVersion,
     headers: Headers,
     url: Url,
     body: Option<Box<dyn BufRead + Send>>,
+    listener: Arc<str>,
+    remote_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
 }
 
 impl Drop for ReceivedRequest {
@@ -120,14 +263,14 @@ impl Request for ReceivedRequest {
     }
 
     fn path(&self) -> &str {
-        &self.url.path()
+        self.url.path()
     }
 
     fn query_string(&self) -> Option<&str> {
         self.url.query()
     }
 
-    fn query_pairs(&self) -> Vec<(Cow<str>, Cow<str>)> {
+    fn query_pairs(&self) -> Vec<(Cow<'_, str>, Cow<'_, str>)> {
         let mut pairs = Vec::new();
         for p in self.url.query_pairs() {
             pairs.push((p.0.clone(), p.1.clone()));
@@ -135,7 +278,7 @@ impl Request for ReceivedRequest {
         pairs
     }
 
-    fn query_first_value(&self, key: &str) -> Option<Cow<str>> {
+    fn query_first_value(&self, key: &str) -> Option<Cow<'_, str>> {
         for (k, v) in self.query_pairs() {
             if k == key {
                 return Some(v.clone());
@@ -162,6 +305,18 @@ impl Request for ReceivedRequest {
     fn take_body(&mut self) -> Option<Box<dyn BufRead + Send>> {
         self.body.take()
     }
+
+    fn listener(&self) -> &str {
+        &self.listener
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -175,10 +330,16 @@ pub struct ResponseWriter<'a, Stream>
 where
     Stream: 'a + Write + Send,
 {
-    stream: Stream,
+    stream: CountingWriter<Stream>,
     state: ResponseState,
     _lifetime: PhantomData<&'a Stream>,
     status: Option<u16>,
+    body_start: u64,
+    started: Instant,
+    record: AccessRecord,
+    access_log: Option<Arc<dyn AccessLog>>,
+    connection: Option<ConnectionGuard>,
+    default_headers: Headers,
 }
 
 fn new_response_writer_1_0<'a, Stream>(s: Stream) -> ResponseWriter<'a, BufWriter<Stream>>
@@ -186,10 +347,27 @@ where
     Stream: Write + Send + 'a,
 {
     ResponseWriter {
-        stream: BufWriter::new(s),
+        stream: CountingWriter::new(BufWriter::new(s)),
         state: ResponseState::Status,
         _lifetime: PhantomData,
         status: None,
+        body_start: 0,
+        started: Instant::now(),
+        record: AccessRecord {
+            time: SystemTime::now(),
+            client_addr: None,
+            method: None,
+            path: None,
+            protocol: None,
+            user_agent: None,
+            principal: None,
+            status: None,
+            bytes: 0,
+            duration: Duration::default(),
+        },
+        access_log: None,
+        connection: None,
+        default_headers: Headers::default(),
     }
 }
 
@@ -199,6 +377,13 @@ where
 {
     fn drop(&mut self) {
         log::debug!("{}", self.status.unwrap_or(0));
+
+        if let Some(access_log) = self.access_log.take() {
+            self.record.status = self.status;
+            self.record.bytes = self.stream.count() - self.body_start;
+            self.record.duration = self.started.elapsed();
+            access_log.log(&self.record);
+        }
     }
 }
 
@@ -218,7 +403,7 @@ where
         Ok(())
     }
 
-    fn set_headers(&mut self, headers: Headers) -> Result<(), io::Error> {
+    fn set_headers(&mut self, mut headers: Headers) -> Result<(), io::Error> {
         match self.state {
             ResponseState::Status => {
                 panic!("Invalid state: status code has not yet been sent; cannot start headers")
@@ -229,6 +414,12 @@ where
             }
         };
 
+        for (h_name, h_value) in self.default_headers.iter() {
+            if headers.get(h_name.clone()).is_none() {
+                headers.set(h_name.clone(), h_value.to_vec());
+            }
+        }
+
         for (h_name, h_value) in headers.iter() {
             log::trace!(
                 "Writing header: {}:{}",
@@ -243,6 +434,7 @@ where
         write!(self.stream, "\r\n")?;
 
         self.state = ResponseState::Body;
+        self.body_start = self.stream.count();
 
         Ok(())
     }
@@ -253,7 +445,10 @@ where
                 log::error!("Invalid state: status code has not yet been sent; cannot start body");
                 panic!("Invalid state: status code has not yet been sent; cannot start body")
             }
-            ResponseState::Headers => write!(self.stream, "\r\n")?,
+            ResponseState::Headers => {
+                write!(self.stream, "\r\n")?;
+                self.body_start = self.stream.count();
+            }
             ResponseState::Body => (),
         }
         self.state = ResponseState::Body;
@@ -281,9 +476,38 @@ where
         Ok(())
     }
 
+    /// Direct access to the connection, for handlers that write their own status line and
+    /// headers. Use `set_raw_status` so the access log knows how it went.
     pub fn raw_writer(&mut self) -> &mut dyn Write {
         &mut self.stream
     }
+
+    pub fn set_raw_status(&mut self, status: u16) {
+        self.status = Some(status);
+    }
+
+    /// Adds a header to whichever response ends up being sent, unless that response sets
+    /// the header itself. Raw responses need to write these out themselves.
+    pub fn set_default_header<V>(&mut self, key: Header, value: V)
+    where
+        V: Into<Cow<'static, [u8]>>,
+    {
+        self.default_headers.set(key, value);
+    }
+
+    pub fn default_headers(&self) -> &Headers {
+        &self.default_headers
+    }
+
+    /// Overrides the client address recorded in the access log (e.g. when behind a proxy).
+    pub fn set_client_addr(&mut self, addr: Option<IpAddr>) {
+        self.record.client_addr = addr;
+    }
+
+    /// Records who the request was authenticated as, for the access log.
+    pub fn set_principal(&mut self, principal: &str) {
+        self.record.principal = Some(principal.to_string());
+    }
 }
 
 
@@ -317,6 +541,15 @@ impl Response {
         }
     }
 
+    /// A bodyless redirect (301, 302, 303, 307 or 308) to `location`.
+    pub fn redirect(status: u16, location: &str) -> Self {
+        debug_assert!((300..400).contains(&status), "Not a redirect status: {}", status);
+        Response::builder(status)
+            .header(Header::Location, location.as_bytes().to_vec())
+            .header(Header::ContentLength, &b"0"[..])
+            .build()
+    }
+
     pub fn builder(status: u16) -> ResponseBuilder {
         ResponseBuilder {
             status,
@@ -327,6 +560,15 @@ impl Response {
 }
 
 impl ResponseBuilder {
+    /// Sets any header, replacing an earlier value for the same header.
+    pub fn header<V>(mut self, key: Header, value: V) -> Self
+    where
+        V: Into<Cow<'static, [u8]>>,
+    {
+        self.headers.set(key, value);
+        self
+    }
+
     pub fn content_type(mut self, content_type: &str) -> Self {
         self.headers
             .set(Header::ContentType, content_type.as_bytes().to_vec());
@@ -358,6 +600,20 @@ impl ResponseBuilder {
         self
     }
 
+    /// Streams the body from `reader`. Without a `len`, the body runs until the connection
+    /// closes.
+    pub fn body_reader<R>(mut self, reader: R, len: Option<u64>) -> Self
+    where
+        R: Read + 'static,
+    {
+        if let Some(len) = len {
+            self.headers
+                .set(Header::ContentLength, len.to_string().into_bytes());
+        }
+        self.stream = Some(Box::new(reader));
+        self
+    }
+
     pub fn body_from_string(self, s: &str) -> Self {
         let bs = s.as_bytes().to_vec();
         self.body(bs)
@@ -384,6 +640,9 @@ where
     let mut found = false;
     while buf.len() < line_len_limit {
         let cur = reader.fill_buf()?;
+        if cur.is_empty() {
+            return Err(HttpError::ClientError(400, "Connection closed mid-request"));
+        }
         if let Some(newline) = cur.iter().position(|&b| b == needle) {
             buf.extend_from_slice(&cur[..newline]);
             reader.consume(newline + 1);
@@ -399,18 +658,26 @@ where
     if found {
         Ok(buf)
     } else {
-        Err(HttpError::ClientError(400, "Header line too long"))
+        Err(HttpError::ClientError(431, "Request headers too large"))
     }
 }
 
-fn parse_request<R>(base_url: &Url, stream: R) -> Result<ReceivedRequest, HttpError>
+fn parse_request<R>(
+    base_url: &Url,
+    config: &ServerConfig,
+    stream: R,
+) -> Result<ReceivedRequest, HttpError>
 where
     R: Read + Send + 'static,
 {
     let mut stream = BufReader::new(stream);
+    let mut header_bytes_left = config.max_header_bytes;
 
     let (method, offset) = {
         let buf = stream.fill_buf()?;
+        if buf.len() < 3 {
+            return Err(HttpError::ClientError(400, "Not enough data in first line"));
+        }
 
         match &buf[0..3] {
             b"GET" => (Method::Get, 4),
@@ -422,6 +689,7 @@ where
         }
     };
     stream.consume(offset);
+    header_bytes_left = header_bytes_left.saturating_sub(offset);
     log::trace!("Determined method: {:?}", method);
 
     let (used, path) = {
@@ -441,6 +709,7 @@ where
         )
     };
     stream.consume(used);
+    header_bytes_left = header_bytes_left.saturating_sub(used);
 
     let (used, http_version) = {
         let rest = stream.fill_buf()?;
@@ -470,6 +739,7 @@ where
         (sp_idx, http_ver)
     };
     stream.consume(used);
+    header_bytes_left = header_bytes_left.saturating_sub(used);
 
     log::trace!(
         "Incoming request has http protocol version: {:?}",
@@ -481,10 +751,14 @@ where
     loop {
         if headers.len() > 100 {
             log::warn!("Stopping after first 100 headers");
-            return Err(HttpError::ClientError(400, "Too many headers"));
+            return Err(HttpError::ClientError(431, "Too many headers"));
+        }
+        if header_bytes_left == 0 {
+            return Err(HttpError::ClientError(431, "Request headers too large"));
         }
 
-        let line = read_until_limited(&mut stream, b'\n', 1000)?;
+        let line = read_until_limited(&mut stream, b'\n', header_bytes_left)?;
+        header_bytes_left = header_bytes_left.saturating_sub(line.len() + 1);
         if line.is_empty() || line.iter().all(|b| b.is_ascii_whitespace()) {
             break;
         }
@@ -522,6 +796,14 @@ where
             b"content-length" => Header::ContentLength,
             b"authorization" => Header::Authorization,
             b"user-agent" => Header::UserAgent,
+            b"forwarded" => Header::Forwarded,
+            b"x-forwarded-for" => Header::XForwardedFor,
+            b"x-forwarded-proto" => Header::XForwardedProto,
+            b"x-forwarded-host" => Header::XForwardedHost,
+            b"x-request-id" => Header::XRequestId,
+            b"traceparent" => Header::TraceParent,
+            b"content-encoding" => Header::ContentEncoding,
+            b"git-protocol" => Header::GitProtocol,
             _ => Header::Other(Cow::from(key.to_vec())),
         };
 
@@ -533,8 +815,8 @@ where
             let content_len: u64 = String::from_utf8_lossy(len)
                 .parse()
                 .map_err(|_| HttpError::ClientError(400, "Bad Content-Length"))?;
-            if content_len > 10_000 {
-                return Err(HttpError::ClientError(400, "Oversized Entity Body"));
+            if content_len > config.max_body_bytes {
+                return Err(HttpError::ClientError(413, "Oversized Entity Body"));
             }
 
             let already_read = Cursor::new(stream.buffer().to_vec());
@@ -556,7 +838,16 @@ where
         .join(&path)
         .map_err(|_| HttpError::ClientError(400, "Invalid path"))?;
 
-    Ok(ReceivedRequest { method, headers, url, body })
+    Ok(ReceivedRequest {
+        method,
+        http_version,
+        headers,
+        url,
+        body,
+        listener: Arc::from(""),
+        remote_addr: None,
+        local_addr: None,
+    })
 }
 
 #[cfg(test)]
@@ -570,12 +861,7 @@ mod test {
     where
         UnderlyingStream: Write + Send + 'a,
     {
-        ResponseWriter {
-            stream: BufWriter::new(s),
-            state: ResponseState::Status,
-            _lifetime: PhantomData,
-            status: None,
-        }
+        new_response_writer_1_0(s)
     }
 
     fn base_url() -> Url {
@@ -592,7 +878,7 @@ mod test {
             \r\n",
         );
 
-        let result = parse_request(&base_url(), req);
+        let result = parse_request(&base_url(), &ServerConfig::default(), req);
 
         let result = result.unwrap();
 
@@ -618,7 +904,7 @@ mod test {
             \r\n",
         );
 
-        let result = parse_request(&base_url(), req);
+        let result = parse_request(&base_url(), &ServerConfig::default(), req);
 
         let result = result.unwrap();
 
@@ -646,7 +932,7 @@ mod test {
             Hello world",
         );
 
-        let mut result = parse_request(&base_url(), req).unwrap();
+        let mut result = parse_request(&base_url(), &ServerConfig::default(), req).unwrap();
 
         let mut body = result.take_body().unwrap();
         let mut result = Vec::new();
@@ -655,6 +941,34 @@ mod test {
         assert_eq!(&result, b"Hello world");
     }
 
+    #[test]
+    fn parses_forwarding_headers_case_insensitively() {
+        let req = Cursor::new(
+            b"GET /hello HTTP/1.1\r\n\
+            Host: localhost:8080\r\n\
+            x-forwarded-for: 203.0.113.7\r\n\
+            X-Forwarded-Proto: https\r\n\
+            FORWARDED: for=203.0.113.7;proto=https\r\n\
+            \r\n",
+        );
+
+        let result = parse_request(&base_url(), &ServerConfig::default(), req).unwrap();
+
+        assert_eq!(
+            result.headers.get(Header::XForwardedFor),
+            Some(b"203.0.113.7" as &[u8])
+        );
+        assert_eq!(
+            result.headers.get(Header::XForwardedProto),
+            Some(b"https" as &[u8])
+        );
+        assert_eq!(
+            result.headers.get(Header::Forwarded),
+            Some(b"for=203.0.113.7;proto=https" as &[u8])
+        );
+        assert_eq!(result.remote_addr(), None);
+    }
+
     #[test]
     fn writes_empty_headers_and_body_when_responding_with_okay() {
         let mut bytes = Vec::new();
@@ -662,7 +976,7 @@ mod test {
 
         {
             let mut response = new_response_writer_for_ref(&mut output);
-            let _ = response
+            response
                 .send_response(
                     Response::builder(200)
                         .body_from_string("Hello world")
@@ -682,6 +996,152 @@ mod test {
         );
     }
 
+    #[test]
+    fn tags_requests_with_listener() {
+        use std::os::unix::net::UnixStream;
+
+        let dir = std::env::temp_dir().join(format!("smtr-serve-all-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.sock"), dir.join("b.sock"));
+        let (a_addr, b_addr) = (
+            format!("unix:{}", a.to_string_lossy()),
+            format!("unix:{}", b.to_string_lossy()),
+        );
+
+        let requests = serve_all(&[&a_addr, &b_addr]).unwrap();
+
+        for (path, expected) in [(&b, &b_addr), (&a, &a_addr)] {
+            let mut client = UnixStream::connect(path).unwrap();
+            client
+                .write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let (req, _resp) = requests.recv().unwrap();
+            assert_eq!(req.listener(), expected.as_str());
+            assert_eq!(req.path(), "/hello");
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rejects_oversized_headers() {
+        let config = ServerConfig {
+            max_header_bytes: 64,
+            ..ServerConfig::default()
+        };
+        let req = Cursor::new(
+            b"GET /hello HTTP/1.1\r\n\
+            Host: localhost:8080\r\n\
+            User-Agent: a-rather-long-user-agent/1.0\r\n\
+            \r\n",
+        );
+
+        match parse_request(&base_url(), &config, req) {
+            Err(HttpError::ClientError(431, _)) => {}
+            _ => panic!("Expected 431"),
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_body() {
+        let config = ServerConfig {
+            max_body_bytes: 5,
+            ..ServerConfig::default()
+        };
+        let req = Cursor::new(
+            b"PUT /hello HTTP/1.1\r\n\
+            Content-Length: 11\r\n\
+            \r\n\
+            Hello world",
+        );
+
+        match parse_request(&base_url(), &config, req) {
+            Err(HttpError::ClientError(413, _)) => {}
+            _ => panic!("Expected 413"),
+        }
+    }
+
+    #[test]
+    fn times_out_slow_headers() {
+        use std::os::unix::net::UnixStream;
+        use std::time::Duration;
+
+        let dir = std::env::temp_dir().join(format!("smtr-timeout-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("slow.sock");
+        let config = ServerConfig {
+            header_read_timeout: Duration::from_millis(200),
+            ..ServerConfig::default()
+        };
+
+        let _requests =
+            serve_all_with_config(&[&format!("unix:{}", path.to_string_lossy())], config)
+                .unwrap();
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"GET /hello HTTP/1.1\r\nHost: loc").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.0 408\r\n"), "{}", response);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn writes_default_headers_unless_overridden() {
+        let mut bytes = Vec::new();
+        let mut output = Cursor::new(&mut bytes);
+
+        {
+            let mut response = new_response_writer_for_ref(&mut output);
+            response.set_default_header(Header::XRequestId, b"abc123".to_vec());
+            response.set_default_header(Header::ContentType, b"text/plain".to_vec());
+            response
+                .send_response(Response::builder(404).content_type("text/html").build())
+                .unwrap();
+        }
+
+        let result = String::from_utf8(output.get_ref().to_vec()).unwrap();
+
+        assert!(result.contains("X-Request-Id: abc123\r\n"));
+        assert!(result.contains("Content-Type: text/html\r\n"));
+        assert!(!result.contains("text/plain"));
+    }
+
+    #[test]
+    fn writes_redirects_and_arbitrary_headers() {
+        let mut bytes = Vec::new();
+        let mut output = Cursor::new(&mut bytes);
+
+        {
+            let mut response = new_response_writer_for_ref(&mut output);
+            response
+                .send_response(Response::redirect(302, "https://example.com/a?b=c"))
+                .unwrap();
+        }
+        let result = String::from_utf8(output.get_ref().to_vec()).unwrap();
+        assert!(result.starts_with("HTTP/1.0 302\r\n"));
+        assert!(result.contains("Location: https://example.com/a?b=c\r\n"));
+        assert!(result.contains("Content-Length: 0\r\n"));
+
+        let mut bytes = Vec::new();
+        let mut output = Cursor::new(&mut bytes);
+        {
+            let mut response = new_response_writer_for_ref(&mut output);
+            let r = Response::builder(200)
+                .header(Header::CacheControl, &b"no-store"[..])
+                .header(Header::Other(Cow::Borrowed(b"X-Custom")), b"one".to_vec())
+                .header(Header::Other(Cow::Borrowed(b"X-Custom")), b"two".to_vec())
+                .build();
+            response.send_response(r).unwrap();
+        }
+        let result = String::from_utf8(output.get_ref().to_vec()).unwrap();
+        assert!(result.contains("Cache-Control: no-store\r\n"));
+        assert!(result.contains("X-Custom: two\r\n"));
+        assert!(!result.contains("one"));
+    }
+
     #[test]
     fn read_line_limited_returns_line_excl_newline() {
         let mut input = BufReader::new(Cursor::new(b"line 1\r\nline 2\r\nline 3\n"));
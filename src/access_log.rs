@@ -0,0 +1,199 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+use anyhow::Context;
+use json::JsonValue;
+use smtr::server::{AccessLog, AccessRecord};
+
+use super::Result;
+use crate::time::{civil_time, rfc3339_time};
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AccessLogFormat {
+    /// NCSA combined log format (common log format plus referer and user agent), with the
+    /// authenticated principal in the `authuser` field and the duration (µs) appended.
+    Common,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct AccessLogConfig {
+    pub path: PathBuf,
+    pub format: AccessLogFormat,
+}
+
+
+static REOPEN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_reopen(_signal: libc::c_int) {
+    REOPEN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Access log written to a file. The file is reopened on SIGHUP so that logrotate can
+/// move it out of the way.
+pub(crate) struct FileAccessLog {
+    path: PathBuf,
+    format: AccessLogFormat,
+    file: Mutex<File>,
+}
+
+impl FileAccessLog {
+    pub(crate) fn open(config: &AccessLogConfig) -> Result<Self> {
+        let file = open_log_file(&config.path)?;
+
+        unsafe {
+            libc::signal(libc::SIGHUP, request_reopen as *const () as libc::sighandler_t);
+        }
+
+        Ok(FileAccessLog {
+            path: config.path.clone(),
+            format: config.format,
+            file: Mutex::new(file),
+        })
+    }
+}
+
+fn open_log_file(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Opening access log at {}", path.to_string_lossy()))
+}
+
+impl AccessLog for FileAccessLog {
+    fn log(&self, record: &AccessRecord) {
+        let line = match self.format {
+            AccessLogFormat::Common => format_common(record),
+            AccessLogFormat::Json => format_json(record),
+        };
+
+        let mut file = match self.file.lock() {
+            Ok(f) => f,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if REOPEN_REQUESTED.swap(false, Ordering::SeqCst) {
+            log::info!("Reopening access log at {}", self.path.to_string_lossy());
+            match open_log_file(&self.path) {
+                Ok(f) => *file = f,
+                Err(e) => log::error!("{:?}", e),
+            }
+        }
+
+        if let Err(e) = writeln!(file, "{}", line) {
+            log::error!("Unable to write access log: {}", e);
+        }
+    }
+}
+
+
+/// Hands each record to several access logs.
+pub(crate) struct AccessLogs(pub Vec<Arc<dyn AccessLog>>);
+
+impl AccessLog for AccessLogs {
+    fn log(&self, record: &AccessRecord) {
+        for access_log in self.0.iter() {
+            access_log.log(record);
+        }
+    }
+}
+
+
+fn format_common(r: &AccessRecord) -> String {
+    let request_line = match (&r.method, &r.path) {
+        (Some(method), Some(path)) => format!("{} {} {}", method.as_str(), path, r.protocol.unwrap_or("-")),
+        _ => String::from("-"),
+    };
+
+    format!(
+        "{} - {} [{}] \"{}\" {} {} \"-\" \"{}\" {}",
+        r.client_addr.map(|a| a.to_string()).unwrap_or_else(|| String::from("-")),
+        r.principal.as_deref().unwrap_or("-"),
+        clf_time(r.time),
+        escape(&request_line),
+        r.status.map(|s| s.to_string()).unwrap_or_else(|| String::from("-")),
+        r.bytes,
+        escape(r.user_agent.as_deref().unwrap_or("-")),
+        r.duration.as_micros(),
+    )
+}
+
+fn format_json(r: &AccessRecord) -> String {
+    let mut entry = JsonValue::new_object();
+    entry["time"] = rfc3339_time(r.time).into();
+    entry["client"] = r.client_addr.map(|a| a.to_string()).into();
+    entry["method"] = r.method.map(|m| m.as_str().to_string()).into();
+    entry["path"] = r.path.clone().into();
+    entry["protocol"] = r.protocol.into();
+    entry["status"] = r.status.into();
+    entry["bytes"] = r.bytes.into();
+    entry["duration_ms"] = (r.duration.as_secs_f64() * 1000.0).into();
+    entry["user_agent"] = r.user_agent.clone().into();
+    entry["principal"] = r.principal.clone().into();
+    entry.dump()
+}
+
+fn escape(s: &str) -> String {
+    s.chars()
+        .flat_map(|c| match c {
+            '"' => vec!['\\', '"'],
+            '\\' => vec!['\\', '\\'],
+            c if c.is_control() => format!("\\x{:02x}", c as u32).chars().collect(),
+            c => vec![c],
+        })
+        .collect()
+}
+
+
+fn clf_time(t: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let c = civil_time(t);
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000", c.day, MONTHS[(c.month - 1) as usize], c.year, c.hour, c.minute, c.second)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn record() -> AccessRecord {
+        AccessRecord {
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            client_addr: Some("203.0.113.7".parse().unwrap()),
+            method: Some(smtr::Method::Get),
+            path: Some(String::from("/repo/testrepo/index/info/refs?service=git-upload-pack")),
+            protocol: Some("HTTP/1.1"),
+            user_agent: Some(String::from("git/2.30.2")),
+            principal: None,
+            status: Some(200),
+            bytes: 512,
+            duration: Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn formats_common_log_lines() {
+        assert_eq!(
+            format_common(&record()),
+            "203.0.113.7 - - [10/Oct/2000:13:55:36 +0000] \"GET /repo/testrepo/index/info/refs?service=git-upload-pack HTTP/1.1\" 200 512 \"-\" \"git/2.30.2\" 1500"
+        );
+    }
+
+    #[test]
+    fn formats_json_lines() {
+        let line = json::parse(&format_json(&record())).unwrap();
+        assert_eq!(line["time"], "2000-10-10T13:55:36Z");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["bytes"], 512);
+        assert_eq!(line["client"], "203.0.113.7");
+        assert!(line["principal"].is_null());
+    }
+}
@@ -3,26 +3,75 @@ use crate::config::AppGitConfig;
 use super::config;
 use super::Result;
 use super::git_cgi;
-
-use std::{io::{Write}, process::Command};
-use std::process::{Stdio};
+use super::upload_pack;
+use super::publish;
+use super::owners;
+use super::yank;
+use super::names;
+use super::health;
+use super::trace;
+use super::metrics::Metrics;
+use super::mirror::Mirrors;
+use super::search::Search;
+use super::blob_store::{self, BlobStore, VerifyingReader};
+use super::index;
+use super::index_writer::{Edit, IndexWriter, IndexWriters};
+
+use std::sync::Arc;
 use anyhow::{Context, bail};
 use smtr::{
-    server::{Response, TcpResponseWriter},
-    Method, Request,
+    server::{Response, ConnectionResponseWriter},
+    Header, Method, Request,
 };
 
 
 pub(crate) struct App {
     config: config::AppConfig,
+    metrics: Arc<Metrics>,
+    blobs: Arc<dyn BlobStore>,
+    writers: IndexWriters,
+    mirrors: Mirrors,
+    search: Search,
+}
+
+/// Routes only served on the admin listener, when one is configured.
+const ADMIN_ROUTES: &[&str] = &["token", "metrics", "mirrors"];
+
+/// Routes served on every listener, admin or not.
+const ANY_LISTENER_ROUTES: &[&str] = &["healthz", "readyz"];
+
+/// A stable name for the route `path` is served by, for metrics labels.
+pub(crate) fn route_name(path: &str) -> &'static str {
+    let path = path.split('?').next().unwrap_or(path);
+    let path_parts: Vec<_> = path.split('/').collect();
+    match path_parts.as_slice() {
+        ["", "api", "v1", "token"] => "token",
+        ["", "metrics"] => "metrics",
+        ["", "mirrors"] => "mirrors",
+        ["", "healthz"] => "healthz",
+        ["", "readyz"] => "readyz",
+        ["", "repo", _repo_name, "index", _rest @ ..] => "git",
+        ["", "repo", _repo_name, "api", "v1", "crates", _name, _version, "download"] => "download",
+        ["", "repo", _repo_name, "api", "v1", "crates"] => "search",
+        ["", "repo", _repo_name, "api", "v1", "crates", "new"] => "publish",
+        ["", "repo", _repo_name, "api", "v1", "crates", _name, "owners"] => "owners",
+        ["", "repo", _repo_name, "api", "v1", "crates", _name, _version, "yank"] => "yank",
+        ["", "repo", _repo_name, "api", "v1", "crates", _name, _version, "unyank"] => "unyank",
+        _ => "other",
+    }
 }
 
 impl App {
 
-    pub(crate) fn new(config: config::AppConfig) -> Result<Self> {
+    pub(crate) fn new(config: config::AppConfig, metrics: Arc<Metrics>, blobs: Arc<dyn BlobStore>, writers: IndexWriters, mirrors: Mirrors, search: Search) -> Result<Self> {
 
         let app = App {
-            config: App::ready_config(config)?
+            config,
+            metrics,
+            blobs,
+            writers,
+            mirrors,
+            search,
         };
 
         log::debug!("Initialized with {} repos", app.config.repos.len());
@@ -30,12 +79,84 @@ impl App {
         Ok(app)
     }
 
-    pub(crate) fn handle(&self, req: &mut dyn Request, mut resp: TcpResponseWriter) -> Result<()> {
+    pub(crate) fn handle(&self, req: &mut dyn Request, mut resp: ConnectionResponseWriter) -> Result<()> {
+        let request_id = trace::request_id(req);
+        let _request = trace::begin_request(req, &request_id);
+        resp.set_default_header(Header::XRequestId, request_id.into_bytes());
+
+        let parse_span = trace::span("parse");
+
+        let client = self.config.proxy.client_info(req);
+        resp.set_client_addr(client.addr);
+        log::debug!("Request from {}: {:?} {}", client, req.method(), req.path());
+
         let path_parts: Vec<_> = req.path().split('/').collect();
 
+        // With a dedicated admin listener, admin routes are only served there (and nothing else is)
+        let route = route_name(req.path());
+        let is_admin_route = ADMIN_ROUTES.contains(&route);
+        if let Some(admin_listen) = &self.config.admin_listen {
+            if ! ANY_LISTENER_ROUTES.contains(&route) && is_admin_route != (req.listener() == admin_listen) {
+                log::debug!("Refusing {} on listener {}", req.path(), req.listener());
+                resp.send_response(Response::err(404))?;
+                return Ok(());
+            }
+        }
+
+        drop(parse_span);
+
+        // Repos with readers keep everything under them to those readers: the index, search,
+        // owners and downloads alike
+        if let ["", "repo", repo_name, ..] = path_parts.as_slice() {
+            if let Some((status, detail)) = self.refuse_reader(repo_name, req, &mut resp) {
+                return publish::send_error(resp, status, &detail);
+            }
+        }
+
         match (req.method(), path_parts.as_slice()) {
             (Method::Post, ["", "api", "v1", "token"]) => self.handle_token_create(req, resp),
-            (_method, ["", "repo", _repo_name, "index", _rest @ ..]) => {
+            (Method::Get, ["", "metrics"]) => self.handle_metrics(resp),
+            (Method::Get, ["", "mirrors"]) => self.handle_mirrors(resp),
+            (Method::Get, ["", "healthz"]) => self.handle_healthz(resp),
+            (Method::Get, ["", "readyz"]) => self.handle_readyz(resp),
+            (Method::Get, ["", "repo", repo_name, "api", "v1", "crates", name, version, "download"]) => {
+                self.handle_download(repo_name, name, version, resp)
+            }
+            (Method::Get, ["", "repo", repo_name, "api", "v1", "crates"]) => self.handle_search(repo_name, req, resp),
+            (Method::Put, ["", "repo", repo_name, "api", "v1", "crates", "new"]) => {
+                let repo_name = repo_name.to_string();
+                match self.writers.get(&repo_name) {
+                    Some(writer) => {
+                        // Without a configured public URL, the index follows wherever publishers reach us
+                        if let (None, Some(base_url)) = (&self.config.public_url, client.base_url()) {
+                            if let Err(e) = set_base_url(writer, &repo_name, &base_url) {
+                                log::warn!("Unable to point {}'s config.json at {}: {:?}", repo_name, base_url, e);
+                            }
+                        }
+                        publish::handle(&self.config, &self.metrics, self.blobs.as_ref(), writer, &repo_name, req, resp)
+                    }
+                    None => publish::send_error(resp, 404, &format!("No such registry: {}", repo_name)),
+                }
+            }
+            (_method, ["", "repo", repo_name, "api", "v1", "crates", name, "owners"]) => {
+                let (repo_name, name) = (repo_name.to_string(), name.to_string());
+                match self.writers.get(&repo_name) {
+                    Some(writer) => owners::handle(&self.config, writer, &repo_name, &name, req, resp),
+                    None => publish::send_error(resp, 404, &format!("No such registry: {}", repo_name)),
+                }
+            }
+            (Method::Delete, ["", "repo", repo_name, "api", "v1", "crates", name, version, "yank"]) => {
+                self.handle_yank(repo_name, name, version, true, req, resp)
+            }
+            (Method::Put, ["", "repo", repo_name, "api", "v1", "crates", name, version, "unyank"]) => {
+                self.handle_yank(repo_name, name, version, false, req, resp)
+            }
+            (_method, ["", "repo", repo_name, "index", rest @ ..]) => {
+                if self.config.git.native_upload_pack && upload_pack::handles(req, rest) {
+                    let _span = trace::span("git");
+                    let repo_name = repo_name.to_string();
+                    return upload_pack::handle(&self.config, &self.metrics, &repo_name, req, resp);
+                }
                 self.handle_git_request(req, resp)
             }
             _ => {
@@ -47,9 +168,12 @@ impl App {
 }
 
 
-fn ensure_index_setup(config: &AppGitConfig, repo_name: &str) -> Result<()> {
-    if ! repo_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
-        bail!("Repo names must match [a-zA-Z_]. Got: {}", repo_name);
+/// Creates the repo's index if need be, returning the writer that owns it from here on.
+/// Its config.json points cargo at `base_url` (as from `AppConfig::base_url`).
+pub(crate) fn ensure_index_setup(config: &AppGitConfig, base_url: &str, repo_name: &str) -> Result<IndexWriter> {
+    let _span = trace::span("index_setup");
+    if let Err(e) = names::validate(repo_name) {
+        bail!("Invalid repo name {:?}: {}", repo_name, e);
     }
 
     let repo_index_path = config.path.join(repo_name);
@@ -61,23 +185,12 @@ fn ensure_index_setup(config: &AppGitConfig, repo_name: &str) -> Result<()> {
 
     if ! repo_index_path.join(".git").exists() {
         log::debug!("Initializing repo: {} (initializing git)", repo_name);
-        let child = Command::new("git")
-            .current_dir(&repo_index_path)
-            .args(&[
-                "init",
-                "-b", "master", // Cargo still expects the main branch to be called "master"
-                ])
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn().context("Spawning git-init")?
-            ;
-
-        let output = child.wait_with_output().context("Awaiting git-init")?;
-        if ! output.status.success() {
-            log::error!("{}", String::from_utf8_lossy(&output.stderr));
-            anyhow::bail!("Failed to initialize fresh repo ({})", repo_name);
-        }
+        git2::Repository::init_opts(
+            &repo_index_path,
+            git2::RepositoryInitOptions::new()
+                .no_reinit(true)
+                .initial_head("master"), // Cargo still expects the main branch to be called "master"
+        ).with_context(|| format!("Failed to initialize fresh repo ({})", repo_name))?;
     }
 
     let git_export_marker = repo_index_path.join(".git").join("git-daemon-export-ok");
@@ -86,61 +199,62 @@ fn ensure_index_setup(config: &AppGitConfig, repo_name: &str) -> Result<()> {
         let _ = std::fs::File::create(&git_export_marker).context("Marking repo index for git export")?;
     }
 
-    let cargo_config_file = repo_index_path.join("config.json");
-    if ! cargo_config_file.exists() {
+    let writer = IndexWriter::start(config, repo_name)?;
+
+    if ! repo_index_path.join("config.json").exists() {
         log::debug!("Initializing repo: {} (setting up cargo repo config)", repo_name);
-        let mut f = std::fs::File::create(&cargo_config_file)?;
-        write!(f, 
+        let contents = format!(
             "{{\n\
-                \"dl\": \"http://localhost:8080/repo/{}/api/v1/crates\",\n\
-                \"api\": \"http://localhost:8080/repo/{}\"\n\
+                \"dl\": \"{}/repo/{}/api/v1/crates\",\n\
+                \"api\": \"{}/repo/{}\"\n\
             }}
-            ", repo_name, repo_name)?;
-
-
-        let add_result = Command::new("git")
-            .current_dir(&repo_index_path)
-            .args(&["add", "config.json"])
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::piped())
-            .spawn().context("Staging repo config")?
-            .wait_with_output()?;
-
-        if ! add_result.status.success() {
-            log::error!("Preparing repo config for {} (add): {}", repo_name, String::from_utf8_lossy(&add_result.stderr));
-            anyhow::bail!("Failed to initialize repo - couldn't add initial config file to git");
-        }
-
-        let commit_result = Command::new("git")
-            .current_dir(&repo_index_path)
-            .args(&[
-                "-c", &format!("user.name='{}'", config.author_name), 
-                "-c", &format!("user.email='{}'", config.author_email), 
-                "commit", 
-                "-m", 
-                "(rotterdam): Initializing repo", 
-                "--author", &config.author, 
-                "--", 
-                "config.json"])
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::piped())
-            .spawn().context("Commiting repo config")?
-            .wait_with_output()?;
-
-        if ! commit_result.status.success() {
-            log::error!("Preparing repo config for {} (commit): {}", repo_name, String::from_utf8_lossy(&commit_result.stderr));
-            anyhow::bail!("Failed to initialize repo - couldn't commit initial config file");
-        }
-    }
-    
-    Ok(())
+            ", base_url, repo_name, base_url, repo_name);
+        writer.mutate("Initializing repo", Box::new(move |_: &std::path::Path| {
+            Ok(vec![Edit { path: "config.json".into(), contents: Some(contents.into_bytes()) }])
+        })).context("Failed to initialize repo - couldn't commit initial config file")?;
+    }
+
+    Ok(writer)
 }
 
+/// Applies `update` to the index's config.json, committing only if that changed anything.
+fn update_cargo_config(writer: &IndexWriter, message: &str, update: impl FnOnce(&mut json::JsonValue) + Send + 'static) -> Result<()> {
+    writer.mutate(message, Box::new(move |index_root: &std::path::Path| {
+        let contents = std::fs::read_to_string(index_root.join("config.json"))?;
+        let mut config = json::parse(&contents).context("config.json is not valid JSON")?;
+        let before = config.clone();
+        update(&mut config);
+        if config == before {
+            return Ok(Vec::new());
+        }
+        Ok(vec![Edit { path: "config.json".into(), contents: Some(format!("{}\n", config.pretty(2)).into_bytes()) }])
+    }))
+}
+
+/// Keeps `auth-required` in the index's config.json in step with whether the repo limits who
+/// may read it, so that cargo sends its token along with downloads.
+fn set_auth_required(writer: &IndexWriter, required: bool) -> Result<()> {
+    update_cargo_config(writer, "Updating auth-required", move |config| {
+        if required {
+            config["auth-required"] = true.into();
+        } else {
+            config.remove("auth-required");
+        }
+    })
+}
+
+/// Points the download and API URLs in the index's config.json at `base_url`.
+fn set_base_url(writer: &IndexWriter, repo_name: &str, base_url: &str) -> Result<()> {
+    let (dl, api) = (format!("{}/repo/{}/api/v1/crates", base_url, repo_name), format!("{}/repo/{}", base_url, repo_name));
+    update_cargo_config(writer, &format!("Serving from {}", base_url), move |config| {
+        config["dl"] = dl.into();
+        config["api"] = api.into();
+    })
+}
 
 impl App {
-    fn ready_config(mut config: config::AppConfig) -> Result<config::AppConfig> {
+    /// Sets up every repo's index, returning the writers that own them.
+    pub(crate) fn ready_config(config: &mut config::AppConfig) -> Result<IndexWriters> {
         if ! config.git.path.exists() {
             std::fs::create_dir_all(&config.git.path)?;
         }
@@ -149,15 +263,31 @@ impl App {
 
         config.git.path = canonical_path;
 
-        for (name, _details) in config.repos.iter() {
-            ensure_index_setup(&config.git, name)?;
+        let mut repos: Vec<&str> = config.repos.keys().map(|r| r.as_ref()).collect();
+        repos.sort_by_key(|r| names::normalize(r));
+        if let Some(pair) = repos.windows(2).find(|pair| names::normalize(pair[0]) == names::normalize(pair[1])) {
+            bail!("Repos {} and {} differ only in case or -/_; give them names that can't be confused", pair[0], pair[1]);
         }
 
-        Ok(config)
+        let mut writers = IndexWriters::new();
+        for repo in config.repos.values() {
+            let writer = ensure_index_setup(&config.git, config.base_url(), &repo.name)?;
+            set_auth_required(&writer, repo.readers.is_some()).with_context(|| format!("Updating config.json for {}", repo.name))?;
+            if let Some(public_url) = &config.public_url {
+                set_base_url(&writer, &repo.name, public_url).with_context(|| format!("Updating config.json for {}", repo.name))?;
+            }
+            writers.insert(repo.name.to_string(), Arc::new(writer));
+        }
+
+        Ok(writers)
     }
 
-    fn handle_token_create(&self, _req: &dyn Request, mut resp: TcpResponseWriter) -> Result<()> {
+    fn handle_token_create(&self, req: &dyn Request, mut resp: ConnectionResponseWriter) -> Result<()> {
+        let _span = trace::span("auth");
         log::debug!("Token create request");
+        if let Some(who) = self.config.users.identify(req) {
+            resp.set_principal(&who.name);
+        }
         let r = Response::builder(200)
             .content_type("application/json")
             .body(br#"{ "token": "12345" }"#.to_vec())
@@ -168,9 +298,511 @@ impl App {
     }
 
 
-    fn handle_git_request(&self, req: &mut dyn Request, resp: TcpResponseWriter) -> Result<()> {
+    /// Why `req` may not read `repo_name`, as a status and a message for whoever made it, if
+    /// the repo limits who may read it and they're not among them.
+    fn refuse_reader(&self, repo_name: &str, req: &dyn Request, resp: &mut ConnectionResponseWriter) -> Option<(u16, String)> {
+        let readers = self.config.repos.get(repo_name)?.readers.as_ref()?;
+        let who = match self.config.users.identify(req) {
+            Some(who) => who,
+            None => return Some((401, format!("Reading {} needs a token that belongs to a user of this registry", repo_name))),
+        };
+        resp.set_principal(&who.name);
+        if who.admin || readers.iter().any(|r| who.is(r)) {
+            return None;
+        }
+        log::info!("Refusing {} access to {} in {}", who.name, req.path(), repo_name);
+        Some((403, format!("Only {} may read {}", readers.join(", "), repo_name)))
+    }
+
+    fn handle_git_request(&self, req: &mut dyn Request, resp: ConnectionResponseWriter) -> Result<()> {
+        let _span = trace::span("git");
         log::debug!("Git request");
 
-        git_cgi::handle(&self.config, req, resp)
+        git_cgi::handle(&self.config, &self.metrics, req, resp)
+    }
+
+    fn handle_search(&self, repo_name: &str, req: &dyn Request, mut resp: ConnectionResponseWriter) -> Result<()> {
+        let _span = trace::span("search");
+        let query = req.query_first_value("q").unwrap_or_default();
+        let per_page = req.query_first_value("per_page").and_then(|n| n.parse().ok()).unwrap_or(10);
+        match self.search.query(repo_name, &query, per_page) {
+            Some(results) => {
+                let r = Response::builder(200)
+                    .content_type("application/json")
+                    .body_from_string(&results.dump())
+                    .build();
+                resp.send_response(r)?;
+                Ok(())
+            }
+            None => publish::send_error(resp, 404, &format!("No such registry: {}", repo_name)),
+        }
+    }
+
+    fn handle_yank(&self, repo_name: &str, name: &str, version: &str, yanked: bool, req: &dyn Request, mut resp: ConnectionResponseWriter) -> Result<()> {
+        let writer = match self.writers.get(repo_name) {
+            Some(writer) => writer,
+            None => return publish::send_error(resp, 404, &format!("No such registry: {}", repo_name)),
+        };
+        let event = if yanked { "yank" } else { "unyank" };
+        let who = self.config.users.identify(req);
+        if let Some(who) = who {
+            resp.set_principal(&who.name);
+        }
+        match yank::set_yanked(&self.config, writer, repo_name, name, version, yanked, who) {
+            Ok(()) => {
+                log::info!("{} {} {} in {}", if yanked { "Yanked" } else { "Unyanked" }, name, version, repo_name);
+                self.metrics.inc("rotterdam_crate_events_total", crate::metrics::labels(&[("repo", repo_name), ("event", event)]));
+                let r = Response::builder(200)
+                    .content_type("application/json")
+                    .body_from_string(&json::object! { ok: true }.dump())
+                    .build();
+                resp.send_response(r)?;
+                Ok(())
+            }
+            Err(e) => match publish::client_error(&e) {
+                Some((status, details)) => {
+                    log::info!("Refusing to {} {} {} in {}: {}", event, name, version, repo_name, e);
+                    publish::send_errors(resp, status, &details)
+                }
+                None => {
+                    publish::send_error(resp, 500, "Internal error while yanking")?;
+                    Err(e).context("Yanking crate")
+                }
+            },
+        }
+    }
+
+    fn handle_download(&self, repo_name: &str, name: &str, version: &str, mut resp: ConnectionResponseWriter) -> Result<()> {
+        if ! self.config.repos.contains_key(repo_name) {
+            resp.send_response(Response::err(404))?;
+            return Ok(());
+        }
+        // The name becomes part of a path into the index, so nothing but a crate name will do
+        if names::validate(name).is_err() {
+            log::debug!("Refusing a download of {:?} from {}: not a crate name", name, repo_name);
+            resp.send_response(Response::err(404))?;
+            return Ok(());
+        }
+
+        let index_span = trace::span("index");
+        let entry = match index::find_version(&self.config.git.path.join(repo_name), name, version) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => {
+                log::debug!("{} {} is not in the {} index", name, version, repo_name);
+                resp.send_response(Response::err(404))?;
+                return Ok(());
+            }
+            Err(e) => {
+                resp.send_response(Response::err(500))?;
+                return Err(e).context("Looking up crate in index");
+            }
+        };
+        drop(index_span);
+
+        let _span = trace::span("storage");
+        let key = blob_store::tarball_key(repo_name, &entry.cksum);
+        match self.blobs.redirect_url(&key) {
+            Ok(Some(url)) => {
+                self.metrics.inc("rotterdam_crate_events_total", crate::metrics::labels(&[("repo", repo_name), ("event", "download")]));
+                resp.send_response(Response::redirect(302, &url))?;
+                return Ok(());
+            }
+            Ok(None) => {}
+            Err(e) => {
+                resp.send_response(Response::err(500))?;
+                return Err(e).context("Presigning crate download");
+            }
+        }
+
+        let blob = match self.blobs.get(&key) {
+            Ok(Some(blob)) => blob,
+            Ok(None) => {
+                log::error!("{} {} is in the {} index, but its tarball ({}) is missing", name, version, repo_name, key);
+                resp.send_response(Response::err(404))?;
+                return Ok(());
+            }
+            Err(e) => {
+                resp.send_response(Response::err(500))?;
+                return Err(e).context("Reading crate tarball");
+            }
+        };
+
+        self.metrics.inc("rotterdam_crate_events_total", crate::metrics::labels(&[("repo", repo_name), ("event", "download")]));
+        let r = Response::builder(200)
+            .content_type("application/x-tar")
+            .body_reader(VerifyingReader::new(blob.reader, &entry.cksum), blob.size)
+            .build();
+        match resp.send_response(r) {
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                // Too late for an error status; the client sees a truncated download
+                log::error!("Served a corrupt tarball for {} {} from {}: {}", name, version, key, e);
+                self.metrics.inc("rotterdam_blob_checksum_failures_total", crate::metrics::labels(&[("repo", repo_name)]));
+                Ok(())
+            }
+            result => Ok(result?),
+        }
+    }
+
+    fn handle_healthz(&self, mut resp: ConnectionResponseWriter) -> Result<()> {
+        let r = Response::builder(200)
+            .content_type("application/json")
+            .body(br#"{ "status": "ok" }"#.to_vec())
+            .build();
+        resp.send_response(r)?;
+
+        Ok(())
     }
-}
\ No newline at end of file
+
+    fn handle_readyz(&self, mut resp: ConnectionResponseWriter) -> Result<()> {
+        let (ready, report) = health::readiness(&self.config, self.blobs.as_ref());
+        if ! ready {
+            log::warn!("Readiness check failed: {}", report.dump());
+        }
+
+        let r = Response::builder(if ready { 200 } else { 503 })
+            .content_type("application/json")
+            .body_from_string(&report.dump())
+            .build();
+        resp.send_response(r)?;
+
+        Ok(())
+    }
+
+    fn handle_metrics(&self, mut resp: ConnectionResponseWriter) -> Result<()> {
+        let r = Response::builder(200)
+            .content_type("text/plain; version=0.0.4")
+            .body_from_string(&self.metrics.render())
+            .build();
+        resp.send_response(r)?;
+
+        Ok(())
+    }
+
+    fn handle_mirrors(&self, mut resp: ConnectionResponseWriter) -> Result<()> {
+        let r = Response::builder(200)
+            .content_type("application/json")
+            .body_from_string(&self.mirrors.status().dump())
+            .build();
+        resp.send_response(r)?;
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::borrow::Cow;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+    use smtr::server::{AccessLog, AccessRecord};
+    use crate::auth::{Identity, Users};
+    use crate::blob_store::FilesystemBlobStore;
+
+    /// A registry for tests: the default config with a single repo, `repo`, whose index and
+    /// blobs live in a temporary directory. The directory is removed when this is dropped, even
+    /// if the test panics.
+    pub(crate) struct TestRegistry {
+        pub(crate) config: config::AppConfig,
+        pub(crate) blobs: FilesystemBlobStore,
+        pub(crate) dir: tempfile::TempDir,
+        users: Vec<(String, Identity)>,
+    }
+
+    impl TestRegistry {
+        pub(crate) fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let mut config = config::load::<&Path>(None).unwrap();
+            config.git.path = dir.path().join("git");
+            config.binaries = crate::blob_store::BlobStoreConfig::Filesystem(dir.path().join("blobs"));
+            config.repos.insert(Cow::from("repo"), config::Repo { name: Cow::from("repo"), ..config::Repo::default() });
+            std::fs::create_dir_all(&config.git.path).unwrap();
+            let blobs = FilesystemBlobStore::new(&dir.path().join("blobs")).unwrap();
+            TestRegistry { config, blobs, dir, users: Vec::new() }
+        }
+
+        /// Sets up `repo`'s index, and starts its writer.
+        pub(crate) fn writer(&self) -> IndexWriter {
+            ensure_index_setup(&self.config.git, self.config.base_url(), "repo").unwrap()
+        }
+
+        pub(crate) fn index_root(&self) -> PathBuf {
+            self.config.git.path.join("repo")
+        }
+
+        /// Configures a user, who'll be known by the token `<name>-token`.
+        pub(crate) fn add_user(&mut self, name: &str, teams: &[&str], admin: bool) {
+            let hash = blob_store::sha256_hex(&mut format!("{}-token", name).as_bytes()).unwrap();
+            self.users.push((hash, Identity { name: name.to_string(), teams: teams.iter().map(|t| t.to_string()).collect(), admin }));
+            self.config.users = Users::new(self.users.clone());
+        }
+
+        /// Starts an `App` on this registry, serving requests on a local TCP port.
+        pub(crate) fn serve(&self) -> TestServer {
+            let mut config = self.config.clone();
+            let writers = App::ready_config(&mut config).unwrap();
+            let blobs: Arc<dyn BlobStore> = Arc::new(FilesystemBlobStore::new(&self.dir.path().join("blobs")).unwrap());
+            let metrics = Arc::new(Metrics::new(&config, Arc::default()));
+            let mirrors = crate::mirror::start(&config, &writers, metrics.clone());
+            let search = crate::search::start(&config, &writers);
+
+            let access_log = Arc::new(RecordedAccess::default());
+            let mut server_config = config.server.clone();
+            server_config.access_log = Some(access_log.clone());
+            let bound = smtr::server::bind_all(&["127.0.0.1:0"]).unwrap();
+            let addr = bound.local_addresses()[0].1.clone();
+            let requests = bound.serve(server_config);
+
+            let app = App::new(config, metrics, blobs, writers, mirrors, search).unwrap();
+            std::thread::spawn(move || {
+                for (mut req, resp) in requests {
+                    if let Err(e) = app.handle(&mut req, resp) {
+                        log::warn!("Test request failed: {:?}", e);
+                    }
+                }
+            });
+            TestServer { addr, access_log }
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordedAccess(Mutex<Vec<AccessRecord>>);
+
+    impl AccessLog for RecordedAccess {
+        fn log(&self, record: &AccessRecord) {
+            self.0.lock().unwrap().push(record.clone());
+        }
+    }
+
+    /// An `App` started by `TestRegistry::serve`.
+    pub(crate) struct TestServer {
+        addr: String,
+        access_log: Arc<RecordedAccess>,
+    }
+
+    /// A response from a `TestServer`, with the access log record it left behind.
+    pub(crate) struct TestResponse {
+        pub(crate) status: u16,
+        pub(crate) head: String,
+        pub(crate) body: Vec<u8>,
+        pub(crate) access: AccessRecord,
+    }
+
+    impl TestResponse {
+        pub(crate) fn header(&self, name: &str) -> Option<&str> {
+            self.head.lines().skip(1)
+                .filter_map(|line| line.split_once(": "))
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value)
+        }
+
+        pub(crate) fn json(&self) -> json::JsonValue {
+            json::parse(&String::from_utf8_lossy(&self.body)).unwrap()
+        }
+    }
+
+    impl TestServer {
+        /// Makes a request, with `token` (if any) as its `Authorization` header.
+        pub(crate) fn request(&self, method: &str, path: &str, token: Option<&str>, body: &[u8]) -> TestResponse {
+            let authorization = token.map(|token| ("Authorization", token));
+            self.request_with_headers(method, path, authorization.as_slice(), body)
+        }
+
+        pub(crate) fn request_with_headers(&self, method: &str, path: &str, headers: &[(&str, &str)], body: &[u8]) -> TestResponse {
+            let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n", method, path, body.len());
+            for (name, value) in headers {
+                request.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            request.push_str("\r\n");
+            self.send(&[request.as_bytes(), body].concat())
+        }
+
+        /// Sends `request` exactly as given, and waits for the server to close the connection.
+        pub(crate) fn send(&self, request: &[u8]) -> TestResponse {
+            let mut stream = TcpStream::connect(&self.addr).unwrap();
+            stream.write_all(request).unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+
+            // The server logs each request before it closes the connection
+            let access = self.access_log.0.lock().unwrap().pop().expect("an access log record");
+            let split = response.windows(4).position(|w| w == b"\r\n\r\n").expect("a complete response");
+            let head = String::from_utf8_lossy(&response[..split]).to_string();
+            let status = head.lines().next().and_then(|line| line.split(' ').nth(1)).and_then(|s| s.parse().ok()).expect("a status");
+            TestResponse { status, head, body: response[split + 4..].to_vec(), access }
+        }
+    }
+
+    fn publish_body(name: &str, vers: &str) -> Vec<u8> {
+        let manifest = format!("[package]\nname = \"{}\"\nversion = \"{}\"\n", name, vers);
+        let tarball = crate::crate_file::test::dot_crate(&[(&format!("{}-{}/Cargo.toml", name, vers), &manifest)]);
+        publish::test::body(&format!(r#"{{"name":"{}","vers":"{}","deps":[]}}"#, name, vers), &tarball)
+    }
+
+    #[test]
+    fn logs_who_made_each_request() {
+        let mut registry = TestRegistry::new();
+        registry.add_user("alice", &[], false);
+        registry.add_user("bob", &[], false);
+        let server = registry.serve();
+
+        let published = server.request("PUT", "/repo/repo/api/v1/crates/new", Some("alice-token"), &publish_body("foo", "1.0.0"));
+        assert_eq!((published.status, published.header("content-type")), (200, Some("application/json")));
+        assert_eq!(published.access.principal.as_deref(), Some("alice"));
+
+        let yanked = server.request("DELETE", "/repo/repo/api/v1/crates/foo/1.0.0/yank", Some("Bearer alice-token"), b"");
+        assert_eq!(yanked.status, 200);
+        assert_eq!(yanked.access.principal.as_deref(), Some("alice"));
+
+        let owners = server.request("PUT", "/repo/repo/api/v1/crates/foo/owners", Some("alice-token"), br#"{"users":["bob"]}"#);
+        assert_eq!(owners.status, 200);
+        assert_eq!(owners.access.principal.as_deref(), Some("alice"));
+
+        let token = server.request("POST", "/api/v1/token", Some("bob-token"), b"");
+        assert_eq!(token.access.principal.as_deref(), Some("bob"));
+
+        let refused = server.request("PUT", "/repo/repo/api/v1/crates/foo/1.0.0/unyank", Some("mallory-token"), b"");
+        assert_eq!(refused.status, 403);
+        assert!(refused.json()["errors"][0]["detail"].as_str().unwrap().starts_with("Only foo's owners"));
+        assert_eq!(refused.access.principal, None);
+        assert_eq!(server.request("GET", "/repo/repo/api/v1/crates?q=foo", None, b"").access.principal, None);
+    }
+
+    #[test]
+    fn accepts_publishes_of_ordinary_sized_crates() {
+        // Random hex barely compresses, so the upload is well over smtr's 10 KB default
+        let source: String = (0..4096).map(|_| format!("{:016x}", trace::random_u64())).collect();
+        let manifest = "[package]\nname = \"big\"\nversion = \"1.0.0\"\n";
+        let tarball = crate::crate_file::test::dot_crate(&[("big-1.0.0/Cargo.toml", manifest), ("big-1.0.0/src/lib.rs", &format!("// {}\n", source))]);
+        let body = publish::test::body(r#"{"name":"big","vers":"1.0.0","deps":[]}"#, &tarball);
+        assert!(body.len() > 20_000, "{} bytes", body.len());
+
+        let registry = TestRegistry::new();
+        let server = registry.serve();
+        let published = server.request("PUT", "/repo/repo/api/v1/crates/new", None, &body);
+        assert_eq!(published.status, 200, "{}", String::from_utf8_lossy(&published.body));
+    }
+
+    #[test]
+    fn leaves_unowned_crates_to_admins() {
+        let mut registry = TestRegistry::new();
+        registry.add_user("bob", &[], false);
+        registry.add_user("root", &[], true);
+        let server = registry.serve();
+        assert_eq!(server.request("PUT", "/repo/repo/api/v1/crates/new", None, &publish_body("foo", "1.0.0")).status, 200);
+        assert_eq!(server.request("GET", "/repo/repo/api/v1/crates/foo/owners", None, b"").json()["users"].len(), 0);
+
+        let yank = "/repo/repo/api/v1/crates/foo/1.0.0/yank";
+        let refused = server.request("DELETE", yank, None, b"");
+        assert_eq!(refused.status, 403);
+        assert_eq!(refused.json()["errors"][0]["detail"], "Nobody owns foo, so only an admin may yank it");
+        assert_eq!(server.request("DELETE", yank, Some("bob-token"), b"").status, 403);
+        assert_eq!(server.request("PUT", "/repo/repo/api/v1/crates/new", None, &publish_body("foo", "1.1.0")).status, 403);
+
+        let owners = "/repo/repo/api/v1/crates/foo/owners";
+        assert_eq!(server.request("PUT", owners, None, br#"{"users":["bob"]}"#).status, 403);
+        assert_eq!(server.request("PUT", owners, Some("bob-token"), br#"{"users":["bob"]}"#).status, 403);
+        assert_eq!(server.request("PUT", owners, Some("root-token"), br#"{"users":["bob"]}"#).status, 200);
+        assert_eq!(server.request("DELETE", yank, Some("bob-token"), b"").status, 200);
+    }
+
+    #[test]
+    fn hands_out_urls_clients_can_reach() {
+        let cargo_config = |registry: &TestRegistry| json::parse(&std::fs::read_to_string(registry.index_root().join("config.json")).unwrap()).unwrap();
+        let forwarded = [("X-Forwarded-For", "203.0.113.7"), ("X-Forwarded-Proto", "https"), ("X-Forwarded-Host", "crates.example.com")];
+
+        let mut registry = TestRegistry::new();
+        registry.config.proxy = crate::proxy::TrustedProxies::new(vec!["127.0.0.1".parse().unwrap()]);
+        let server = registry.serve();
+        assert_eq!(cargo_config(&registry)["api"], "http://localhost:8080/repo/repo");
+        let published = server.request_with_headers("PUT", "/repo/repo/api/v1/crates/new", &forwarded, &publish_body("foo", "1.0.0"));
+        assert_eq!(published.status, 200);
+        let config = cargo_config(&registry);
+        assert_eq!(config["dl"], "https://crates.example.com/repo/repo/api/v1/crates");
+        assert_eq!(config["api"], "https://crates.example.com/repo/repo");
+        assert_eq!(index::index_url(&registry.index_root(), registry.config.base_url(), "repo"), "https://crates.example.com/repo/repo/index");
+
+        // A configured public URL wins over whatever proxies say
+        registry.config.public_url = Some(String::from("https://registry.example.org"));
+        let server = registry.serve();
+        assert_eq!(server.request_with_headers("PUT", "/repo/repo/api/v1/crates/new", &forwarded, &publish_body("bar", "1.0.0")).status, 200);
+        assert_eq!(cargo_config(&registry)["dl"], "https://registry.example.org/repo/repo/api/v1/crates");
+        assert_eq!(index::index_url(&registry.index_root(), registry.config.base_url(), "repo"), "https://registry.example.org/repo/repo/index");
+    }
+
+    #[test]
+    fn times_out_bodies_that_stop_arriving() {
+        let mut registry = TestRegistry::new();
+        registry.add_user("alice", &[], false);
+        registry.config.server.body_idle_timeout = std::time::Duration::from_millis(100);
+        let server = registry.serve();
+
+        let requests = [("PUT", "/repo/repo/api/v1/crates/new"), ("PUT", "/repo/repo/api/v1/crates/foo/owners"), ("POST", "/repo/repo/index/git-upload-pack")];
+        for (method, path) in requests {
+            let request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: alice-token\r\nContent-Length: 100\r\n\r\n{{", method, path);
+            assert_eq!(server.send(request.as_bytes()).status, 408, "{}", path);
+        }
+    }
+
+    #[test]
+    fn only_downloads_crate_names() {
+        let registry = TestRegistry::new();
+        let server = registry.serve();
+        assert_eq!(server.request("PUT", "/repo/repo/api/v1/crates/new", None, &publish_body("foo", "1.0.0")).status, 200);
+        assert_eq!(server.request("GET", "/repo/repo/api/v1/crates/foo/1.0.0/download", None, b"").status, 200);
+        for name in ["....", "..", "-foo", "foo.bar", "%2e%2e"] {
+            let download = format!("/repo/repo/api/v1/crates/{}/1.0.0/download", name);
+            assert_eq!(server.request("GET", &download, None, b"").status, 404, "{}", name);
+        }
+    }
+
+    #[test]
+    fn only_readers_read_restricted_repos() {
+        let download = "/repo/repo/api/v1/crates/foo/1.0.0/download";
+        let auth_required = |registry: &TestRegistry| {
+            json::parse(&std::fs::read_to_string(registry.index_root().join("config.json")).unwrap()).unwrap()["auth-required"].as_bool()
+        };
+
+        let open = TestRegistry::new();
+        let server = open.serve();
+        assert_eq!(server.request("PUT", "/repo/repo/api/v1/crates/new", None, &publish_body("foo", "1.0.0")).status, 200);
+        assert_eq!(server.request("GET", download, None, b"").status, 200);
+        assert_eq!(auth_required(&open), None);
+
+        let mut registry = TestRegistry::new();
+        registry.add_user("alice", &["payments"], false);
+        registry.add_user("bob", &[], false);
+        registry.add_user("root", &[], true);
+        registry.config.repos.get_mut("repo").unwrap().readers = Some(vec![String::from("payments")]);
+        let server = registry.serve();
+        assert_eq!(auth_required(&registry), Some(true));
+        assert_eq!(server.request("PUT", "/repo/repo/api/v1/crates/new", Some("alice-token"), &publish_body("foo", "1.0.0")).status, 200);
+
+        let anonymous = server.request("GET", download, None, b"");
+        assert_eq!(anonymous.status, 401);
+        assert!(anonymous.json()["errors"][0]["detail"].as_str().unwrap().contains("needs a token"));
+        assert_eq!(server.request("GET", download, Some("mallory-token"), b"").status, 401);
+        let refused = server.request("GET", download, Some("bob-token"), b"");
+        assert_eq!((refused.status, refused.access.principal.as_deref()), (403, Some("bob")));
+
+        let allowed = server.request("GET", download, Some("alice-token"), b"");
+        assert_eq!((allowed.status, allowed.header("content-type")), (200, Some("application/x-tar")));
+        assert_eq!(allowed.access.principal.as_deref(), Some("alice"));
+        assert_eq!(server.request("GET", download, Some("root-token"), b"").status, 200);
+
+        // Nor may anyone else see what the repo holds through its index, search or owners
+        let fetch = "/repo/repo/index/info/refs?service=git-upload-pack";
+        let search = "/repo/repo/api/v1/crates?q=foo";
+        for path in [fetch, search, "/repo/repo/api/v1/crates/foo/owners"] {
+            let refused = server.request("GET", path, Some("bob-token"), b"");
+            assert_eq!(refused.status, 403, "{}", path);
+            assert_eq!(refused.json()["errors"][0]["detail"], "Only payments may read repo");
+            assert_eq!(server.request("GET", path, None, b"").status, 401, "{}", path);
+        }
+        assert_eq!(server.request("GET", fetch, Some("alice-token"), b"").status, 200);
+        assert_eq!(server.request("GET", search, Some("alice-token"), b"").json()["meta"]["total"], 1);
+    }
+}
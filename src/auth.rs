@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+
+use smtr::{Header, Request};
+
+use crate::blob_store;
+
+
+/// Someone a request can be made on behalf of, as configured under `[rotterdam.users.<name>]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Identity {
+    pub name: String,
+    pub teams: Vec<String>,
+    /// Admins may act as an owner of any crate.
+    pub admin: bool,
+}
+
+impl Identity {
+    /// Whether `who` (from a list of users and teams in config) refers to this identity.
+    pub(crate) fn is(&self, who: &str) -> bool {
+        self.name == who || self.teams.iter().any(|t| t == who)
+    }
+}
+
+/// A request someone isn't allowed to make, in words fit to show them.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub(crate) struct NotAllowed(pub String);
+
+/// The configured users, by the SHA-256 of their token. Only hashes are kept in config, so the
+/// config file alone doesn't let anyone publish.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Users(HashMap<String, Identity>);
+
+impl Users {
+    pub(crate) fn new(users: Vec<(String, Identity)>) -> Self {
+        Users(users.into_iter().collect())
+    }
+
+    /// Who the token in `req`'s `Authorization` header belongs to. Cargo sends the token as the
+    /// whole header value; a `Bearer ` prefix is accepted too.
+    pub(crate) fn identify(&self, req: &dyn Request) -> Option<&Identity> {
+        let token: &[u8] = req.headers().get(Header::Authorization)?;
+        let token = token.strip_prefix(b"Bearer ").unwrap_or(token);
+        let hash = blob_store::sha256_hex(&mut &token[..]).ok()?;
+        self.0.get(&hash)
+    }
+
+    /// Whether `who` is a configured user or team.
+    pub(crate) fn knows(&self, who: &str) -> bool {
+        self.0.values().any(|identity| identity.is(who))
+    }
+
+    /// Whether `who` is a configured user, rather than a team.
+    pub(crate) fn is_user(&self, who: &str) -> bool {
+        self.0.values().any(|identity| identity.name == who)
+    }
+}
@@ -0,0 +1,150 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::{validate_key, Blob, BlobInfo, BlobStore, Result};
+
+
+/// Stores each blob as a plain file under `root`, at the path given by its key.
+pub(crate) struct FilesystemBlobStore {
+    root: PathBuf,
+}
+
+impl FilesystemBlobStore {
+    pub(crate) fn new(root: &Path) -> Result<Self> {
+        fs::create_dir_all(root)?;
+        Ok(FilesystemBlobStore { root: root.canonicalize()? })
+    }
+
+    fn path_for(&self, key: &str) -> Result<PathBuf> {
+        validate_key(key)?;
+        Ok(key.split('/').fold(self.root.clone(), |path, segment| path.join(segment)))
+    }
+
+    /// Blobs are written here first and renamed into place once complete. Keys can't start
+    /// with a `.`, so this never collides with a blob.
+    fn staging_dir(&self) -> PathBuf {
+        self.root.join(".staging")
+    }
+}
+
+impl BlobStore for FilesystemBlobStore {
+    fn put(&self, key: &str, data: &mut dyn Read) -> Result<u64> {
+        let path = self.path_for(key)?;
+
+        let staging = self.staging_dir();
+        fs::create_dir_all(&staging)?;
+        let temp_path = staging.join(format!("{:016x}", crate::trace::random_u64()));
+
+        let written = (|| {
+            let mut f = File::create(&temp_path)?;
+            let written = io::copy(data, &mut f)?;
+            f.flush()?;
+            f.sync_all()?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&temp_path, &path)?;
+            Ok(written)
+        })();
+
+        if written.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        written
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Blob>> {
+        let path = self.path_for(key)?;
+        match File::open(&path) {
+            Ok(f) => Ok(Some(Blob { size: Some(f.metadata()?.len()), reader: Box::new(f) })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.path_for(key)?.is_file())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        match fs::remove_file(self.path_for(key)?) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<BlobInfo>> {
+        let mut blobs = Vec::new();
+        walk(&self.root, "", prefix, &mut blobs)?;
+        blobs.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(blobs)
+    }
+
+    fn check(&self) -> Result<()> {
+        let staging = self.staging_dir();
+        fs::create_dir_all(&staging)?;
+        let probe = staging.join(format!("readyz-{}", std::process::id()));
+        fs::write(&probe, b"")?;
+        let _ = fs::remove_file(&probe);
+        Ok(())
+    }
+}
+
+fn walk(dir: &Path, key_prefix: &str, wanted: &str, blobs: &mut Vec<BlobInfo>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) if !name.starts_with('.') => name,
+            _ => continue,
+        };
+        let key = format!("{}{}", key_prefix, name);
+
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            let key_prefix = format!("{}/", key);
+            // Only descend where there could be matches
+            if key_prefix.starts_with(wanted) || wanted.starts_with(&key_prefix) {
+                walk(&entry.path(), &key_prefix, wanted, blobs)?;
+            }
+        } else if file_type.is_file() && key.starts_with(wanted) {
+            blobs.push(BlobInfo { key, size: entry.metadata()?.len() });
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stores_and_lists_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FilesystemBlobStore::new(dir.path()).unwrap();
+
+        assert_eq!(store.put("repo/crates/foo/foo-1.0.0.crate", &mut &b"tarball"[..]).unwrap(), 7);
+        store.put("repo/crates/bar/bar-0.1.0.crate", &mut &b"other"[..]).unwrap();
+        store.put("elsewhere/x", &mut &b""[..]).unwrap();
+
+        let mut contents = Vec::new();
+        let mut blob = store.get("repo/crates/foo/foo-1.0.0.crate").unwrap().unwrap();
+        assert_eq!(blob.size, Some(7));
+        blob.reader.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"tarball");
+
+        let listed: Vec<_> = store.list("repo/").unwrap().into_iter().map(|b| b.key).collect();
+        assert_eq!(listed, ["repo/crates/bar/bar-0.1.0.crate", "repo/crates/foo/foo-1.0.0.crate"]);
+        assert!(store.check().is_ok());
+        assert_eq!(store.list("").unwrap().len(), 3, "staging files must not be listed");
+
+        store.delete("repo/crates/foo/foo-1.0.0.crate").unwrap();
+        store.delete("repo/crates/foo/foo-1.0.0.crate").unwrap();
+        assert!(!store.exists("repo/crates/foo/foo-1.0.0.crate").unwrap());
+        assert!(store.get("repo/crates/foo/foo-1.0.0.crate").unwrap().is_none());
+        assert!(store.get("../outside").is_err());
+    }
+}
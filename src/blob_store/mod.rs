@@ -0,0 +1,235 @@
+use std::io::{self, Read};
+use std::sync::Arc;
+
+use ring::digest;
+
+mod filesystem;
+mod s3;
+
+pub(crate) use filesystem::FilesystemBlobStore;
+pub(crate) use s3::{S3BlobStore, S3Config};
+
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum BlobStoreError {
+    #[error("Invalid blob key: {0:?}")]
+    InvalidKey(String),
+    #[error("Blob store I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Object store request failed: {0}")]
+    Backend(String),
+}
+
+pub(crate) type Result<T> = std::result::Result<T, BlobStoreError>;
+
+/// Where crate tarballs live. `[rotterdam.binaries.filesystem]` and
+/// `[rotterdam.binaries.s3]` pick the implementation.
+#[derive(Clone, Debug)]
+pub(crate) enum BlobStoreConfig {
+    Filesystem(std::path::PathBuf),
+    S3(S3Config),
+}
+
+/// A blob being read back from the store.
+pub(crate) struct Blob {
+    /// Length in bytes, when the store knows it up front.
+    pub size: Option<u64>,
+    pub reader: Box<dyn Read + Send>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct BlobInfo {
+    pub key: String,
+    pub size: u64,
+}
+
+/// Storage for crate tarballs and other opaque blobs.
+///
+/// Keys are `/`-separated paths such as `myrepo/sha256/ab/abcd...`; see
+/// `validate_key` for exactly what's allowed.
+pub(crate) trait BlobStore: Send + Sync {
+    /// Stores everything `data` yields under `key`, replacing any existing blob. Readers
+    /// never see a partially written blob. Returns the number of bytes stored.
+    fn put(&self, key: &str, data: &mut dyn Read) -> Result<u64>;
+
+    /// Opens the blob stored under `key` for streaming, or `None` if there isn't one.
+    fn get(&self, key: &str) -> Result<Option<Blob>>;
+
+    fn exists(&self, key: &str) -> Result<bool>;
+
+    /// Removes the blob under `key`. Deleting a blob that doesn't exist is not an error.
+    fn delete(&self, key: &str) -> Result<()>;
+
+    /// Every blob whose key starts with `prefix`, sorted by key.
+    fn list(&self, prefix: &str) -> Result<Vec<BlobInfo>>;
+
+    /// Where clients should be sent to fetch `key` themselves (e.g. a pre-signed object
+    /// store URL), if the store is set up to serve downloads that way.
+    fn redirect_url(&self, key: &str) -> Result<Option<String>> {
+        validate_key(key)?;
+        Ok(None)
+    }
+
+    /// Checks that the store is reachable and writable, for `/readyz`.
+    fn check(&self) -> Result<()>;
+}
+
+pub(crate) fn open(config: &BlobStoreConfig) -> Result<Arc<dyn BlobStore>> {
+    Ok(match config {
+        BlobStoreConfig::Filesystem(path) => Arc::new(FilesystemBlobStore::new(path)?),
+        BlobStoreConfig::S3(s3) => Arc::new(S3BlobStore::new(s3.clone())),
+    })
+}
+
+/// Tarballs are stored under their SHA-256 (the index's `cksum`), so identical uploads
+/// share a blob and any blob can be checked against its own key.
+pub(crate) fn tarball_key(repo: &str, cksum: &str) -> String {
+    format!("{}/sha256/{}/{}", repo, &cksum[..2.min(cksum.len())], cksum)
+}
+
+/// The checksum a `tarball_key` names, if `key` is one.
+pub(crate) fn tarball_cksum(key: &str) -> Option<&str> {
+    let mut parts = key.rsplit('/');
+    let (cksum, fanout, kind) = (parts.next()?, parts.next()?, parts.next()?);
+    let is_tarball = kind == "sha256" && crate::index::is_sha256_hex(cksum) && cksum.starts_with(fanout) && fanout.len() == 2;
+    if is_tarball { Some(cksum) } else { None }
+}
+
+pub(crate) fn sha256_hex(reader: &mut dyn Read) -> io::Result<String> {
+    let mut context = digest::Context::new(&digest::SHA256);
+    let mut buf = [0; 64 * 1024];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(hex(context.finish().as_ref())),
+            n => context.update(&buf[..n]),
+        }
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hashes a blob as it's read, and fails rather than finish if the hash isn't what was
+/// expected. The last chunk read is held back until the hash is checked, so whoever is
+/// streaming the blob (a client download, say) never gets all of a corrupt one.
+pub(crate) struct VerifyingReader<R> {
+    inner: R,
+    context: Option<digest::Context>,
+    expected: String,
+    held: Vec<u8>,
+    ready: Vec<u8>,
+    ready_pos: usize,
+}
+
+impl<R: Read> VerifyingReader<R> {
+    pub(crate) fn new(inner: R, expected_sha256: &str) -> Self {
+        VerifyingReader {
+            inner,
+            context: Some(digest::Context::new(&digest::SHA256)),
+            expected: expected_sha256.to_string(),
+            held: Vec::new(),
+            ready: Vec::new(),
+            ready_pos: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for VerifyingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.ready_pos < self.ready.len() || buf.is_empty() {
+                let n = buf.len().min(self.ready.len() - self.ready_pos);
+                buf[..n].copy_from_slice(&self.ready[self.ready_pos..self.ready_pos + n]);
+                self.ready_pos += n;
+                return Ok(n);
+            }
+
+            let context = match self.context.as_mut() {
+                Some(context) => context,
+                None => return Ok(0), // Verified, and everything handed out
+            };
+
+            let mut next = vec![0; 64 * 1024];
+            let n = self.inner.read(&mut next)?;
+            next.truncate(n);
+
+            if n > 0 {
+                context.update(&next);
+            } else if let Some(context) = self.context.take() {
+                let actual = hex(context.finish().as_ref());
+                if actual != self.expected {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Blob checksum mismatch: expected {}, read {}", self.expected, actual),
+                    ));
+                }
+            }
+            self.ready = std::mem::replace(&mut self.held, next);
+            self.ready_pos = 0;
+        }
+    }
+}
+
+
+/// Keys are non-empty `/`-separated segments of `[A-Za-z0-9._+-]`, none of which may
+/// start with a `.` (which rules out `..`, and leaves dotfiles free for the store's own use).
+pub(crate) fn validate_key(key: &str) -> Result<()> {
+    let valid = !key.is_empty()
+        && key.split('/').all(|segment| {
+            !segment.is_empty()
+                && !segment.starts_with('.')
+                && segment.bytes().all(|b| b.is_ascii_alphanumeric() || b"._+-".contains(&b))
+        });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(BlobStoreError::InvalidKey(key.to_string()))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn names_tarballs_by_checksum() {
+        let key = tarball_key("testrepo", EMPTY_SHA256);
+        assert_eq!(key, format!("testrepo/sha256/e3/{}", EMPTY_SHA256));
+        assert_eq!(tarball_cksum(&key), Some(EMPTY_SHA256));
+        assert_eq!(tarball_cksum(&format!("testrepo/sha256/ff/{}", EMPTY_SHA256)), None);
+        assert_eq!(tarball_cksum("testrepo/quarantine/abc"), None);
+    }
+
+    #[test]
+    fn verifies_while_reading() {
+        assert_eq!(sha256_hex(&mut &b""[..]).unwrap(), EMPTY_SHA256);
+
+        let mut out = Vec::new();
+        assert!(VerifyingReader::new(&b""[..], EMPTY_SHA256).read_to_end(&mut out).is_ok());
+
+        let data = vec![7; 200 * 1024];
+        let cksum = sha256_hex(&mut &data[..]).unwrap();
+        VerifyingReader::new(&data[..], &cksum).read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+
+        let mut out = Vec::new();
+        let mut reader = VerifyingReader::new(&b"bitrot"[..], EMPTY_SHA256);
+        let err = std::io::copy(&mut reader, &mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(out.is_empty(), "corrupt data must not all be handed out");
+    }
+
+    #[test]
+    fn validates_keys() {
+        assert!(validate_key("testrepo/crates/foo/foo-1.0.0+build.crate").is_ok());
+        assert!(validate_key(&tarball_key("testrepo", EMPTY_SHA256)).is_ok());
+        for bad in ["", "/abs", "trailing/", "a//b", "../escape", "a/.hidden", "sp ace", "back\\slash"] {
+            assert!(validate_key(bad).is_err(), "{:?} should be rejected", bad);
+        }
+    }
+}
@@ -2,26 +2,73 @@ use std::{collections::HashMap, ops::Deref};
 use std::path::{PathBuf, Path};
 use std::borrow::Cow;
 use std::env;
+use std::time::Duration;
+
+use smtr::server::ServerConfig;
+
+use crate::access_log::{AccessLogConfig, AccessLogFormat};
+use crate::auth::{Identity, Users};
+use crate::blob_store::{BlobStoreConfig, S3Config};
+use crate::otlp::TracingConfig;
+use crate::scrub::ScrubConfig;
+use crate::maintenance::{Archive, MaintenanceConfig};
+use crate::mirror::MirrorConfig;
+use crate::names::Reservations;
+use crate::policy::{PublishPolicy, METADATA_FIELDS};
+use crate::publish::PublishConfig;
+use crate::proxy::{IpRange, TrustedProxies};
 
 
 
 #[derive(Clone, Debug)]
 pub(crate) struct AppConfig {
+    pub listen: Vec<String>,
+    pub admin_listen: Option<String>,
+    /// Where clients reach rotterdam (`https://crates.example.com`), for the URLs it hands out.
+    /// Unset, they follow what trusted proxies say the client asked for.
+    pub public_url: Option<String>,
+    pub server: ServerConfig,
+    pub access_log: Option<AccessLogConfig>,
+    pub tracing: Option<TracingConfig>,
     pub git: AppGitConfig,
+    pub binaries: BlobStoreConfig,
+    pub scrub: Option<ScrubConfig>,
+    pub maintenance: Option<MaintenanceConfig>,
+    pub mirror: MirrorConfig,
+    pub publish: PublishConfig,
+    pub users: Users,
     pub repos: HashMap<Cow<'static, str>, Repo>,
+    pub proxy: TrustedProxies,
+}
+
+impl AppConfig {
+    /// The URL rotterdam is reached at, for when no request says otherwise: `public_url`, or
+    /// where it listens by default.
+    pub(crate) fn base_url(&self) -> &str {
+        self.public_url.as_deref().unwrap_or("http://localhost:8080")
+    }
 }
 
 #[derive(Clone, Debug)]
 pub(crate) struct AppGitConfig {
     pub path: PathBuf,
-    pub author: String,
     pub author_name: String,
     pub author_email: String,
+    /// Serve fetches ourselves rather than through `git http-backend`.
+    pub native_upload_pack: bool,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub(crate) struct Repo {
     pub name: Cow<'static, str>,
+    /// A git remote (path or URL) to push the index to after every change.
+    pub mirror: Option<String>,
+    pub reserved: Reservations,
+    pub policy: PublishPolicy,
+    /// Users and teams who may read the repo: its index, search, owners and downloads. Unset,
+    /// anyone may; set, all of those need a token, and the index's config.json asks cargo to
+    /// send one.
+    pub readers: Option<Vec<String>>,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -36,16 +83,36 @@ pub(crate) enum Error {
     InvalidConfiguration(&'static str),
 }
 
+/// The largest request body accepted unless `rotterdam.server.max_body_bytes` says otherwise.
+/// Publishes are the only big bodies; crates.io takes `.crate` files of up to 10 MiB, so this
+/// leaves room for those and their metadata with plenty to spare.
+const MAX_BODY_BYTES: u64 = 50 << 20;
+
 
 pub(crate) fn load<P: Deref<Target=Path>+AsRef<Path>>(path: Option<P>) -> Result<AppConfig, Error> {
     let mut result = AppConfig {
+        listen: vec![String::from("127.0.0.1:8080")],
+        admin_listen: None,
+        public_url: None,
+        server: ServerConfig { max_body_bytes: MAX_BODY_BYTES, ..ServerConfig::default() },
+        access_log: None,
+        tracing: None,
         git: AppGitConfig {
             path: env::current_dir().expect("Unable to determine current working directory").join("rotterdam-data").join("git"),
-            author: String::from("rotterdam <rotterdam@rotterdam.jameselford.com>"),
             author_name: String::from("rotterdam"),
             author_email: String::from("rotterdam@rotterdam.jameselford.com"),
+            native_upload_pack: true,
         },
+        binaries: BlobStoreConfig::Filesystem(
+            env::current_dir().expect("Unable to determine current working directory").join("rotterdam-data").join("binaries"),
+        ),
+        scrub: None,
+        maintenance: None,
+        mirror: MirrorConfig::default(),
+        publish: PublishConfig::default(),
+        users: Users::default(),
         repos: HashMap::new(),
+        proxy: TrustedProxies::default(),
     };
 
     if let Some(config_path) = path {
@@ -61,22 +128,146 @@ pub(crate) fn load<P: Deref<Target=Path>+AsRef<Path>>(path: Option<P>) -> Result
                 .ok_or(Error::InvalidConfiguration("git storage path not a valid string"))?;
         let git_path = PathBuf::from(git_path);
 
+        if let Some(listen) = toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("listen")) {
+            result.listen = match listen {
+                toml::Value::String(addr) => vec![addr.clone()],
+                toml::Value::Array(addrs) => {
+                    let mut listen = Vec::new();
+                    for addr in addrs {
+                        let addr = addr.as_str().ok_or(Error::InvalidConfiguration("rotterdam.listen must contain addresses (host:port, unix:/path or systemd)"))?;
+                        listen.push(addr.to_string());
+                    }
+                    listen
+                },
+                _ => return Err(Error::InvalidConfiguration("rotterdam.listen must be an address (host:port, unix:/path or systemd) or a list of them")),
+            };
+        }
+
+        if let Some(admin_listen) = toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("admin_listen")) {
+            let admin_listen = admin_listen.as_str().ok_or(Error::InvalidConfiguration("rotterdam.admin_listen must be an address (host:port, unix:/path or systemd)"))?;
+            if result.listen.iter().any(|l| l == admin_listen) {
+                return Err(Error::InvalidConfiguration("rotterdam.admin_listen must not also appear in rotterdam.listen"));
+            }
+            result.admin_listen = Some(admin_listen.to_string());
+        }
+
+        if let Some(public_url) = toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("public_url")) {
+            let public_url = public_url.as_str()
+                .filter(|url| url.starts_with("http://") || url.starts_with("https://"))
+                .ok_or(Error::InvalidConfiguration("rotterdam.public_url must be an http:// or https:// URL"))?;
+            result.public_url = Some(public_url.trim_end_matches('/').to_string());
+        }
+
         result.git.path = git_path;
+
+        if let Some(upload_pack) = toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("git")).and_then(|gc| gc.get("upload_pack")) {
+            result.git.native_upload_pack = match upload_pack.as_str() {
+                Some("native") => true,
+                Some("http-backend") => false,
+                _ => return Err(Error::InvalidConfiguration("rotterdam.git.upload_pack must be \"native\" or \"http-backend\"")),
+            };
+        }
+
+        if let Some(binaries_path) = toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("binaries")).and_then(|bc| bc.get("filesystem")).and_then(|fs| fs.get("path")) {
+            let binaries_path = binaries_path.as_str().ok_or(Error::InvalidConfiguration("binary storage path not a valid string"))?;
+            result.binaries = BlobStoreConfig::Filesystem(PathBuf::from(binaries_path));
+        }
+
+        if let Some(s3) = toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("binaries")).and_then(|bc| bc.get("s3")) {
+            if toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("binaries")).and_then(|bc| bc.get("filesystem")).is_some() {
+                return Err(Error::InvalidConfiguration("only one of rotterdam.binaries.filesystem and rotterdam.binaries.s3 may be configured"));
+            }
+            result.binaries = BlobStoreConfig::S3(load_s3(s3)?);
+        }
         
+        if let Some(scrub) = toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("scrub")) {
+            let interval = match scrub.get("interval") {
+                None => Duration::from_secs(24 * 60 * 60),
+                Some(toml::Value::Integer(secs)) if *secs > 0 => Duration::from_secs(*secs as u64),
+                Some(_) => return Err(Error::InvalidConfiguration("rotterdam.scrub.interval must be a positive number of seconds")),
+            };
+            let quarantine = match scrub.get("quarantine") {
+                None => false,
+                Some(q) => q.as_bool().ok_or(Error::InvalidConfiguration("rotterdam.scrub.quarantine must be true or false"))?,
+            };
+            result.scrub = Some(ScrubConfig { interval, quarantine });
+        }
+
+        if let Some(maintenance) = toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("maintenance")) {
+            let interval = match maintenance.get("interval") {
+                None => Duration::from_secs(24 * 60 * 60),
+                Some(toml::Value::Integer(secs)) if *secs > 0 => Duration::from_secs(*secs as u64),
+                Some(_) => return Err(Error::InvalidConfiguration("rotterdam.maintenance.interval must be a positive number of seconds")),
+            };
+            let squash_after = match maintenance.get("squash_after") {
+                None => None,
+                Some(toml::Value::Integer(commits)) if *commits > 1 => Some(*commits as u64),
+                Some(_) => return Err(Error::InvalidConfiguration("rotterdam.maintenance.squash_after must be a number of commits greater than 1")),
+            };
+            let archive = match maintenance.get("archive").map(|a| a.as_str()) {
+                None | Some(Some("bundle")) => {
+                    let path = match maintenance.get("archive_path") {
+                        None => None,
+                        Some(path) => Some(PathBuf::from(path.as_str().ok_or(Error::InvalidConfiguration("rotterdam.maintenance.archive_path must be a path"))?)),
+                    };
+                    Archive::Bundle(path)
+                }
+                Some(Some("branch")) => Archive::Branch,
+                Some(_) => return Err(Error::InvalidConfiguration("rotterdam.maintenance.archive must be \"bundle\" or \"branch\"")),
+            };
+            result.maintenance = Some(MaintenanceConfig { interval, squash_after, archive });
+        }
+
+        if let Some(mirror) = toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("mirror")) {
+            if let Some(retries) = mirror.get("retries") {
+                result.mirror.retries = retries.as_integer().filter(|r| *r >= 0).ok_or(Error::InvalidConfiguration("rotterdam.mirror.retries must be a number, 0 or more"))? as u32;
+            }
+            if let Some(delay) = mirror.get("retry_delay") {
+                result.mirror.retry_delay = delay.as_float().or_else(|| delay.as_integer().map(|i| i as f64))
+                    .filter(|secs| *secs > 0.0)
+                    .map(Duration::from_secs_f64)
+                    .ok_or(Error::InvalidConfiguration("rotterdam.mirror.retry_delay must be a positive number of seconds"))?;
+            }
+        }
+
+        if let Some(max_unpacked) = toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("publish")).and_then(|p| p.get("max_unpacked_size")) {
+            result.publish.max_unpacked_bytes = max_unpacked.as_integer().filter(|b| *b > 0)
+                .ok_or(Error::InvalidConfiguration("rotterdam.publish.max_unpacked_size must be a positive number of bytes"))? as u64;
+        }
+
         if let Some(config_repos) = toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("repos")) {
             let mut repos = HashMap::new();
             match config_repos {
                 toml::Value::Table(config_repos) => {
-                    for (name, _info) in config_repos.iter() {
+                    for (name, info) in config_repos.iter() {
                         let name = Cow::from(name.clone());
-                        repos.insert(name.clone(), Repo { name: name.clone() });
+                        let mirror = match info.get("mirror") {
+                            None => None,
+                            Some(mirror) => Some(mirror.as_str().filter(|m| ! m.is_empty()).ok_or(Error::InvalidConfiguration("rotterdam.repos.<name>.mirror must be a git remote path or URL"))?.to_string()),
+                        };
+                        let reserved = match info.get("reserved") {
+                            None => Reservations::default(),
+                            Some(reserved) => Reservations {
+                                names: load_reserved(reserved.get("names"))?,
+                                prefixes: load_reserved(reserved.get("prefixes"))?,
+                            },
+                        };
+                        let policy = match info.get("policy") {
+                            None => PublishPolicy::default(),
+                            Some(policy) => load_policy(policy)?,
+                        };
+                        let readers = match info.get("readers") {
+                            None => None,
+                            Some(readers) => Some(string_list(readers).ok_or(Error::InvalidConfiguration("rotterdam.repos.<name>.readers must be a list of users and teams"))?),
+                        };
+                        repos.insert(name.clone(), Repo { name: name.clone(), mirror, reserved, policy, readers });
                     }
                 },
                 toml::Value::Array(config_repos) => {
                     for name in config_repos {
                         let name = name.as_str().ok_or(Error::InvalidConfiguration("rotterdam.repos must contain repository names when specified as an array"))?;
                         let name = Cow::from(name.to_string());
-                        repos.insert(name.clone(), Repo { name: name.clone() });
+                        repos.insert(name.clone(), Repo { name: name.clone(), ..Repo::default() });
                     }
                 }
                 _ => {
@@ -86,7 +277,193 @@ pub(crate) fn load<P: Deref<Target=Path>+AsRef<Path>>(path: Option<P>) -> Result
 
             result.repos = repos;
         }
+
+        if let Some(users) = toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("users")) {
+            let users = users.as_table().ok_or(Error::InvalidConfiguration("rotterdam.users must be a table of users"))?;
+            let mut identities = Vec::new();
+            for (name, user) in users {
+                let token_sha256 = user.get("token_sha256").and_then(|t| t.as_str())
+                    .filter(|t| crate::index::is_sha256_hex(t))
+                    .ok_or(Error::InvalidConfiguration("rotterdam.users.<name>.token_sha256 must be the SHA-256 of the user's token, as lowercase hex"))?;
+                let teams = match user.get("teams") {
+                    None => Vec::new(),
+                    Some(teams) => string_list(teams).ok_or(Error::InvalidConfiguration("rotterdam.users.<name>.teams must be a list of team names"))?,
+                };
+                let admin = match user.get("admin") {
+                    None => false,
+                    Some(admin) => admin.as_bool().ok_or(Error::InvalidConfiguration("rotterdam.users.<name>.admin must be true or false"))?,
+                };
+                identities.push((token_sha256.to_string(), Identity { name: name.clone(), teams, admin }));
+            }
+            result.users = Users::new(identities);
+        }
+
+        if let Some(server) = toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("server")) {
+            let seconds = |key: &str, default: Duration| -> Result<Duration, Error> {
+                match server.get(key) {
+                    None => Ok(default),
+                    Some(v) => v.as_float().or_else(|| v.as_integer().map(|i| i as f64))
+                        .filter(|secs| *secs > 0.0)
+                        .map(Duration::from_secs_f64)
+                        .ok_or(Error::InvalidConfiguration("rotterdam.server timeouts must be a positive number of seconds")),
+                }
+            };
+            let bytes = |key: &str, default: u64| -> Result<u64, Error> {
+                match server.get(key) {
+                    None => Ok(default),
+                    Some(v) => v.as_integer()
+                        .filter(|b| *b > 0)
+                        .map(|b| b as u64)
+                        .ok_or(Error::InvalidConfiguration("rotterdam.server size limits must be a positive number of bytes")),
+                }
+            };
+
+            let defaults = result.server.clone();
+            result.server = ServerConfig {
+                header_read_timeout: seconds("header_read_timeout", defaults.header_read_timeout)?,
+                body_idle_timeout: seconds("body_idle_timeout", defaults.body_idle_timeout)?,
+                request_deadline: seconds("request_deadline", defaults.request_deadline)?,
+                write_timeout: seconds("write_timeout", defaults.write_timeout)?,
+                max_header_bytes: bytes("max_header_bytes", defaults.max_header_bytes as u64)? as usize,
+                max_body_bytes: bytes("max_body_bytes", defaults.max_body_bytes)?,
+                ..defaults
+            };
+        }
+
+        if let Some(access_log) = toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("access_log")) {
+            let path = access_log.get("path").and_then(|p| p.as_str())
+                .ok_or(Error::InvalidConfiguration("rotterdam.access_log.path must be set to a file path"))?;
+            let format = match access_log.get("format").map(|f| f.as_str()) {
+                None | Some(Some("common")) => AccessLogFormat::Common,
+                Some(Some("json")) => AccessLogFormat::Json,
+                _ => return Err(Error::InvalidConfiguration("rotterdam.access_log.format must be either \"common\" or \"json\"")),
+            };
+            result.access_log = Some(AccessLogConfig { path: PathBuf::from(path), format });
+        }
+
+        if let Some(tracing) = toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("tracing")) {
+            let otlp_endpoint = tracing.get("otlp_endpoint").and_then(|e| e.as_str())
+                .filter(|e| e.starts_with("http://") || e.starts_with("https://"))
+                .ok_or(Error::InvalidConfiguration("rotterdam.tracing.otlp_endpoint must be set to an http(s) URL, e.g. http://localhost:4318/v1/traces"))?;
+            let service_name = match tracing.get("service_name") {
+                None => "rotterdam",
+                Some(name) => name.as_str().ok_or(Error::InvalidConfiguration("rotterdam.tracing.service_name must be a string"))?,
+            };
+            result.tracing = Some(TracingConfig { otlp_endpoint: otlp_endpoint.to_string(), service_name: service_name.to_string() });
+        }
+
+        if let Some(trusted) = toml.get("rotterdam").and_then(|rtrdm| rtrdm.get("proxy")).and_then(|p| p.get("trusted")) {
+            let trusted = trusted.as_array().ok_or(Error::InvalidConfiguration("rotterdam.proxy.trusted must be a list of addresses"))?;
+            let mut ranges = Vec::new();
+            for range in trusted {
+                let range: IpRange = range.as_str()
+                    .and_then(|r| r.parse().ok())
+                    .ok_or(Error::InvalidConfiguration("rotterdam.proxy.trusted must contain IP addresses or CIDR ranges (e.g. 10.0.0.0/8)"))?;
+                ranges.push(range);
+            }
+            result.proxy = TrustedProxies::new(ranges);
+        }
     }
 
     Ok(result)
-}
\ No newline at end of file
+}
+
+fn string_list(value: &toml::Value) -> Option<Vec<String>> {
+    value.as_array()?.iter().map(|v| v.as_str().map(str::to_string)).collect()
+}
+
+/// Reserved names or prefixes: a list (which nobody may publish), or a table of them to the
+/// users and teams who may.
+fn load_reserved(reserved: Option<&toml::Value>) -> Result<Vec<(String, Vec<String>)>, Error> {
+    const INVALID: Error = Error::InvalidConfiguration("rotterdam.repos.<name>.reserved names and prefixes must be a list, or a table of them to lists of users and teams");
+    match reserved {
+        None => Ok(Vec::new()),
+        Some(toml::Value::Table(reserved)) => reserved.iter()
+            .map(|(name, who)| Ok((name.clone(), string_list(who).ok_or(INVALID)?)))
+            .collect(),
+        Some(reserved) => Ok(string_list(reserved).ok_or(INVALID)?.into_iter().map(|name| (name, Vec::new())).collect()),
+    }
+}
+
+fn load_policy(policy: &toml::Value) -> Result<PublishPolicy, Error> {
+    let flag = |key: &str, default: bool| -> Result<bool, Error> {
+        match policy.get(key) {
+            None => Ok(default),
+            Some(v) => v.as_bool().ok_or(Error::InvalidConfiguration("rotterdam.repos.<name>.policy switches (forbid_build_scripts, forbid_proc_macros, monotonic_versions, allow_prerelease, resolvable_dependencies) must be true or false")),
+        }
+    };
+    let list = |key: &str, err: &'static str| -> Result<Option<Vec<String>>, Error> {
+        match policy.get(key) {
+            None => Ok(None),
+            Some(v) => string_list(v).map(Some).ok_or(Error::InvalidConfiguration(err)),
+        }
+    };
+
+    let defaults = PublishPolicy::default();
+    let required_metadata = list("required_metadata", "rotterdam.repos.<name>.policy.required_metadata must be a list of [package] fields")?.unwrap_or_default();
+    if ! required_metadata.iter().all(|field| METADATA_FIELDS.contains(&field.as_str())) {
+        return Err(Error::InvalidConfiguration("rotterdam.repos.<name>.policy.required_metadata may only contain license, description, repository, homepage, documentation, readme, keywords, categories and authors"));
+    }
+    let max_crate_size = match policy.get("max_crate_size") {
+        None => None,
+        Some(size) => Some(size.as_integer().filter(|s| *s > 0).ok_or(Error::InvalidConfiguration("rotterdam.repos.<name>.policy.max_crate_size must be a positive number of bytes"))? as u64),
+    };
+
+    Ok(PublishPolicy {
+        required_metadata,
+        allowed_licenses: list("allowed_licenses", "rotterdam.repos.<name>.policy.allowed_licenses must be a list of SPDX license ids")?,
+        forbid_build_scripts: flag("forbid_build_scripts", defaults.forbid_build_scripts)?,
+        forbid_proc_macros: flag("forbid_proc_macros", defaults.forbid_proc_macros)?,
+        monotonic_versions: flag("monotonic_versions", defaults.monotonic_versions)?,
+        allow_prerelease: flag("allow_prerelease", defaults.allow_prerelease)?,
+        max_crate_size,
+        allowed_registries: list("allowed_registries", "rotterdam.repos.<name>.policy.allowed_registries must be a list of index URLs (or \"crates.io\")")?,
+        resolvable_dependencies: flag("resolvable_dependencies", defaults.resolvable_dependencies)?,
+    })
+}
+
+fn load_s3(s3: &toml::Value) -> Result<S3Config, Error> {
+    let string = |key: &str, env_var: Option<&str>, err: &'static str| -> Result<Option<String>, Error> {
+        match s3.get(key) {
+            Some(value) => value.as_str().map(|v| Some(v.to_string())).ok_or(Error::InvalidConfiguration(err)),
+            None => Ok(env_var.and_then(|var| env::var(var).ok())),
+        }
+    };
+
+    let endpoint = string("endpoint", None, "rotterdam.binaries.s3.endpoint must be a URL")?
+        .and_then(|e| url::Url::parse(&e).ok())
+        .filter(|e| (e.scheme() == "http" || e.scheme() == "https") && e.host_str().is_some())
+        .ok_or(Error::InvalidConfiguration("rotterdam.binaries.s3.endpoint must be set to an http(s) URL, e.g. http://localhost:9000"))?;
+    let bucket = string("bucket", None, "rotterdam.binaries.s3.bucket must be a string")?
+        .ok_or(Error::InvalidConfiguration("rotterdam.binaries.s3.bucket must be set"))?;
+    let region = string("region", Some("AWS_REGION"), "rotterdam.binaries.s3.region must be a string")?
+        .unwrap_or_else(|| String::from("us-east-1"));
+    let access_key_id = string("access_key_id", Some("AWS_ACCESS_KEY_ID"), "rotterdam.binaries.s3.access_key_id must be a string")?
+        .ok_or(Error::InvalidConfiguration("rotterdam.binaries.s3.access_key_id (or AWS_ACCESS_KEY_ID) must be set"))?;
+    let secret_access_key = string("secret_access_key", Some("AWS_SECRET_ACCESS_KEY"), "rotterdam.binaries.s3.secret_access_key must be a string")?
+        .ok_or(Error::InvalidConfiguration("rotterdam.binaries.s3.secret_access_key (or AWS_SECRET_ACCESS_KEY) must be set"))?;
+    let path_style = match s3.get("path_style") {
+        None => true,
+        Some(p) => p.as_bool().ok_or(Error::InvalidConfiguration("rotterdam.binaries.s3.path_style must be true or false"))?,
+    };
+
+    let redirect_downloads = match s3.get("redirect_downloads") {
+        None => false,
+        Some(r) => r.as_bool().ok_or(Error::InvalidConfiguration("rotterdam.binaries.s3.redirect_downloads must be true or false"))?,
+    };
+    let redirect_expiry = match s3.get("redirect_expiry") {
+        None => Duration::from_secs(300),
+        Some(toml::Value::Integer(secs)) if (1..=604_800).contains(secs) => Duration::from_secs(*secs as u64),
+        Some(_) => return Err(Error::InvalidConfiguration("rotterdam.binaries.s3.redirect_expiry must be a number of seconds, at most 604800 (a week)")),
+    };
+
+    Ok(S3Config {
+        endpoint,
+        bucket,
+        region,
+        access_key_id,
+        secret_access_key,
+        path_style,
+        redirect_downloads: if redirect_downloads { Some(redirect_expiry) } else { None },
+    })
+}
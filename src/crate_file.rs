@@ -0,0 +1,524 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Result, anyhow, bail};
+use json::JsonValue;
+
+use crate::config::AppConfig;
+use crate::index;
+
+
+/// Dependencies with no `registry-index` come from crates.io.
+pub(crate) const CRATES_IO_INDEX: &str = "https://github.com/rust-lang/crates.io-index";
+
+/// Something wrong with a `.crate` or its metadata, in words fit to show whoever uploaded it.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub(crate) struct InvalidCrate(pub String);
+
+fn invalid(message: impl Into<String>) -> anyhow::Error {
+    InvalidCrate(message.into()).into()
+}
+
+/// What's in a `.crate`.
+#[derive(Debug)]
+pub(crate) struct Unpacked {
+    pub manifest: toml::Value,
+    /// Every file, directory and link, relative to `<name>-<version>/`.
+    pub files: Vec<PathBuf>,
+}
+
+/// Unpacks a `.crate` (a gzipped tar of `<name>-<version>/...`) without writing anything out,
+/// checking that every path stays under `<name>-<version>/`, that links don't point outside
+/// it, and that it unpacks to at most `max_unpacked` bytes.
+pub(crate) fn unpack(contents: &[u8], max_unpacked: u64) -> Result<Unpacked> {
+    let size = Cell::new(0);
+    let reader = Limited { inner: flate2::read::GzDecoder::new(contents), read: &size, limit: max_unpacked };
+    let result = walk(&mut tar::Archive::new(reader));
+    if size.get() > max_unpacked {
+        bail!(InvalidCrate(format!("The crate unpacks to more than the allowed {} bytes", max_unpacked)));
+    }
+    let (root, manifest, files) = result?;
+
+    let package = manifest.get("package").ok_or_else(|| invalid("Cargo.toml has no [package]"))?;
+    let string = |key: &str| package.get(key).and_then(|v| v.as_str());
+    let name = string("name").ok_or_else(|| invalid("Cargo.toml has no package.name"))?;
+    let vers = string("version").ok_or_else(|| invalid("Cargo.toml has no package.version"))?;
+    if root != Path::new(&format!("{}-{}", name, vers)) {
+        bail!(InvalidCrate(format!("The crate's files are under {}/, not {}-{}/", root.to_string_lossy(), name, vers)));
+    }
+    Ok(Unpacked { manifest, files })
+}
+
+/// The directory everything in the archive is under, the `Cargo.toml` in it, and every path
+/// under it.
+fn walk<R: Read>(archive: &mut tar::Archive<R>) -> Result<(PathBuf, toml::Value, Vec<PathBuf>)> {
+    let unreadable = |e: io::Error| invalid(format!("The crate is not a valid .tar.gz: {}", e));
+
+    let mut root: Option<PathBuf> = None;
+    let mut manifest = None;
+    let mut files = Vec::new();
+    for entry in archive.entries().map_err(unreadable)? {
+        let mut entry = entry.map_err(unreadable)?;
+        let kind = entry.header().entry_type();
+        if kind.is_pax_global_extensions() || kind.is_pax_local_extensions() {
+            continue;
+        }
+        let path = entry.path().map_err(unreadable)?.into_owned();
+        if ! path.components().all(|c| matches!(c, Component::Normal(_))) || path.components().count() == 0 {
+            bail!(InvalidCrate(format!("Invalid path in the crate: {}", path.to_string_lossy())));
+        }
+        let top = Path::new(path.iter().next().unwrap_or_default());
+        match &root {
+            None => root = Some(top.to_path_buf()),
+            Some(root) if root != top => bail!(InvalidCrate(format!("{} is outside {}/", path.to_string_lossy(), root.to_string_lossy()))),
+            Some(_) => {}
+        }
+
+        if kind.is_symlink() || kind.is_hard_link() {
+            let target = entry.link_name().map_err(unreadable)?.ok_or_else(|| invalid(format!("{} is a link to nowhere", path.to_string_lossy())))?;
+            // Symlinks are relative to where they are; hard links to the top of the archive
+            let base = if kind.is_symlink() { path.parent().unwrap_or(top) } else { Path::new("") };
+            if ! resolve(base, &target).is_some_and(|t| t.starts_with(top) && t != top) {
+                bail!(InvalidCrate(format!("{} links outside the crate (to {})", path.to_string_lossy(), target.to_string_lossy())));
+            }
+        } else if ! (kind.is_file() || kind.is_contiguous() || kind.is_dir()) {
+            bail!(InvalidCrate(format!("{} is not a file, directory or link", path.to_string_lossy())));
+        }
+
+        if kind.is_file() && path.components().count() == 2 && path.ends_with("Cargo.toml") {
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).map_err(|e| invalid(format!("Unable to read Cargo.toml: {}", e)))?;
+            manifest = Some(contents.parse::<toml::Value>().map_err(|e| invalid(format!("Cargo.toml is not valid TOML: {}", e)))?);
+        }
+        files.extend(path.strip_prefix(top).ok().filter(|p| ! p.as_os_str().is_empty()).map(Path::to_path_buf));
+    }
+
+    match (root, manifest) {
+        (Some(root), Some(manifest)) => Ok((root, manifest, files)),
+        _ => bail!(InvalidCrate(String::from("No Cargo.toml in the crate"))),
+    }
+}
+
+/// Where `target` ends up when followed from `base`, or `None` if it climbs out of the archive.
+fn resolve(base: &Path, target: &Path) -> Option<PathBuf> {
+    let mut resolved: Vec<Component> = base.components().collect();
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => resolved.push(component),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop()?;
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(resolved.iter().collect())
+}
+
+/// Counts bytes as they're read, failing once more than `limit` have been.
+struct Limited<'a, R> {
+    inner: R,
+    read: &'a Cell<u64>,
+    limit: u64,
+}
+
+impl<R: Read> Read for Limited<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.set(self.read.get() + n as u64);
+        if self.read.get() > self.limit {
+            return Err(io::Error::other("unpacked size limit exceeded"));
+        }
+        Ok(n)
+    }
+}
+
+/// How dependencies' registries are written in one repo's index. However cargo spelled them,
+/// crates.io is always [`CRATES_IO_INDEX`], the repo itself is no registry at all, and other
+/// repos here are the index URL their `config.json` gives. Any index URL ending in
+/// `/repo/<name>/index` is taken to be one of this server's repos.
+pub(crate) struct Registries {
+    repo: String,
+    /// Every configured repo's index URL.
+    index_urls: BTreeMap<String, String>,
+}
+
+impl Registries {
+    pub(crate) fn load(config: &AppConfig, repo: &str) -> Self {
+        let index_urls = config.repos.keys()
+            .map(|name| (name.to_string(), index::index_url(&config.git.path.join(name.as_ref()), config.base_url(), name)))
+            .collect();
+        Registries { repo: repo.to_string(), index_urls }
+    }
+
+    /// How the registry `url` of the dependency `dep` is written in the index.
+    fn translate(&self, dep: &str, url: &str) -> Result<Option<String>> {
+        let bare = url.trim_start_matches("sparse+").trim_start_matches("registry+").trim_end_matches('/');
+        let bare = bare.strip_suffix(".git").unwrap_or(bare);
+        let location = bare.split_once("://").map_or(bare, |(_, location)| location);
+        if location == "github.com/rust-lang/crates.io-index" || location == "index.crates.io" {
+            return Ok(Some(CRATES_IO_INDEX.to_string()));
+        }
+
+        let repo = location.strip_suffix("/index").and_then(|l| l.rsplit_once("/repo/")).map(|(_, repo)| repo).filter(|repo| ! repo.contains('/'));
+        match repo {
+            Some(repo) if repo == self.repo => Ok(None),
+            Some(repo) => match self.index_urls.get(repo) {
+                Some(index_url) => Ok(Some(index_url.clone())),
+                None => bail!(InvalidCrate(format!("The dependency {} comes from {}, but there's no repo called {} here", dep, url, repo))),
+            },
+            None => Ok(Some(url.to_string())),
+        }
+    }
+
+    /// The repo here that a dependency's (translated) `registry` refers to, if any.
+    pub(crate) fn repo_of(&self, registry: Option<&str>) -> Option<&str> {
+        match registry {
+            None => Some(&self.repo),
+            Some(url) => self.index_urls.iter().find(|(_, index_url)| *index_url == url).map(|(repo, _)| repo.as_str()),
+        }
+    }
+}
+
+/// Checks that the name, version and dependencies cargo sent with a publish (the JSON
+/// metadata) are the ones in the crate's own `Cargo.toml`.
+pub(crate) fn check_metadata(metadata: &JsonValue, manifest: &toml::Value, registries: &Registries) -> Result<()> {
+    let package = manifest.get("package");
+    for (field, key) in [("name", "name"), ("vers", "version")] {
+        let (sent, packaged) = (metadata[field].as_str(), package.and_then(|p| p.get(key)).and_then(|v| v.as_str()));
+        if sent != packaged {
+            bail!(InvalidCrate(format!("The {} in the metadata ({}) doesn't match Cargo.toml ({})", field, sent.unwrap_or("none"), packaged.unwrap_or("none"))));
+        }
+    }
+
+    let mut packaged = Vec::new();
+    collect_all_deps(manifest, registries, &mut packaged)?;
+    let mut packaged: Vec<(String, String)> = packaged.iter().map(comparable_dep).collect();
+    let mut sent = Vec::new();
+    for dep in metadata["deps"].members() {
+        // Publish metadata names the package, and gives any rename separately; the index the other way round
+        let name = dep["explicit_name_in_toml"].as_str().or(dep["name"].as_str());
+        let registry = match dep["registry"].as_str() {
+            Some(url) => registries.translate(name.unwrap_or_default(), url)?,
+            None => None,
+        };
+        let mut index_dep = json::object! {
+            name: name,
+            req: dep["version_req"].clone(),
+            features: dep["features"].clone(),
+            optional: dep["optional"].clone(),
+            default_features: dep["default_features"].clone(),
+            target: dep["target"].clone(),
+            kind: dep["kind"].clone(),
+            registry: registry,
+        };
+        if dep["explicit_name_in_toml"].is_string() {
+            index_dep["package"] = dep["name"].clone();
+        }
+        sent.push(comparable_dep(&index_dep));
+    }
+    packaged.sort();
+    sent.sort();
+
+    if let Some((_, dep)) = sent.iter().find(|d| ! packaged.contains(d)) {
+        bail!(InvalidCrate(format!("The metadata has a dependency that doesn't match Cargo.toml: {}", dep)));
+    }
+    if let Some((_, dep)) = packaged.iter().find(|d| ! sent.contains(d)) {
+        bail!(InvalidCrate(format!("Cargo.toml has a dependency that isn't in the metadata: {}", dep)));
+    }
+    if sent.len() != packaged.len() {
+        bail!(InvalidCrate(String::from("The metadata and Cargo.toml list different numbers of dependencies")));
+    }
+    Ok(())
+}
+
+/// An index dependency, reduced to what has to match and written the same way however it
+/// was spelled (cargo sends `^1` where `Cargo.toml` says `1`), along with a short description.
+fn comparable_dep(dep: &JsonValue) -> (String, String) {
+    let req = dep["req"].as_str().unwrap_or("*").split(',').map(|r| {
+        let r: String = r.chars().filter(|c| ! c.is_whitespace()).collect();
+        if r.starts_with(|c: char| c.is_ascii_digit()) { format!("^{}", r) } else { r }
+    }).collect::<Vec<_>>().join(", ");
+    let mut features: Vec<&str> = dep["features"].members().filter_map(|f| f.as_str()).collect();
+    features.sort_unstable();
+    let kind = dep["kind"].as_str().unwrap_or("normal");
+    let description = format!("{} {} ({}{})", dep["name"], req, kind, dep["target"].as_str().map(|t| format!(", for {}", t)).unwrap_or_default());
+    let comparable = json::object! {
+        name: dep["name"].clone(),
+        package: dep["package"].clone(),
+        req: req,
+        features: features,
+        optional: dep["optional"].as_bool().unwrap_or(false),
+        default_features: dep["default_features"].as_bool().unwrap_or(true),
+        target: dep["target"].clone(),
+        kind: kind,
+        registry: dep["registry"].clone(),
+    }.dump();
+    (comparable, description)
+}
+
+/// Builds the index line cargo would have published for `manifest`. `yanked` starts out false.
+pub(crate) fn index_line(manifest: &toml::Value, cksum: &str, registries: &Registries) -> Result<JsonValue> {
+    let package = manifest.get("package").ok_or_else(|| anyhow!("Cargo.toml has no [package]"))?;
+    let string = |key: &str| package.get(key).and_then(|v| v.as_str());
+    let name = string("name").ok_or_else(|| anyhow!("Cargo.toml has no package.name"))?;
+    let vers = string("version").ok_or_else(|| anyhow!("Cargo.toml has no package.version"))?;
+
+    let mut deps = Vec::new();
+    collect_all_deps(manifest, registries, &mut deps)?;
+
+    let mut features = JsonValue::new_object();
+    let mut features2 = JsonValue::new_object();
+    if let Some(table) = manifest.get("features").and_then(|f| f.as_table()) {
+        for (feature, enables) in table {
+            let enables: Vec<&str> = enables.as_array().map(|a| a.iter().filter_map(|v| v.as_str()).collect()).unwrap_or_default();
+            // Cargo keeps the newer feature syntax out of `features`, so old cargos can still read it
+            let uses_new_syntax = enables.iter().any(|e| e.starts_with("dep:") || e.contains("?/"));
+            let into = if uses_new_syntax { &mut features2 } else { &mut features };
+            into[feature.as_str()] = enables.into();
+        }
+    }
+
+    let mut line = json::object! { name: name, vers: vers };
+    line["deps"] = deps.into();
+    line["cksum"] = cksum.into();
+    line["features"] = features;
+    line["yanked"] = false.into();
+    line["links"] = string("links").into();
+    if ! features2.is_empty() {
+        line["v"] = 2.into();
+        line["features2"] = features2;
+    }
+    if let Some(rust_version) = string("rust-version") {
+        line["rust_version"] = rust_version.into();
+    }
+    Ok(line)
+}
+
+fn collect_all_deps(manifest: &toml::Value, registries: &Registries, deps: &mut Vec<JsonValue>) -> Result<()> {
+    collect_deps(manifest, None, registries, deps)?;
+    if let Some(targets) = manifest.get("target").and_then(|t| t.as_table()) {
+        for (target, table) in targets {
+            collect_deps(table, Some(target), registries, deps)?;
+        }
+    }
+    Ok(())
+}
+
+fn collect_deps(table: &toml::Value, target: Option<&str>, registries: &Registries, deps: &mut Vec<JsonValue>) -> Result<()> {
+    const KINDS: &[(&str, &str)] = &[
+        ("dependencies", "normal"),
+        ("dev-dependencies", "dev"),
+        ("dev_dependencies", "dev"),
+        ("build-dependencies", "build"),
+        ("build_dependencies", "build"),
+    ];
+
+    for (section, kind) in KINDS {
+        let section = match table.get(section).and_then(|s| s.as_table()) {
+            Some(section) => section,
+            None => continue,
+        };
+        for (dep_name, spec) in section {
+            let mut dep = json::object! {
+                name: dep_name.as_str(),
+                req: "*",
+                features: [],
+                optional: false,
+                default_features: true,
+                target: target,
+                kind: *kind,
+                registry: CRATES_IO_INDEX,
+            };
+            match spec {
+                toml::Value::String(req) => dep["req"] = req.as_str().into(),
+                toml::Value::Table(spec) => {
+                    let string = |key: &str| spec.get(key).and_then(|v| v.as_str());
+                    let flag = |key: &str| spec.get(key).or_else(|| spec.get(&key.replace('-', "_"))).and_then(|v| v.as_bool());
+                    if let Some(req) = string("version") {
+                        dep["req"] = req.into();
+                    }
+                    if let Some(features) = spec.get("features").and_then(|f| f.as_array()) {
+                        dep["features"] = features.iter().filter_map(|f| f.as_str()).collect::<Vec<_>>().into();
+                    }
+                    dep["optional"] = flag("optional").unwrap_or(false).into();
+                    dep["default_features"] = flag("default-features").unwrap_or(true).into();
+                    if let Some(package) = string("package") {
+                        dep["package"] = package.into();
+                    }
+                    if let Some(url) = string("registry-index") {
+                        match registries.translate(dep_name, url)? {
+                            Some(registry) => dep["registry"] = registry.into(),
+                            None => { dep.remove("registry"); }
+                        }
+                    }
+                }
+                _ => bail!("Unexpected dependency spec for {}", dep_name),
+            }
+            deps.push(dep);
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// A `.crate` holding `files`, each given as `(path, contents)`, with paths written as-is.
+    pub(crate) fn dot_crate(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()));
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, contents.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn with_link(kind: tar::EntryType, path: &str, target: &str) -> Vec<u8> {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(MANIFEST.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, "foo-1.0.0/Cargo.toml", MANIFEST.as_bytes()).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_size(0);
+        header.as_old_mut().linkname[..target.len()].copy_from_slice(target.as_bytes());
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_cksum();
+        builder.append(&header, &[][..]).unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    const MANIFEST: &str = r#"
+        [package]
+        name = "foo"
+        version = "1.0.0"
+
+        [dependencies]
+        serde = { version = "1.0", features = ["std", "derive"] }
+        bar = { version = "0.1", registry-index = "http://localhost:8080/repo/repo/index", package = "bar-impl" }
+
+        [target.'cfg(unix)'.build-dependencies]
+        cc = ">= 1, < 2"
+    "#;
+
+    fn registries() -> Registries {
+        let index_urls = [("repo", "http://localhost:8080/repo/repo/index"), ("shared", "https://crates.example.com/repo/shared/index")];
+        Registries { repo: String::from("repo"), index_urls: index_urls.iter().map(|(r, u)| (r.to_string(), u.to_string())).collect() }
+    }
+
+    fn invalid_reason(result: Result<Unpacked>) -> String {
+        result.unwrap_err().downcast::<InvalidCrate>().expect("a client error").0
+    }
+
+    #[test]
+    fn unpacks_well_formed_crates() {
+        let contents = dot_crate(&[("foo-1.0.0/Cargo.toml", MANIFEST), ("foo-1.0.0/src/lib.rs", "")]);
+        let unpacked = unpack(&contents, 1 << 20).unwrap();
+        assert_eq!(unpacked.manifest["package"]["name"].as_str(), Some("foo"));
+        assert_eq!(unpacked.files, [Path::new("Cargo.toml"), Path::new("src/lib.rs")]);
+
+        let contents = with_link(tar::EntryType::Symlink, "foo-1.0.0/src/lib.rs", "../README.md");
+        assert!(unpack(&contents, 1 << 20).is_ok());
+    }
+
+    #[test]
+    fn refuses_crates_that_escape_their_directory() {
+        let reason = invalid_reason(unpack(&dot_crate(&[("foo-1.0.0/Cargo.toml", MANIFEST), ("foo-1.0.0/../../etc/passwd", "")]), 1 << 20));
+        assert!(reason.contains("Invalid path"), "{}", reason);
+
+        let reason = invalid_reason(unpack(&dot_crate(&[("foo-1.0.0/Cargo.toml", MANIFEST), ("bar-1.0.0/src/lib.rs", "")]), 1 << 20));
+        assert!(reason.contains("outside foo-1.0.0/"), "{}", reason);
+
+        let reason = invalid_reason(unpack(&dot_crate(&[("foo-2.0.0/Cargo.toml", MANIFEST)]), 1 << 20));
+        assert!(reason.contains("not foo-1.0.0/"), "{}", reason);
+
+        for (kind, target) in [(tar::EntryType::Symlink, "../../../etc/passwd"), (tar::EntryType::Symlink, "/etc/passwd"), (tar::EntryType::Link, "etc/passwd")] {
+            let reason = invalid_reason(unpack(&with_link(kind, "foo-1.0.0/src/lib.rs", target), 1 << 20));
+            assert!(reason.contains("links outside the crate"), "{}", reason);
+        }
+
+        let reason = invalid_reason(unpack(&dot_crate(&[("foo-1.0.0/src/lib.rs", "")]), 1 << 20));
+        assert!(reason.contains("No Cargo.toml"), "{}", reason);
+        let reason = invalid_reason(unpack(b"not gzip", 1 << 20));
+        assert!(reason.contains("not a valid .tar.gz"), "{}", reason);
+    }
+
+    #[test]
+    fn limits_unpacked_size() {
+        let padding = "x".repeat(64 * 1024);
+        let contents = dot_crate(&[("foo-1.0.0/Cargo.toml", MANIFEST), ("foo-1.0.0/padding", &padding)]);
+        assert!(contents.len() < 4096);
+        assert!(unpack(&contents, 128 * 1024).is_ok());
+        let reason = invalid_reason(unpack(&contents, 32 * 1024));
+        assert!(reason.contains("more than the allowed 32768 bytes"), "{}", reason);
+    }
+
+    #[test]
+    fn checks_metadata_against_the_manifest() {
+        let manifest: toml::Value = MANIFEST.parse().unwrap();
+        let mut metadata = json::object! {
+            name: "foo",
+            vers: "1.0.0",
+            deps: [
+                { name: "serde", version_req: "^1.0", features: ["derive", "std"], optional: false, default_features: true, target: null, kind: "normal", registry: CRATES_IO_INDEX },
+                { name: "bar-impl", explicit_name_in_toml: "bar", version_req: "^0.1", features: [], optional: false, default_features: true, target: null, kind: "normal", registry: null },
+                { name: "cc", version_req: ">=1, <2", features: [], optional: false, default_features: true, target: "cfg(unix)", kind: "build", registry: CRATES_IO_INDEX },
+            ],
+        };
+        check_metadata(&metadata, &manifest, &registries()).unwrap();
+
+        let invalid_reason = |metadata: &JsonValue| check_metadata(metadata, &manifest, &registries()).unwrap_err().downcast::<InvalidCrate>().unwrap().0;
+        metadata["deps"][0]["version_req"] = "^2".into();
+        assert!(invalid_reason(&metadata).ends_with("doesn't match Cargo.toml: serde ^2 (normal)"));
+        metadata["deps"].array_remove(0);
+        assert!(invalid_reason(&metadata).ends_with("isn't in the metadata: serde ^1.0 (normal)"));
+        metadata["vers"] = "1.0.1".into();
+        assert!(invalid_reason(&metadata).contains("The vers in the metadata (1.0.1)"));
+    }
+
+    #[test]
+    fn translates_dependency_registries() {
+        let registries = registries();
+        let translate = |url: &str| registries.translate("dep", url).map_err(|e| e.downcast::<InvalidCrate>().unwrap().0);
+        for crates_io in [CRATES_IO_INDEX, "https://github.com/rust-lang/crates.io-index.git", "sparse+https://index.crates.io/", "registry+https://github.com/rust-lang/crates.io-index"] {
+            assert_eq!(translate(crates_io).unwrap().as_deref(), Some(CRATES_IO_INDEX));
+        }
+        assert_eq!(translate("sparse+http://127.0.0.1:8080/repo/repo/index/").unwrap(), None);
+        assert_eq!(translate("http://127.0.0.1:8080/repo/shared/index").unwrap().as_deref(), Some("https://crates.example.com/repo/shared/index"));
+        assert_eq!(translate("https://elsewhere.example.com/index/").unwrap().as_deref(), Some("https://elsewhere.example.com/index/"));
+        assert_eq!(translate("http://localhost:8080/repo/gone/index").unwrap_err(), "The dependency dep comes from http://localhost:8080/repo/gone/index, but there's no repo called gone here");
+
+        assert_eq!(registries.repo_of(None), Some("repo"));
+        assert_eq!(registries.repo_of(Some("https://crates.example.com/repo/shared/index")), Some("shared"));
+        assert_eq!(registries.repo_of(Some(CRATES_IO_INDEX)), None);
+
+        // Both sides are translated before they're compared
+        let manifest: toml::Value = r#"
+            [package]
+            name = "foo"
+            version = "1.0.0"
+            [dependencies]
+            common = { version = "1", registry-index = "http://127.0.0.1:8080/repo/shared/index" }
+        "#.parse().unwrap();
+        let metadata = json::object! {
+            name: "foo",
+            vers: "1.0.0",
+            deps: [{ name: "common", version_req: "^1", features: [], optional: false, default_features: true, target: null, kind: "normal", registry: "sparse+http://localhost:8080/repo/shared/index/" }],
+        };
+        check_metadata(&metadata, &manifest, &registries).unwrap();
+        let line = index_line(&manifest, &"0".repeat(64), &registries).unwrap();
+        assert_eq!(line["deps"][0]["registry"], "https://crates.example.com/repo/shared/index");
+    }
+}
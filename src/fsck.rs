@@ -0,0 +1,282 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use json::JsonValue;
+
+use crate::blob_store::{self, BlobStore};
+use crate::config::{AppConfig, AppGitConfig};
+use crate::index::{self, IndexEntry};
+use crate::index_writer::{Edit, IndexWriter};
+use crate::names;
+use crate::scrub;
+
+
+/// Something wrong with a repo's index or stored tarballs.
+#[derive(Debug)]
+struct Problem {
+    kind: &'static str,
+    /// The index file or blob key at fault.
+    location: String,
+    detail: String,
+    repaired: bool,
+}
+
+impl Problem {
+    fn new(kind: &'static str, location: impl Into<String>, detail: impl Into<String>) -> Self {
+        Problem { kind, location: location.into(), detail: detail.into(), repaired: false }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        json::object! {
+            kind: self.kind,
+            location: self.location.as_str(),
+            detail: self.detail.as_str(),
+            repaired: self.repaired,
+        }
+    }
+}
+
+/// Checks every configured repo, returning whether they're all consistent (after any
+/// repairs) along with a JSON report of what was found.
+pub(crate) fn run(config: &AppConfig, blobs: &dyn BlobStore, repair: bool) -> Result<(bool, JsonValue)> {
+    let mut repos: Vec<_> = config.repos.keys().collect();
+    repos.sort();
+
+    let mut consistent = true;
+    let mut report = json::object! { repos: {} };
+    for repo in repos {
+        let problems = check_repo(&config.git, repo, blobs, repair)
+            .with_context(|| format!("Checking {}", repo))?;
+        let outstanding = problems.iter().filter(|p| ! p.repaired).count();
+        consistent &= outstanding == 0;
+
+        report["repos"][repo.as_ref()] = json::object! {
+            status: if outstanding == 0 { "ok" } else { "inconsistent" },
+            problems: problems.iter().map(Problem::to_json).collect::<Vec<_>>(),
+        };
+    }
+    report["status"] = if consistent { "ok" } else { "inconsistent" }.into();
+
+    Ok((consistent, report))
+}
+
+fn check_repo(git: &AppGitConfig, repo: &str, blobs: &dyn BlobStore, repair: bool) -> Result<Vec<Problem>> {
+    let index_root = git.path.join(repo);
+    if ! index_root.join(".git").is_dir() {
+        bail!("{} is not a git repository", index_root.to_string_lossy());
+    }
+
+    let mut problems = Vec::new();
+    let mut entries = Vec::new();
+    let mut names_by_file: BTreeMap<PathBuf, String> = BTreeMap::new();
+    let mut moves = Vec::new();
+
+    for file in index_files(&index_root)? {
+        let display = file.to_string_lossy().to_string();
+        let reader = BufReader::new(std::fs::File::open(index_root.join(&file))?);
+        let mut file_entries = Vec::new();
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match IndexEntry::parse(&line) {
+                Ok(entry) => file_entries.push(entry),
+                Err(e) => problems.push(Problem::new("unparseable_line", format!("{}:{}", display, n + 1), format!("{:#}", e))),
+            }
+        }
+
+        let names: BTreeSet<_> = file_entries.iter().map(|e| e.name.as_str()).collect();
+        if names.len() > 1 {
+            let names: Vec<_> = names.into_iter().collect();
+            problems.push(Problem::new("mixed_names", display.clone(), format!("Entries name different crates: {}", names.join(", "))));
+        }
+
+        if let Some(entry) = file_entries.first() {
+            let expected = index::index_path(&entry.name);
+            if expected != file {
+                let mut problem = Problem::new(
+                    "misplaced_file",
+                    display.clone(),
+                    format!("Cargo looks for {} at {}", entry.name, expected.to_string_lossy()),
+                );
+                if repair && ! index_root.join(&expected).exists() {
+                    moves.push((file.clone(), expected));
+                    problem.repaired = true;
+                }
+                problems.push(problem);
+            }
+            names_by_file.insert(file.clone(), entry.name.clone());
+        }
+        entries.extend(file_entries);
+    }
+
+    if ! moves.is_empty() {
+        commit_moves(git, repo, moves)?;
+    }
+
+    // Cargo treats names as equal ignoring case and `-`/`_`, so should we
+    let mut by_normalized: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for name in names_by_file.values() {
+        by_normalized.entry(names::normalize(name)).or_default().push(name);
+    }
+    for (normalized, names) in by_normalized.iter().filter(|(_, names)| names.len() > 1) {
+        problems.push(Problem::new("name_collision", normalized.clone(), format!("Distinct index files for {}", names.join(", "))));
+    }
+
+    let mut referenced = BTreeSet::new();
+    for entry in entries.iter() {
+        let key = blob_store::tarball_key(repo, &entry.cksum);
+        referenced.insert(key.clone());
+        let location = format!("{} {}", entry.name, entry.vers);
+
+        let mut blob = match blobs.get(&key)? {
+            Some(blob) => blob,
+            None => {
+                problems.push(Problem::new("missing_blob", location, format!("No tarball at {}", key)));
+                continue;
+            }
+        };
+        let actual = blob_store::sha256_hex(&mut blob.reader)?;
+        if actual != entry.cksum {
+            let mut problem = Problem::new("corrupt_blob", location, format!("{} hashes to {}", key, actual));
+            if repair {
+                // Still not repaired: the index names a tarball we no longer have, and only
+                // a re-upload can fix that
+                let to = scrub::move_aside(blobs, &key, &format!("{}/quarantine/{}", repo, entry.cksum))?;
+                problem.detail = format!("{}; quarantined as {}", problem.detail, to);
+            }
+            problems.push(problem);
+        }
+    }
+
+    for blob in blobs.list(&format!("{}/sha256/", repo))? {
+        if referenced.contains(&blob.key) {
+            continue;
+        }
+        let mut problem = Problem::new("orphaned_blob", blob.key.clone(), "No index entry refers to this tarball");
+        if repair {
+            let cksum = blob_store::tarball_cksum(&blob.key).unwrap_or("unnamed").to_string();
+            let to = scrub::move_aside(blobs, &blob.key, &format!("{}/orphaned/{}", repo, cksum))?;
+            problem.detail = format!("{}; moved to {}", problem.detail, to);
+            problem.repaired = true;
+        }
+        problems.push(problem);
+    }
+
+    Ok(problems)
+}
+
+/// Every crate file in the index checkout, relative to its root.
+fn index_files(index_root: &Path) -> Result<Vec<PathBuf>> {
+    fn walk(root: &Path, relative: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+        for entry in std::fs::read_dir(root.join(relative))? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name.to_string_lossy().starts_with('.') || (relative.as_os_str().is_empty() && name == "config.json") {
+                continue;
+            }
+            let path = relative.join(&name);
+            if entry.file_type()?.is_dir() {
+                walk(root, &path, files)?;
+            } else {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    walk(index_root, Path::new(""), &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+/// Moves index files through the repo's index writer, so the moves land in one commit and
+/// can't clobber anything committed meanwhile. Files whose destination has appeared since are
+/// left where they are.
+fn commit_moves(git: &AppGitConfig, repo: &str, moves: Vec<(PathBuf, PathBuf)>) -> Result<()> {
+    let writer = IndexWriter::start(git, repo)?;
+    writer.mutate("Move index files to where cargo expects them", Box::new(move |index_root: &Path| {
+        let mut edits = Vec::new();
+        for (from, to) in moves {
+            if index_root.join(&to).exists() {
+                continue;
+            }
+            let contents = std::fs::read(index_root.join(&from)).with_context(|| format!("Reading {}", from.to_string_lossy()))?;
+            edits.push(Edit { path: to, contents: Some(contents) });
+            edits.push(Edit { path: from, contents: None });
+        }
+        Ok(edits)
+    }))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::process::{Command, Stdio};
+
+    fn line(name: &str, vers: &str, cksum: &str) -> String {
+        format!(r#"{{"name":"{}","vers":"{}","deps":[],"cksum":"{}","features":{{}},"yanked":false}}"#, name, vers, cksum)
+    }
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git").current_dir(dir).args(args).stdout(Stdio::null()).stderr(Stdio::null()).status().unwrap();
+        assert!(status.success(), "git {:?}", args);
+    }
+
+    #[test]
+    fn reports_and_repairs_inconsistencies() {
+        let registry = crate::app::test::TestRegistry::new();
+        let (config, blobs, index_root) = (&registry.config, &registry.blobs, registry.index_root());
+        std::fs::create_dir_all(&index_root).unwrap();
+
+        let stored = |contents: &[u8]| {
+            let cksum = blob_store::sha256_hex(&mut &contents[..]).unwrap();
+            blobs.put(&blob_store::tarball_key("repo", &cksum), &mut &contents[..]).unwrap();
+            cksum
+        };
+        let good = stored(b"good");
+        let orphan = stored(b"orphan");
+        let missing = blob_store::sha256_hex(&mut &b"missing"[..]).unwrap();
+
+        let files = [
+            ("3/f/foo", format!("{}\n{}\n", line("foo", "1.0.0", &good), line("foo", "1.1.0", &missing))),
+            ("wr/on/g-place", line("wrong-place", "0.1.0", &good)),
+            ("my/-c/my-crate", line("my-crate", "0.1.0", &good)),
+            ("my/_c/my_crate", line("my_crate", "0.1.0", &good)),
+            ("3/b/bad", String::from("{ not json\n")),
+        ];
+        for (path, contents) in files.iter() {
+            let path = index_root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        git(&index_root, &["init", "-q", "-b", "master"]);
+        git(&index_root, &["add", "."]);
+        git(&index_root, &["-c", "user.name=t", "-c", "user.email=t@t", "commit", "-q", "-m", "init"]);
+
+        let (consistent, report) = run(config, blobs, false).unwrap();
+        assert!(!consistent);
+        let kinds: BTreeSet<_> = report["repos"]["repo"]["problems"].members().map(|p| p["kind"].as_str().unwrap().to_string()).collect();
+        let expected: BTreeSet<_> = ["unparseable_line", "misplaced_file", "name_collision", "missing_blob", "orphaned_blob"].iter().map(|k| k.to_string()).collect();
+        assert_eq!(kinds, expected);
+
+        run(config, blobs, true).unwrap();
+        assert!(index_root.join("wr/on/wrong-place").is_file());
+        assert!(!index_root.join("wr/on/g-place").exists());
+        let last_commit = Command::new("git").current_dir(&index_root).args(["log", "-1", "--format=%an <%ae>%n%s"]).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&last_commit.stdout), "rotterdam <rotterdam@rotterdam.jameselford.com>\n(rotterdam): Move index files to where cargo expects them\n");
+        let status = Command::new("git").current_dir(&index_root).args(["status", "--porcelain"]).output().unwrap();
+        assert!(status.stdout.is_empty(), "{}", String::from_utf8_lossy(&status.stdout));
+        assert!(!blobs.exists(&blob_store::tarball_key("repo", &orphan)).unwrap());
+
+        let (_, report) = run(config, blobs, false).unwrap();
+        let kinds: BTreeSet<_> = report["repos"]["repo"]["problems"].members().map(|p| p["kind"].as_str().unwrap().to_string()).collect();
+        let expected: BTreeSet<_> = ["unparseable_line", "name_collision", "missing_blob"].iter().map(|k| k.to_string()).collect();
+        assert_eq!(kinds, expected);
+    }
+}
@@ -1,10 +1,12 @@
 
 use super::config::AppConfig;
-use super::{Request, TcpResponseWriter, Response};
+use super::metrics::{Metrics, labels};
+use super::publish::UnreadableBody;
+use super::{Request, ConnectionResponseWriter, Response};
 
 use anyhow::{Result, Context, bail};
 
-use std::{ffi::OsString, io::{Read}, process::Command};
+use std::{ffi::OsString, io::{BufRead, Read, Write}, process::Command, time::Instant};
 use std::process::{Stdio};
 
 
@@ -12,7 +14,7 @@ use std::process::{Stdio};
 fn as_os_str(bytes_from_network: &[u8]) -> OsString {
     use std::os::unix::ffi::OsStrExt;
     use std::ffi::OsStr;
-    let result = OsStr::from_bytes(&bytes_from_network);
+    let result = OsStr::from_bytes(bytes_from_network);
     result.to_os_string()
 }
 
@@ -25,7 +27,7 @@ fn as_os_str(bytes_from_network: &[u8]) -> OsString {
     OsString::from_wide(&bytes_from_network)
 }
 
-pub(crate) fn handle(config: &AppConfig, req: &mut dyn Request, mut resp: TcpResponseWriter) -> Result<()> {
+pub(crate) fn handle(config: &AppConfig, metrics: &Metrics, req: &mut dyn Request, mut resp: ConnectionResponseWriter) -> Result<()> {
     let (git_cgi_path, repo_name) = {
         let path = req.path(); // /repo/<repo_name>/index/...
         let mut parts = path.splitn(5, '/');
@@ -43,10 +45,10 @@ pub(crate) fn handle(config: &AppConfig, req: &mut dyn Request, mut resp: TcpRes
 
         let (repo_name, rest) = (repo_name.unwrap(), rest.unwrap());
 
-        (format!("/{}/.git/{}", repo_name, rest), repo_name)
+        (format!("/{}/.git/{}", repo_name, rest), repo_name.to_string())
     };
 
-    let repo = config.repos.get(repo_name);
+    let repo = config.repos.get(repo_name.as_str());
     if repo.is_none() {
         log::debug!("Repo not found: {}", repo_name);
         resp.send_response(Response::err(404))?;
@@ -98,8 +100,9 @@ pub(crate) fn handle(config: &AppConfig, req: &mut dyn Request, mut resp: TcpRes
     git_command.env("PATH_INFO", &git_cgi_path);
     
 
+    let started = Instant::now();
     let mut git = git_command
-        .args(&["http-backend"])
+        .args(["http-backend"])
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -110,11 +113,34 @@ pub(crate) fn handle(config: &AppConfig, req: &mut dyn Request, mut resp: TcpRes
     let mut git_stdin = git.stdin.take().unwrap();
 
     if let Some(mut body) = req.take_body() {
-        std::io::copy(&mut body, &mut git_stdin)?;
+        // Copied by hand, to tell the client's failings (a body that stops arriving) from git's
+        loop {
+            let chunk = match body.fill_buf() {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    let e = UnreadableBody(e);
+                    log::debug!("Giving up on git request: {}", e);
+                    let _ = git.kill();
+                    let _ = git.wait();
+                    resp.send_response(Response::err(e.status()))?;
+                    return Ok(());
+                }
+            };
+            if chunk.is_empty() {
+                break;
+            }
+            git_stdin.write_all(chunk)?;
+            let len = chunk.len();
+            body.consume(len);
+        }
     }
     drop(git_stdin);
 
     let result = git.wait_with_output().context("Git backend")?;
+    let outcome = if result.status.success() { "success" } else { "failure" };
+    metrics.inc("rotterdam_git_backend_spawns_total", labels(&[("repo", &repo_name), ("outcome", outcome)]));
+    metrics.observe("rotterdam_git_backend_duration_seconds", labels(&[("repo", &repo_name)]), started.elapsed());
+
     if ! result.status.success() {
         log::error!("Error in git backend: {}", String::from_utf8_lossy(&result.stderr));
         resp.send_response(Response::err(500))?;
@@ -123,10 +149,17 @@ pub(crate) fn handle(config: &AppConfig, req: &mut dyn Request, mut resp: TcpRes
 
     log::debug!("Git stderr: {}", String::from_utf8_lossy(&result.stderr));
 
-    let writer = resp.raw_writer();
-    let status_line = std::io::Cursor::new(b"HTTP/1.0 200\r\nConnection: close\r\n");
+    resp.set_raw_status(200);
+    let mut status_line = b"HTTP/1.0 200\r\nConnection: close\r\n".to_vec();
+    for (name, value) in resp.default_headers().iter() {
+        status_line.extend_from_slice(&name.as_header_string());
+        status_line.extend_from_slice(b": ");
+        status_line.extend_from_slice(value);
+        status_line.extend_from_slice(b"\r\n");
+    }
+    let status_line = std::io::Cursor::new(status_line);
     let git_response = std::io::Cursor::new(result.stdout);
 
-    std::io::copy(&mut status_line.chain(git_response), writer)?;
+    std::io::copy(&mut status_line.chain(git_response), resp.raw_writer())?;
     Ok(())
 }
\ No newline at end of file
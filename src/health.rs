@@ -0,0 +1,140 @@
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use json::JsonValue;
+
+use crate::blob_store::BlobStore;
+use crate::config::AppConfig;
+
+
+/// Outcome of one readiness check.
+struct Check {
+    name: String,
+    result: Result<(), String>,
+}
+
+/// Runs every readiness check, returning whether all of them passed along with a JSON
+/// report of each.
+pub(crate) fn readiness(config: &AppConfig, blobs: &dyn BlobStore) -> (bool, JsonValue) {
+    let mut checks = vec![Check { name: String::from("git"), result: check_git_executable() }];
+
+    let mut repos: Vec<_> = config.repos.keys().collect();
+    repos.sort();
+    for repo in repos {
+        checks.push(Check {
+            name: format!("repo:{}", repo),
+            result: check_index(&config.git.path.join(repo.as_ref())),
+        });
+    }
+
+    checks.push(Check { name: String::from("blob_store"), result: blobs.check().map_err(|e| e.to_string()) });
+
+    let ready = checks.iter().all(|c| c.result.is_ok());
+
+    let mut report = JsonValue::new_object();
+    report["status"] = if ready { "ok" } else { "unavailable" }.into();
+    report["checks"] = JsonValue::new_object();
+    for check in checks {
+        let mut entry = JsonValue::new_object();
+        match check.result {
+            Ok(()) => entry["status"] = "ok".into(),
+            Err(reason) => {
+                entry["status"] = "failed".into();
+                entry["reason"] = reason.into();
+            }
+        }
+        report["checks"][check.name.as_str()] = entry;
+    }
+
+    (ready, report)
+}
+
+fn check_git_executable() -> Result<(), String> {
+    let status = Command::new("git")
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(|e| format!("Unable to run git: {}", e))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("git --version exited with {}", status))
+    }
+}
+
+/// The index must be a git repo with a commit on master and the cargo config in place;
+/// anything less means `ensure_index_setup` didn't get to the end.
+fn check_index(path: &Path) -> Result<(), String> {
+    if ! path.is_dir() {
+        return Err(format!("{} does not exist", path.to_string_lossy()));
+    }
+    if ! path.join("config.json").is_file() {
+        return Err(String::from("config.json is missing"));
+    }
+
+    // Opened rather than discovered, so a checkout further up doesn't count for this one
+    let found = git2::Repository::open(path).and_then(|repo| repo.revparse_single("refs/heads/master").map(|_| ()));
+    found.map_err(|_| String::from("Not a git repository with a master branch"))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::borrow::Cow;
+    use crate::app::test::TestRegistry;
+    use crate::config::Repo;
+
+    #[test]
+    fn reports_each_failing_check() {
+        let mut registry = TestRegistry::new();
+        let _writer = registry.writer();
+        for repo in ["missing", "plain"] {
+            registry.config.repos.insert(Cow::from(repo), Repo { name: Cow::from(repo), ..Repo::default() });
+        }
+        let plain = registry.config.git.path.join("plain");
+        std::fs::create_dir_all(&plain).unwrap();
+        std::fs::write(plain.join("config.json"), "{}").unwrap();
+        // A checkout holding the repos mustn't pass for theirs
+        let outer = git2::Repository::init_opts(&registry.config.git.path, git2::RepositoryInitOptions::new().initial_head("master")).unwrap();
+        let (signature, tree) = (git2::Signature::now("test", "test@example.com").unwrap(), outer.index().unwrap().write_tree().unwrap());
+        outer.commit(Some("HEAD"), &signature, &signature, "Outer", &outer.find_tree(tree).unwrap(), &[]).unwrap();
+
+        let (ready, report) = readiness(&registry.config, &registry.blobs);
+        assert!(! ready);
+        assert_eq!(report["status"], "unavailable");
+        let checks = &report["checks"];
+        assert_eq!(checks["git"]["status"], "ok");
+        assert_eq!(checks["repo:repo"]["status"], "ok");
+        assert_eq!(checks["repo:missing"]["status"], "failed");
+        assert!(checks["repo:missing"]["reason"].as_str().unwrap().ends_with("does not exist"));
+        assert_eq!(checks["repo:plain"]["reason"], "Not a git repository with a master branch");
+        assert_eq!(checks["blob_store"]["status"], "ok");
+    }
+
+    #[test]
+    fn serves_liveness_and_readiness() {
+        let registry = TestRegistry::new();
+        let server = registry.serve();
+
+        let healthz = server.request("GET", "/healthz", None, b"");
+        assert_eq!((healthz.status, healthz.json()["status"].as_str()), (200, Some("ok")));
+        assert_eq!(server.request("GET", "/readyz", None, b"").status, 200);
+
+        // Nothing can be staged in the blob store any more
+        let staging = registry.dir.path().join("blobs").join(".staging");
+        std::fs::remove_dir_all(&staging).unwrap();
+        std::fs::write(&staging, "").unwrap();
+        let readyz = server.request("GET", "/readyz", None, b"");
+        assert_eq!(readyz.status, 503);
+        let report = readyz.json();
+        assert_eq!(report["status"], "unavailable");
+        assert_eq!(report["checks"]["blob_store"]["status"], "failed");
+        assert!(report["checks"]["blob_store"]["reason"].is_string());
+        assert_eq!(report["checks"]["repo:repo"]["status"], "ok");
+        assert_eq!(server.request("GET", "/healthz", None, b"").status, 200);
+    }
+}
@@ -0,0 +1,340 @@
+use std::cmp::Ordering;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+
+/// A crate or version that isn't in the index, in words fit to show whoever asked for it.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub(crate) struct NotFound(pub String);
+
+/// One line of a crate's index file: a published version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct IndexEntry {
+    pub name: String,
+    pub vers: String,
+    /// SHA-256 of the `.crate` tarball, as lowercase hex.
+    pub cksum: String,
+    pub yanked: bool,
+}
+
+impl IndexEntry {
+    pub(crate) fn parse(line: &str) -> Result<Self> {
+        let value = json::parse(line).context("Index line is not valid JSON")?;
+        let field = |name: &str| value[name].as_str().map(str::to_string).with_context(|| format!("Index line has no {:?}", name));
+        let entry = IndexEntry {
+            name: field("name")?,
+            vers: field("vers")?,
+            cksum: field("cksum")?,
+            yanked: value["yanked"].as_bool().unwrap_or(false),
+        };
+        if ! is_sha256_hex(&entry.cksum) {
+            anyhow::bail!("Index line for {} {} has a malformed cksum", entry.name, entry.vers);
+        }
+        Ok(entry)
+    }
+}
+
+pub(crate) fn is_sha256_hex(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// Where cargo expects a crate's index file, relative to the index root: `1/a`, `2/ab`,
+/// `3/a/abc`, and `ab/cd/abcd...` for longer names, all lowercased.
+pub(crate) fn index_path(name: &str) -> PathBuf {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => Path::new("1").join(&name),
+        2 => Path::new("2").join(&name),
+        3 => Path::new("3").join(&name[..1]).join(&name),
+        _ => Path::new(&name[..2]).join(&name[2..4]).join(&name),
+    }
+}
+
+/// Finds `name`'s `version` in the index checked out at `index_root`.
+pub(crate) fn find_version(index_root: &Path, name: &str, version: &str) -> Result<Option<IndexEntry>> {
+    Ok(entries(index_root, name)?.into_iter().find(|entry| entry.vers == version && entry.name.eq_ignore_ascii_case(name)))
+}
+
+/// Every published version of `name` in the index checked out at `index_root`, oldest first.
+pub(crate) fn entries(index_root: &Path, name: &str) -> Result<Vec<IndexEntry>> {
+    if name.is_empty() || ! name.is_ascii() {
+        return Ok(Vec::new());
+    }
+    let file = match std::fs::File::open(index_root.join(index_path(name))) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("Reading index file"),
+    };
+
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(IndexEntry::parse(&line)?);
+    }
+    Ok(entries)
+}
+
+/// The URL cargo should use for the index checked out at `index_root`, going by the API URL
+/// in its `config.json`, or by `base_url` if that's missing.
+pub(crate) fn index_url(index_root: &Path, base_url: &str, repo: &str) -> String {
+    let config = std::fs::read_to_string(index_root.join("config.json")).ok().and_then(|c| json::parse(&c).ok());
+    match config.as_ref().and_then(|c| c["api"].as_str()) {
+        Some(api) => format!("{}/index", api.trim_end_matches('/')),
+        None => format!("{}/repo/{}/index", base_url, repo),
+    }
+}
+
+/// The name a crate was published under, if one that's the same name as `name` (differing only
+/// in case or `-`/`_`) is in the index checked out at `index_root`.
+pub(crate) fn published_name(index_root: &Path, name: &str) -> Result<Option<String>> {
+    let normalized = crate::names::normalize(name);
+    let dir = index_path(name).parent().map(|p| p.to_string_lossy().to_string()).unwrap_or_default();
+    // The same name can be spread across directories: foo-bar is under fo/o-, foo_bar under fo/o_
+    let mut dirs = vec![String::new()];
+    for c in dir.chars() {
+        let options: &[char] = if c == '-' || c == '_' { &['-', '_'] } else { &[c] };
+        dirs = dirs.iter().flat_map(|d| options.iter().map(move |o| format!("{}{}", d, o))).collect();
+    }
+
+    for dir in dirs {
+        let files = match std::fs::read_dir(index_root.join(&dir)) {
+            Ok(files) => files,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).context("Reading index directory"),
+        };
+        for file in files {
+            let file = file?;
+            if crate::names::normalize(&file.file_name().to_string_lossy()) != normalized || ! file.file_type()?.is_file() {
+                continue;
+            }
+            let contents = std::fs::read_to_string(file.path()).context("Reading index file")?;
+            if let Some(line) = contents.lines().find(|l| ! l.trim().is_empty()) {
+                return Ok(Some(IndexEntry::parse(line)?.name));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Splits a semver version into its release numbers and pre-release, dropping build metadata.
+fn parse_version(v: &str) -> Option<([u64; 3], Option<&str>)> {
+    let v = v.split('+').next()?;
+    let (release, pre) = match v.split_once('-') {
+        Some((release, pre)) => (release, Some(pre)),
+        None => (v, None),
+    };
+    let mut parts = release.split('.').map(|p| p.parse::<u64>().ok());
+    let numbers = [parts.next()??, parts.next()??, parts.next()??];
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((numbers, pre))
+}
+
+/// Orders versions by semver precedence (build metadata ignored). Anything that isn't
+/// valid semver sorts after everything that is, by plain string comparison.
+pub(crate) fn cmp_versions(a: &str, b: &str) -> Ordering {
+    fn cmp_pre(a: &str, b: &str) -> Ordering {
+        let (mut a, mut b) = (a.split('.'), b.split('.'));
+        loop {
+            return match (a.next(), b.next()) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Less,
+                (Some(_), None) => Ordering::Greater,
+                (Some(x), Some(y)) => {
+                    let ordering = match (x.parse::<u64>(), y.parse::<u64>()) {
+                        (Ok(x), Ok(y)) => x.cmp(&y),
+                        (Ok(_), Err(_)) => Ordering::Less,
+                        (Err(_), Ok(_)) => Ordering::Greater,
+                        (Err(_), Err(_)) => x.cmp(y),
+                    };
+                    if ordering == Ordering::Equal {
+                        continue;
+                    }
+                    ordering
+                }
+            };
+        }
+    }
+
+    match (parse_version(a), parse_version(b)) {
+        (Some((a_release, a_pre)), Some((b_release, b_pre))) => a_release.cmp(&b_release).then_with(|| match (a_pre, b_pre) {
+            (None, None) => Ordering::Equal,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(_), None) => Ordering::Less,
+            (Some(a), Some(b)) => cmp_pre(a, b),
+        }),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.cmp(b),
+    }
+}
+
+/// Whether `version` meets cargo's version requirement `req`, such as `^1.2, <1.5`: a bare
+/// version means `^`, and partial versions and `*` wildcards stand for ranges, as in cargo.
+/// Pre-releases only match a requirement naming a pre-release of the same version.
+pub(crate) fn version_matches(req: &str, version: &str) -> bool {
+    let (numbers, pre) = match parse_version(version) {
+        Some(version) => version,
+        None => return false,
+    };
+    let within = |bound: &(String, bool), wanted: Ordering| {
+        let ordering = cmp_versions(version, &bound.0);
+        ordering == wanted || (bound.1 && ordering == Ordering::Equal)
+    };
+
+    let mut pre_allowed = pre.is_none();
+    for comparator in req.split(',').map(str::trim) {
+        let split = comparator.find(|c: char| c.is_ascii_alphanumeric() || c == '*').unwrap_or(comparator.len());
+        let (op, partial) = (comparator[..split].trim(), comparator[split..].trim());
+        let partial = partial.split('+').next().unwrap_or_default();
+        let (partial, partial_pre) = match partial.split_once('-') {
+            Some((partial, pre)) => (partial, Some(pre)),
+            None => (partial, None),
+        };
+
+        let mut parts = Vec::new();
+        let mut wildcard = false;
+        for part in partial.split('.') {
+            if matches!(part, "*" | "x" | "X") {
+                wildcard = true;
+                break;
+            }
+            match part.parse::<u64>() {
+                Ok(n) => parts.push(n),
+                Err(_) => return false,
+            }
+        }
+        if parts.len() > 3 || (partial_pre.is_some() && parts.len() < 3) {
+            return false;
+        }
+        if parts.is_empty() {
+            continue; // `*`, which anything matches
+        }
+        if parts.len() == 3 && partial_pre.is_some() && parts[..] == numbers[..] {
+            pre_allowed = true;
+        }
+
+        let named = [parts[0], parts.get(1).copied().unwrap_or(0), parts.get(2).copied().unwrap_or(0)];
+        let written = |n: [u64; 3]| format!("{}.{}.{}", n[0], n[1], n[2]);
+        let lower = match partial_pre {
+            Some(pre) => format!("{}-{}", written(named), pre),
+            None => written(named),
+        };
+        // The next version up at part `i`: bump(0) of 1.2.3 is 2.0.0
+        let bump = |i: usize| {
+            let mut next = named;
+            next[i] += 1;
+            next.iter_mut().skip(i + 1).for_each(|n| *n = 0);
+            written(next)
+        };
+        let last = parts.len() - 1;
+        let op = if wildcard && op.is_empty() { "=" } else { op };
+        let (min, max) = match op {
+            "=" if last == 2 => (Some((lower.clone(), true)), Some((lower, true))),
+            "=" => (Some((lower, true)), Some((bump(last), false))),
+            ">" if last == 2 => (Some((lower, false)), None),
+            ">" => (Some((bump(last), true)), None),
+            ">=" => (Some((lower, true)), None),
+            "<" => (None, Some((lower, false))),
+            "<=" if last == 2 => (None, Some((lower, true))),
+            "<=" => (None, Some((bump(last), false))),
+            "~" => (Some((lower, true)), Some((bump(last.min(1)), false))),
+            "^" | "" => {
+                let first_significant = parts.iter().position(|n| *n != 0).unwrap_or(last);
+                (Some((lower, true)), Some((bump(first_significant.min(last)), false)))
+            }
+            _ => return false,
+        };
+        if ! (min.is_none_or(|min| within(&min, Ordering::Greater)) && max.is_none_or(|max| within(&max, Ordering::Less))) {
+            return false;
+        }
+    }
+    pre_allowed
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lays_out_index_paths_like_cargo() {
+        assert_eq!(index_path("a"), Path::new("1/a"));
+        assert_eq!(index_path("ab"), Path::new("2/ab"));
+        assert_eq!(index_path("abc"), Path::new("3/a/abc"));
+        assert_eq!(index_path("Serde_JSON"), Path::new("se/rd/serde_json"));
+    }
+
+    #[test]
+    fn parses_index_lines() {
+        let line = r#"{"name":"foo","vers":"1.0.0","deps":[],"cksum":"ab9d6ac3ed0f5e4bd70c6cd5c4d0a5bd9f8e7e2e1fc18fd1d0da6a4b1c2ef0e1","features":{},"yanked":true}"#;
+        let entry = IndexEntry::parse(line).unwrap();
+        assert_eq!((entry.name.as_str(), entry.vers.as_str(), entry.yanked), ("foo", "1.0.0", true));
+
+        assert!(IndexEntry::parse(r#"{"name":"foo","vers":"1.0.0","cksum":"nothex"}"#).is_err());
+        assert!(IndexEntry::parse("not json").is_err());
+    }
+
+    #[test]
+    fn finds_crates_published_under_other_spellings() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for name in ["Foo-Bar", "ab", "x_y"] {
+            let path = root.join(index_path(name));
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, format!("{{\"name\":\"{}\",\"vers\":\"1.0.0\",\"cksum\":\"{}\"}}\n", name, "0".repeat(64))).unwrap();
+        }
+
+        assert_eq!(published_name(root, "foo_bar").unwrap().as_deref(), Some("Foo-Bar"));
+        assert_eq!(published_name(root, "FOO-BAR").unwrap().as_deref(), Some("Foo-Bar"));
+        assert_eq!(published_name(root, "AB").unwrap().as_deref(), Some("ab"));
+        assert_eq!(published_name(root, "x-y").unwrap().as_deref(), Some("x_y"));
+        assert_eq!(published_name(root, "foo_baz").unwrap(), None);
+        assert_eq!(published_name(root, "xy").unwrap(), None);
+    }
+
+    #[test]
+    fn orders_versions_by_semver() {
+        let mut versions = vec!["1.0.0", "0.10.0", "1.0.0-alpha.beta", "0.9.1", "1.0.0-alpha", "1.0.0-rc.1", "1.0.0-alpha.1", "1.0.0-beta.11", "1.0.0-beta.2", "1.0.0-beta", "bogus"];
+        versions.sort_by(|a, b| cmp_versions(a, b));
+        assert_eq!(versions, ["0.9.1", "0.10.0", "1.0.0-alpha", "1.0.0-alpha.1", "1.0.0-alpha.beta", "1.0.0-beta", "1.0.0-beta.2", "1.0.0-beta.11", "1.0.0-rc.1", "1.0.0", "bogus"]);
+        assert_eq!(cmp_versions("1.0.0+build.1", "1.0.0"), Ordering::Equal);
+    }
+
+    #[test]
+    fn matches_version_requirements_like_cargo() {
+        let cases = [
+            ("1.2", &["1.2.0", "1.9.9"][..], &["1.1.9", "2.0.0", "1.3.0-alpha"][..]),
+            ("^0.2.3", &["0.2.3", "0.2.99"], &["0.2.2", "0.3.0"]),
+            ("^0.0.3", &["0.0.3"], &["0.0.4"]),
+            ("0.0", &["0.0.9"], &["0.1.0"]),
+            ("~1.2.3", &["1.2.3", "1.2.9"], &["1.3.0"]),
+            ("~1", &["1.9.0"], &["2.0.0"]),
+            ("=1.2", &["1.2.5"], &["1.3.0"]),
+            ("=1.2.3", &["1.2.3"], &["1.2.4"]),
+            (">= 1.2, < 1.5", &["1.4.9"], &["1.5.0", "1.1.0"]),
+            (">1.2", &["1.3.0"], &["1.2.9"]),
+            ("<=1.2", &["1.2.9"], &["1.3.0"]),
+            ("1.*", &["1.5.0"], &["2.0.0"]),
+            ("1.2.*", &["1.2.7"], &["1.3.0"]),
+            ("*", &["0.0.1", "9.0.0"], &["1.0.0-rc.1"]),
+            ("^1.0.0-rc.1", &["1.0.0-rc.2", "1.0.0", "1.2.0"], &["1.0.0-alpha", "1.1.0-beta"]),
+            ("bogus", &[], &["1.0.0"]),
+        ];
+        for (req, matching, other) in cases {
+            for version in matching {
+                assert!(version_matches(req, version), "{} should match {}", version, req);
+            }
+            for version in other {
+                assert!(! version_matches(req, version), "{} shouldn't match {}", version, req);
+            }
+        }
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result, anyhow, bail};
+use git2::build::{CheckoutBuilder, TreeUpdateBuilder};
+use git2::{FileMode, Repository, Signature, StatusOptions};
+
+use crate::config::AppGitConfig;
+use crate::trace::{self, Handoff};
+
+
+/// At most this many queued mutations go into one commit.
+const MAX_BATCH: usize = 32;
+
+/// Lock files git leaves behind when it's killed mid-write.
+const STALE_LOCKS: &[&str] = &["index.lock", "HEAD.lock", "packed-refs.lock", "refs/heads/master.lock"];
+
+/// The new contents of one index file, relative to the index root.
+pub(crate) struct Edit {
+    pub path: PathBuf,
+    /// `None` removes the file.
+    pub contents: Option<Vec<u8>>,
+}
+
+/// Works out what to change given the current index checkout. Nothing is written until it
+/// returns, so a mutation that fails leaves the index untouched.
+pub(crate) type Mutation = Box<dyn FnOnce(&Path) -> Result<Vec<Edit>> + Send>;
+
+/// A mutation that also has something to do once its edits are committed, and only then: say,
+/// writing files kept beside the index that mustn't get ahead of it.
+pub(crate) type FollowedMutation = Box<dyn FnOnce(&Path) -> Result<(Vec<Edit>, AfterCommit)> + Send>;
+
+pub(crate) type AfterCommit = Box<dyn FnOnce() -> Result<()> + Send>;
+
+type Exclusive = Box<dyn FnOnce(&Path) + Send>;
+
+/// A mutation that's been applied to the checkout but not yet committed, and the request
+/// it's for.
+struct Applied {
+    message: String,
+    edits: Vec<Edit>,
+    after: AfterCommit,
+    done: Sender<Result<()>>,
+    handoff: Option<Handoff>,
+}
+
+enum Job {
+    Mutate { message: String, mutation: FollowedMutation, done: Sender<Result<()>> },
+    Exclusive(Exclusive),
+}
+
+/// A job, along with the request that sent it, so its log lines and spans are that request's.
+type Queued = (Job, Option<Handoff>);
+
+/// The only thing that writes to a repo's index while the server runs. Mutations are queued
+/// and applied one at a time on a dedicated thread; whatever has queued up by the time the
+/// thread gets to it goes into a single commit.
+pub(crate) struct IndexWriter {
+    repo: String,
+    jobs: Mutex<Sender<Queued>>,
+    /// Jobs sent but not yet picked up by the writer thread.
+    queued: Arc<AtomicUsize>,
+    subscribers: Subscribers,
+}
+
+/// Told whenever `master` may have moved.
+type Subscribers = Arc<Mutex<Vec<Sender<()>>>>;
+
+pub(crate) type IndexWriters = HashMap<String, Arc<IndexWriter>>;
+
+impl IndexWriter {
+    /// Cleans up after any writer that crashed part way through, then starts writing.
+    pub(crate) fn start(git: &AppGitConfig, repo: &str) -> Result<Self> {
+        let index_root = git.path.join(repo);
+        recover(&Repository::open(&index_root)?).with_context(|| format!("Recovering the {} index", repo))?;
+
+        let (tx, rx) = mpsc::channel();
+        let git = git.clone();
+        let repository = Repository::open(&index_root).with_context(|| format!("Opening the {} index", repo))?;
+        let subscribers = Subscribers::default();
+        let notify = subscribers.clone();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let dequeued = queued.clone();
+        std::thread::Builder::new()
+            .name(format!("index-writer-{}", repo))
+            .spawn(move || write_loop(&git, &repository, rx, &dequeued, &notify))
+            .context("Starting index writer thread")?;
+        Ok(IndexWriter { repo: repo.to_string(), jobs: Mutex::new(tx), queued, subscribers })
+    }
+
+    /// Notifications, after the fact, of each commit and each exclusive job (which might have
+    /// rewritten history). Several may arrive for one change.
+    pub(crate) fn subscribe(&self) -> Receiver<()> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap_or_else(|p| p.into_inner()).push(tx);
+        rx
+    }
+
+    /// Applies `mutation` and commits it (possibly along with others), returning once the commit
+    /// is made.
+    pub(crate) fn mutate(&self, message: impl Into<String>, mutation: Mutation) -> Result<()> {
+        self.mutate_then(message, Box::new(move |index_root: &Path| {
+            Ok((mutation(index_root)?, Box::new(|| Ok(())) as AfterCommit))
+        }))
+    }
+
+    /// Like `mutate`, but once the commit is made (and not if it fails), runs what the mutation
+    /// returned along with its edits, before anything else gets to the index.
+    pub(crate) fn mutate_then(&self, message: impl Into<String>, mutation: FollowedMutation) -> Result<()> {
+        let (done, result) = mpsc::channel();
+        self.send(Job::Mutate { message: message.into(), mutation, done })?;
+        result.recv().map_err(|_| anyhow!("The {} index writer stopped", self.repo))?
+    }
+
+    /// Runs `f` with nothing else writing to the index, for work like `git gc` that doesn't fit
+    /// the mutation model.
+    pub(crate) fn exclusive<T: Send + 'static>(&self, f: impl FnOnce(&Path) -> Result<T> + Send + 'static) -> Result<T> {
+        let (done, result) = mpsc::channel();
+        self.send(Job::Exclusive(Box::new(move |index_root| {
+            let _ = done.send(f(index_root));
+        })))?;
+        result.recv().map_err(|_| anyhow!("The {} index writer stopped", self.repo))?
+    }
+
+    /// How many jobs are waiting for the writer thread.
+    #[cfg(test)]
+    pub(crate) fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    fn send(&self, job: Job) -> Result<()> {
+        let jobs = self.jobs.lock().unwrap_or_else(|p| p.into_inner());
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        jobs.send((job, trace::handoff())).map_err(|_| anyhow!("The {} index writer stopped", self.repo))
+    }
+}
+
+fn write_loop(git: &AppGitConfig, repo: &Repository, jobs: Receiver<Queued>, queued: &AtomicUsize, subscribers: &Subscribers) {
+    let index_root = repo.workdir().expect("Index repos have a working tree");
+    let notify = || subscribers.lock().unwrap_or_else(|p| p.into_inner()).retain(|s| s.send(()).is_ok());
+    let mut batch = Vec::new();
+    while let Ok(job) = jobs.recv() {
+        let mut next = Some(job);
+        while let Some((job, handoff)) = next.take() {
+            queued.fetch_sub(1, Ordering::SeqCst);
+            let request = trace::resume(handoff.as_ref(), "index_write");
+            match job {
+                Job::Mutate { message, mutation, done } => {
+                    match mutation(index_root).and_then(|(edits, after)| apply(index_root, &edits).map(|()| (edits, after))) {
+                        Ok((edits, after)) => batch.push(Applied { message, edits, after, done, handoff }),
+                        Err(e) => {
+                            let _ = done.send(Err(e));
+                        }
+                    }
+                    if batch.len() < MAX_BATCH {
+                        next = jobs.try_recv().ok();
+                    }
+                }
+                Job::Exclusive(f) => {
+                    drop(request);
+                    commit_batch(git, repo, &mut batch);
+                    let _request = trace::resume(handoff.as_ref(), "index_write");
+                    f(index_root);
+                    notify();
+                }
+            }
+        }
+        if commit_batch(git, repo, &mut batch) {
+            notify();
+        }
+    }
+}
+
+/// Writes each edit via a temporary file, so a crash leaves every file either old or new.
+fn apply(index_root: &Path, edits: &[Edit]) -> Result<()> {
+    let staging = index_root.join(".git").join("rotterdam-edit");
+    for edit in edits {
+        let path = index_root.join(&edit.path);
+        let contents = match &edit.contents {
+            Some(contents) => contents,
+            None => {
+                if path.exists() {
+                    std::fs::remove_file(&path).with_context(|| format!("Removing {}", edit.path.to_string_lossy()))?;
+                }
+                continue;
+            }
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&staging, contents)?;
+        std::fs::rename(&staging, &path).with_context(|| format!("Writing {}", edit.path.to_string_lossy()))?;
+    }
+    Ok(())
+}
+
+/// Returns whether anything was committed.
+fn commit_batch(git: &AppGitConfig, repo: &Repository, batch: &mut Vec<Applied>) -> bool {
+    if batch.is_empty() {
+        return false;
+    }
+
+    let message = match batch.as_slice() {
+        [applied] => format!("(rotterdam): {}", applied.message),
+        _ => {
+            let details: Vec<_> = batch.iter().map(|applied| format!("- {}", applied.message)).collect();
+            format!("(rotterdam): {} index updates\n\n{}", batch.len(), details.join("\n"))
+        }
+    };
+    // Later mutations saw (and built on) earlier ones' edits, so the last edit to a path wins
+    let edits: BTreeMap<&Path, &Edit> = batch.iter().flat_map(|applied| applied.edits.iter()).map(|e| (e.path.as_path(), e)).collect();
+    let edits: Vec<&Edit> = edits.into_values().collect();
+
+    let result = commit(git, repo, &message, &edits).and_then(|committed| sync_index(repo, &edits).map(|()| committed));
+    if let Err(e) = &result {
+        let index_root = repo.workdir().unwrap_or_else(|| repo.path());
+        // Logged for each request in the batch, so each one's logs say why it failed
+        for applied in batch.iter() {
+            let _request = trace::resume(applied.handoff.as_ref(), "index_commit");
+            log::error!("Unable to commit {:?} to {}: {:#}", applied.message, index_root.to_string_lossy(), e);
+        }
+        if let Err(e) = reset(repo) {
+            log::error!("Unable to reset {} after a failed commit: {:#}", index_root.to_string_lossy(), e);
+        }
+    }
+    for Applied { message, after, done, handoff, .. } in batch.drain(..) {
+        let _ = done.send(match &result {
+            Ok(_) => {
+                let _request = trace::resume(handoff.as_ref(), "index_commit");
+                after().with_context(|| format!("After committing {:?}", message))
+            }
+            Err(e) => Err(anyhow!("Committing to the index: {:#}", e)),
+        });
+    }
+    matches!(result, Ok(true))
+}
+
+/// Commits `edits` on top of `master`, building the blobs and tree from the edits themselves
+/// rather than from whatever happens to be in the working tree. Returns whether there was
+/// anything to commit.
+fn commit(git: &AppGitConfig, repo: &Repository, message: &str, edits: &[&Edit]) -> Result<bool> {
+    let parent = match repo.find_reference("refs/heads/master") {
+        Ok(master) => Some(master.peel_to_commit()?),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let base = match &parent {
+        Some(parent) => parent.tree()?,
+        None => repo.find_tree(repo.treebuilder(None)?.write()?)?,
+    };
+
+    let mut update = TreeUpdateBuilder::new();
+    for edit in edits {
+        match &edit.contents {
+            Some(contents) => update.upsert(edit.path.as_path(), repo.blob(contents)?, FileMode::Blob),
+            None => update.remove(edit.path.as_path()),
+        };
+    }
+    let tree = repo.find_tree(update.create_updated(repo, &base)?)?;
+    if tree.id() == base.id() {
+        return Ok(false); // Nothing actually changed
+    }
+
+    let author = Signature::now(&git.author_name, &git.author_email)?;
+    let parents: Vec<_> = parent.iter().collect();
+    // Fails rather than clobbering master if it moved since we read it
+    repo.commit(Some("refs/heads/master"), &author, &author, message, &tree, &parents)?;
+    Ok(true)
+}
+
+/// Points git's index at the new commit. The edited files are already in place, so unlike a
+/// checkout this leaves the working tree alone while requests may be reading it.
+fn sync_index(repo: &Repository, edits: &[&Edit]) -> Result<()> {
+    let tree = repo.find_reference("refs/heads/master")?.peel_to_tree()?;
+    let mut index = repo.index()?;
+    index.read_tree(&tree)?;
+    for edit in edits {
+        match edit.contents {
+            Some(_) => index.add_path(&edit.path)?,
+            None => index.remove_path(&edit.path)?,
+        }
+    }
+    index.write()?;
+    Ok(())
+}
+
+/// Makes the checkout match the last commit again: stale locks go, and anything uncommitted
+/// (which no caller was ever told had succeeded) is thrown away.
+fn recover(repo: &Repository) -> Result<()> {
+    for lock in STALE_LOCKS {
+        let lock = repo.path().join(lock);
+        if lock.exists() {
+            log::warn!("Removing stale git lock {}", lock.to_string_lossy());
+            std::fs::remove_file(&lock)?;
+        }
+    }
+    let _ = std::fs::remove_file(repo.path().join("rotterdam-edit"));
+
+    let mut options = StatusOptions::new();
+    options.include_untracked(true).recurse_untracked_dirs(true);
+    if ! repo.statuses(Some(&mut options))?.is_empty() {
+        log::warn!("Discarding uncommitted changes in {}", repo.workdir().unwrap_or_else(|| repo.path()).to_string_lossy());
+        reset(repo)?;
+    }
+    Ok(())
+}
+
+fn reset(repo: &Repository) -> Result<()> {
+    let tree = match repo.find_reference("refs/heads/master") {
+        Ok(master) => master.peel_to_tree()?,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => repo.find_tree(repo.treebuilder(None)?.write()?)?,
+        Err(e) => return Err(e.into()),
+    };
+    repo.checkout_tree(tree.as_object(), Some(CheckoutBuilder::new().force().remove_untracked(true)))?;
+    let mut index = repo.index()?;
+    index.read_tree(&tree)?;
+    index.write()?;
+    Ok(())
+}
+
+/// Runs the git binary in `index_root`, returning its trimmed stdout. Only for what libgit2
+/// can't do, like `git gc`; index writes happen in-process.
+pub(crate) fn run_git(index_root: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .current_dir(index_root)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .context("Running git")?;
+    if ! output.status.success() {
+        bail!("git {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Barrier;
+
+    fn appending(path: &'static str, line: String) -> Mutation {
+        Box::new(move |index_root: &Path| {
+            let mut contents = std::fs::read(index_root.join(path)).unwrap_or_default();
+            contents.extend_from_slice(line.as_bytes());
+            contents.push(b'\n');
+            Ok(vec![Edit { path: PathBuf::from(path), contents: Some(contents) }])
+        })
+    }
+
+    #[test]
+    fn serializes_and_batches_concurrent_mutations() {
+        let registry = crate::app::test::TestRegistry::new();
+        let index_root = registry.index_root();
+        std::fs::create_dir_all(&index_root).unwrap();
+        run_git(&index_root, &["init", "-q", "-b", "master"]).unwrap();
+
+        // Left behind by a writer that crashed
+        std::fs::write(index_root.join("half-written"), "x").unwrap();
+        std::fs::write(index_root.join(".git/index.lock"), "").unwrap();
+
+        let writer = Arc::new(IndexWriter::start(&registry.config.git, "repo").unwrap());
+        assert!(!index_root.join("half-written").exists());
+        assert!(!index_root.join(".git/index.lock").exists());
+
+        writer.mutate("first", appending("3/f/foo", String::from("0"))).unwrap();
+
+        // Hold the writer up so the next mutations queue behind it and share a commit
+        let (started, release) = (Arc::new(Barrier::new(2)), Arc::new(Barrier::new(2)));
+        let blocked = {
+            let (writer, started, release) = (writer.clone(), started.clone(), release.clone());
+            std::thread::spawn(move || writer.exclusive(move |_| { started.wait(); release.wait(); Ok(()) }).unwrap())
+        };
+        started.wait();
+        let publishers: Vec<_> = (1..=8).map(|n| {
+            let writer = writer.clone();
+            std::thread::spawn(move || writer.mutate(format!("publish {}", n), appending("3/f/foo", n.to_string())).unwrap())
+        }).collect();
+        while writer.queue_depth() < 8 {
+            std::thread::yield_now();
+        }
+        release.wait();
+        blocked.join().unwrap();
+        for publisher in publishers {
+            publisher.join().unwrap();
+        }
+
+        let failed = writer.mutate("broken", Box::new(|_: &Path| bail!("nope")));
+        assert!(failed.is_err());
+
+        let contents = std::fs::read_to_string(index_root.join("3/f/foo")).unwrap();
+        let mut lines: Vec<_> = contents.lines().collect();
+        lines.sort();
+        assert_eq!(lines, ["0", "1", "2", "3", "4", "5", "6", "7", "8"]);
+        assert_eq!(run_git(&index_root, &["rev-list", "--count", "HEAD"]).unwrap(), "2");
+        assert_eq!(run_git(&index_root, &["status", "--porcelain"]).unwrap(), "");
+    }
+
+    #[test]
+    fn runs_jobs_as_the_request_that_sent_them() {
+        let registry = crate::app::test::TestRegistry::new();
+        let writer = registry.writer();
+        assert_eq!(writer.exclusive(|_| Ok(trace::log_prefix())).unwrap(), None);
+
+        let _request = trace::resume(Some(&Handoff::new("req-1")), "publish");
+        let (tx, rx) = mpsc::channel();
+        writer.mutate("publish", Box::new(move |_: &Path| {
+            tx.send(trace::log_prefix()).unwrap();
+            Ok(Vec::new())
+        })).unwrap();
+        assert_eq!(rx.recv().unwrap().as_deref(), Some("[req-1]"));
+        assert_eq!(writer.exclusive(|_| Ok(trace::log_prefix())).unwrap().as_deref(), Some("[req-1]"));
+    }
+}
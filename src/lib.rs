@@ -1,24 +1,51 @@
-use std::{env, error::Error, io::{Write, stdout}, os::unix::prelude::AsRawFd, path::{PathBuf}};
+use std::{env, error::Error, sync::Arc, io::{Write, stdout}, os::unix::prelude::AsRawFd, path::{PathBuf}};
 
 use anyhow::{Result};
 use smtr::{
-    server::{Response, TcpResponseWriter}, Request,
+    server::{Response, ConnectionResponseWriter}, Request,
 };
 
 mod git_cgi;
+mod upload_pack;
+mod publish;
+mod crate_file;
+mod names;
+mod owners;
+mod yank;
+mod policy;
+mod search;
+mod auth;
 mod config;
 mod app;
+mod proxy;
+mod access_log;
+mod time;
+mod metrics;
+mod health;
+mod trace;
+mod otlp;
+mod blob_store;
+mod index;
+mod index_writer;
+mod scrub;
+mod fsck;
+mod rebuild;
+mod maintenance;
+mod mirror;
+
+pub use trace::init_logging;
 
 
 /*
 /api/v1/
        /token            <-- POST (issues new token)
 /repo/<reponame>/index/             <-- git stuff
-/repo/<reponame>/api/v1/crates      <-- downloads
+/repo/<reponame>/api/v1/crates      <-- downloads, GET (cargo search)
 /repo/<reponame>/api                <-- API base path
-/repo/<reponame>/api/v1/new         <-- PUT (cargo publish)
+/repo/<reponame>/api/v1/crates/new  <-- PUT (cargo publish)
 /repo/<reponame>/api/v1/crates/{crate_name}/{version}/yank    <-- DELETE (cargo yank)
 /repo/<reponame>/api/v1/crates/{crate_name}/{version}/unyank  <-- PUT (cargo unyank)
+/repo/<reponame>/api/v1/crates/{crate_name}/owners  <-- GET, PUT, DELETE (cargo owner)
 */
 
 pub fn main() -> Result<(), Box<dyn Error>> {
@@ -29,33 +56,130 @@ pub fn main() -> Result<(), Box<dyn Error>> {
             clap::Arg::with_name("print-info")
                 .long("print-info")
                 .help("If set, the server will print connection details and then close stdout")
-                .long_help("If set, the server will print connection details and then close stderr. This is useful when the server is allowed to pick the listen port.")
+                .long_help("If set, the server will print the addresses it listens on as JSON once they are bound, and then close stdout. This is useful when the server is allowed to pick the listen port.")
                 .takes_value(false))
         .arg(
             clap::Arg::with_name("config")
                 .long("config")
                 .short("c")
                 .help("Where can I find my configuration?")
-                .takes_value(true))
+                .takes_value(true)
+                .global(true))
+        .subcommand(
+            clap::SubCommand::with_name("fsck")
+                .about("Checks each repo's index and stored tarballs are consistent, printing a JSON report")
+                .arg(
+                    clap::Arg::with_name("repair")
+                        .long("repair")
+                        .help("Fix what can be fixed safely: move misplaced index files, and set aside corrupt or orphaned tarballs")))
+        .subcommand(
+            clap::SubCommand::with_name("rebuild-index")
+                .about("Recreates a repo's index from its stored crate tarballs, printing a JSON report")
+                .arg(
+                    clap::Arg::with_name("repo")
+                        .required(true)
+                        .help("Which repo to rebuild"))
+                .arg(
+                    clap::Arg::with_name("audit-log")
+                        .long("audit-log")
+                        .takes_value(true)
+                        .help("A JSON access log to replay yanks and unyanks from"))
+                .arg(
+                    clap::Arg::with_name("force")
+                        .long("force")
+                        .help("Move an existing index aside instead of refusing to rebuild")))
+        .subcommand(
+            clap::SubCommand::with_name("gc")
+                .about("Runs index maintenance (git gc, and squashing once due) on each repo now")
+                .arg(
+                    clap::Arg::with_name("squash")
+                        .long("squash")
+                        .help("Squash each repo's history into a single commit, whether or not it's due")))
         .get_matches();
 
-    if matches.is_present("print-info") {
-        let stdout = stdout();
-        let mut s = stdout.lock();
-        s.write_all(br#"{ "port": 8080 }"#)?;
-        s.flush()?;
-        unsafe {
-            let _ = libc::close(s.as_raw_fd());
-        };
+    log::debug!("Running here: {}", env::current_dir()?.to_string_lossy());
+
+    let mut config: config::AppConfig = config::load(matches.value_of("config").map(PathBuf::from))?;
+
+    if let Some(fsck) = matches.subcommand_matches("fsck") {
+        let blobs = blob_store::open(&config.binaries)?;
+        let (consistent, report) = fsck::run(&config, blobs.as_ref(), fsck.is_present("repair"))?;
+        println!("{}", report.pretty(2));
+        std::process::exit(if consistent { 0 } else { 1 });
     }
 
-    log::debug!("Running here: {}", env::current_dir()?.to_string_lossy());
+    if let Some(rebuild) = matches.subcommand_matches("rebuild-index") {
+        let blobs = blob_store::open(&config.binaries)?;
+        let report = rebuild::run(
+            &config,
+            blobs.as_ref(),
+            rebuild.value_of("repo").unwrap_or_default(),
+            rebuild.value_of("audit-log").map(std::path::Path::new),
+            rebuild.is_present("force"),
+        )?;
+        println!("{}", report.pretty(2));
+        return Ok(());
+    }
+
+    if let Some(gc) = matches.subcommand_matches("gc") {
+        let maintenance = config.maintenance.clone().unwrap_or(maintenance::MaintenanceConfig {
+            interval: std::time::Duration::from_secs(24 * 60 * 60),
+            squash_after: None,
+            archive: maintenance::Archive::Bundle(None),
+        });
+        let mut repos: Vec<_> = config.repos.keys().collect();
+        repos.sort();
+        for repo in repos {
+            let report = maintenance::run_repo(&config.git, &maintenance, repo, gc.is_present("squash"))?;
+            match report.squashed_to {
+                Some(archive) => println!("{}: squashed {} commits; old history archived to {}", repo, report.commits, archive),
+                None => println!("{}: {} commits", repo, report.commits),
+            }
+        }
+        return Ok(());
+    }
+
+    let mut listen = config.listen.clone();
+    listen.extend(config.admin_listen.clone());
+    let mut server_config = config.server.clone();
+
+    let writers = app::App::ready_config(&mut config)?;
+    let blobs = blob_store::open(&config.binaries)?;
+    let metrics = Arc::new(metrics::Metrics::new(&config, server_config.stats.clone()));
+    metrics::spawn_store_sizes(metrics.clone(), config.git.path.clone(), blobs.clone());
+    let mut access_logs: Vec<Arc<dyn smtr::server::AccessLog>> = vec![metrics.clone()];
+    if let Some(access_log) = &config.access_log {
+        access_logs.push(Arc::new(access_log::FileAccessLog::open(access_log)?));
+    }
+    server_config.access_log = Some(Arc::new(access_log::AccessLogs(access_logs)));
+
+    if let Some(tracing) = &config.tracing {
+        log::info!("Exporting traces to {}", tracing.otlp_endpoint);
+        trace::set_exporter(Box::new(otlp::OtlpExporter::start(tracing)));
+    }
+
+    if let Some(scrub) = &config.scrub {
+        let mut repos: Vec<String> = config.repos.keys().map(|r| r.to_string()).collect();
+        repos.sort();
+        scrub::spawn(scrub.clone(), repos, blobs.clone(), metrics.clone());
+    }
+
+    if let Some(maintenance) = &config.maintenance {
+        maintenance::spawn(config.git.clone(), maintenance.clone(), &writers, metrics.clone());
+    }
 
-    let config: config::AppConfig = config::load(matches.value_of("config").map(PathBuf::from))?;
+    let mirrors = mirror::start(&config, &writers, metrics.clone());
+    let search = search::start(&config, &writers);
 
-    let app = app::App::new(config)?;
+    let app = app::App::new(config, metrics, blobs, writers, mirrors, search)?;
 
-    let chan = smtr::server::serve("127.0.0.1:8080")?;
+    log::info!("Listening on {}", listen.join(", "));
+    let listen: Vec<&str> = listen.iter().map(String::as_str).collect();
+    let bound = smtr::server::bind_all(&listen)?;
+    if matches.is_present("print-info") {
+        print_info(&bound)?;
+    }
+    let chan = bound.serve(server_config);
     for (mut req, response_writer) in chan {
         log::debug!("Reading request: {:?} : {:?}", req.method(), req.path());
         match app.handle(&mut req, response_writer) {
@@ -68,3 +192,24 @@ pub fn main() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+/// Writes what the server ended up listening on to stdout as JSON, then closes it: each
+/// listener's bind address and the address it's bound to, plus the first TCP port as `port`.
+fn print_info(bound: &smtr::server::Bound) -> Result<(), Box<dyn Error>> {
+    let addresses = bound.local_addresses();
+    let listen: Vec<json::JsonValue> = addresses.iter()
+        .map(|(bind, address)| json::object! { bind: *bind, address: address.as_str() })
+        .collect();
+    let port = addresses.iter()
+        .find_map(|(_, address)| address.parse::<std::net::SocketAddr>().ok())
+        .map(|addr| addr.port());
+
+    let stdout = stdout();
+    let mut s = stdout.lock();
+    s.write_all(json::object! { listen: listen, port: port }.dump().as_bytes())?;
+    s.flush()?;
+    unsafe {
+        let _ = libc::close(s.as_raw_fd());
+    };
+    Ok(())
+}
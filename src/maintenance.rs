@@ -0,0 +1,189 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, bail};
+
+use crate::time::compact_utc_time;
+use crate::config::AppGitConfig;
+use crate::index_writer::{run_git as git_output, IndexWriter, IndexWriters};
+use crate::metrics::{labels, Metrics};
+
+
+#[derive(Clone, Debug)]
+pub(crate) struct MaintenanceConfig {
+    /// How long to wait between maintenance passes.
+    pub interval: Duration,
+    /// Squash a repo's history into a single commit once it has at least this many commits.
+    /// Never squash when unset.
+    pub squash_after: Option<u64>,
+    pub archive: Archive,
+}
+
+/// Where history goes before it's squashed away.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Archive {
+    /// A `git bundle` of the old history, written to the given directory (by default
+    /// `<repo>.archive/` next to the repo).
+    Bundle(Option<PathBuf>),
+    /// A `snapshot-<time>` branch in the repo itself. Cargo only fetches `HEAD`, so clones
+    /// still shrink, but the repo on disk doesn't.
+    Branch,
+}
+
+/// What one maintenance pass did to a repo.
+#[derive(Debug, Default)]
+pub(crate) struct MaintenanceReport {
+    pub commits: u64,
+    /// Where the old history was archived, if it was squashed.
+    pub squashed_to: Option<String>,
+}
+
+/// Squashes `repo`'s history if it's grown past `config.squash_after` commits (or `force_squash`
+/// is set), then runs `git gc`.
+///
+/// Squashing leaves `master` as a single root commit with the same tree. Cargo fetches the index
+/// with a forced refspec, so existing clients pick up the rewritten history without complaint.
+pub(crate) fn run_repo(git: &AppGitConfig, config: &MaintenanceConfig, repo: &str, force_squash: bool) -> Result<MaintenanceReport> {
+    let index_root = git.path.join(repo);
+    if ! index_root.join(".git").is_dir() {
+        bail!("{} is not a git repository", index_root.to_string_lossy());
+    }
+
+    let mut report = MaintenanceReport {
+        commits: git_output(&index_root, &["rev-list", "--count", "HEAD"])?.parse().context("Counting commits")?,
+        ..MaintenanceReport::default()
+    };
+
+    let due = config.squash_after.is_some_and(|after| report.commits >= after);
+    if (force_squash || due) && report.commits > 1 {
+        report.squashed_to = Some(squash(git, &config.archive, &index_root, repo)?);
+    }
+
+    if report.squashed_to.is_some() && config.archive != Archive::Branch {
+        // Nothing else refers to the old history, so let gc drop it now rather than in two weeks
+        git_output(&index_root, &["reflog", "expire", "--expire=now", "--all"])?;
+        git_output(&index_root, &["gc", "--quiet", "--prune=now"])?;
+    } else {
+        git_output(&index_root, &["gc", "--quiet"])?;
+    }
+
+    Ok(report)
+}
+
+fn squash(git: &AppGitConfig, archive: &Archive, index_root: &Path, repo: &str) -> Result<String> {
+    let head = git_output(index_root, &["rev-parse", "--verify", "refs/heads/master"])?;
+    let snapshot = format!("snapshot-{}", compact_utc_time(SystemTime::now()));
+
+    let archived_to = match archive {
+        Archive::Bundle(dir) => {
+            let dir = dir.clone().unwrap_or_else(|| git.path.join(format!("{}.archive", repo)));
+            std::fs::create_dir_all(&dir).context("Creating archive directory")?;
+            let bundle = dir.join(format!("{}-{}.bundle", repo, snapshot));
+            git_output(index_root, &["bundle", "create", &bundle.to_string_lossy(), "refs/heads/master"])?;
+            bundle.to_string_lossy().to_string()
+        }
+        Archive::Branch => {
+            git_output(index_root, &["branch", &snapshot, &head])?;
+            format!("branch {}", snapshot)
+        }
+    };
+
+    let message = format!("(rotterdam): Squash index history\n\nPrevious history ends at {}, archived to {}", head, archived_to);
+    let tree = format!("{}^{{tree}}", head);
+    let squashed = git_output(index_root, &[
+        "-c", &format!("user.name={}", git.author_name),
+        "-c", &format!("user.email={}", git.author_email),
+        "commit-tree", &tree, "-m", &message,
+    ])?;
+    // Only moves master if nothing was committed while we were busy
+    git_output(index_root, &["update-ref", "-m", "squash", "refs/heads/master", &squashed, &head])?;
+
+    log::info!("Squashed {} history at {} into {}; archived to {}", repo, head, squashed, archived_to);
+    Ok(archived_to)
+}
+
+/// Runs maintenance on every repo, every `config.interval`, on a background thread. Each repo's
+/// writer is held off while its maintenance runs.
+pub(crate) fn spawn(git: AppGitConfig, config: MaintenanceConfig, writers: &IndexWriters, metrics: Arc<Metrics>) {
+    let mut writers: Vec<(String, Arc<IndexWriter>)> = writers.iter().map(|(repo, writer)| (repo.clone(), writer.clone())).collect();
+    writers.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let maintainer = move || loop {
+        std::thread::sleep(config.interval);
+        for (repo, writer) in writers.iter() {
+            let (git, config, name) = (git.clone(), config.clone(), repo.clone());
+            match writer.exclusive(move |_| run_repo(&git, &config, &name, false)) {
+                Ok(report) => {
+                    log::info!("Maintained {} ({} commits{})", repo, report.commits, if report.squashed_to.is_some() { ", squashed" } else { "" });
+                    metrics.inc("rotterdam_index_maintenance_total", labels(&[("repo", repo), ("task", "gc")]));
+                    if report.squashed_to.is_some() {
+                        metrics.inc("rotterdam_index_maintenance_total", labels(&[("repo", repo), ("task", "squash")]));
+                    }
+                }
+                Err(e) => {
+                    log::error!("Unable to maintain {}: {:#}", repo, e);
+                    metrics.inc("rotterdam_index_maintenance_total", labels(&[("repo", repo), ("task", "failed")]));
+                }
+            }
+        }
+    };
+
+    std::thread::Builder::new()
+        .name(String::from("index-maintenance"))
+        .spawn(maintainer)
+        .expect("Unable to start index maintenance thread");
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        git_output(dir, args).unwrap_or_else(|e| panic!("{:#}", e))
+    }
+
+    #[test]
+    fn squashes_and_archives_history() {
+        let registry = crate::app::test::TestRegistry::new();
+        let (git_config, index_root) = (&registry.config.git, registry.index_root());
+        std::fs::create_dir_all(&index_root).unwrap();
+        git(&index_root, &["init", "-q", "-b", "master"]);
+        for n in 0..3 {
+            std::fs::write(index_root.join("file"), format!("{}\n", n)).unwrap();
+            git(&index_root, &["add", "file"]);
+            git(&index_root, &["-c", "user.name=t", "-c", "user.email=t@t", "commit", "-q", "-m", &n.to_string()]);
+        }
+        let old_tree = git(&index_root, &["rev-parse", "HEAD^{tree}"]);
+
+        let clone = registry.dir.path().join("clone");
+        git(registry.dir.path(), &["clone", "-q", &index_root.to_string_lossy(), &clone.to_string_lossy()]);
+
+        let mut config = MaintenanceConfig { interval: Duration::from_secs(1), squash_after: Some(10), archive: Archive::Bundle(None) };
+
+        let report = run_repo(git_config, &config, "repo", false).unwrap();
+        assert_eq!(report.commits, 3);
+        assert!(report.squashed_to.is_none());
+
+        config.squash_after = Some(3);
+        let report = run_repo(git_config, &config, "repo", false).unwrap();
+        let bundle = report.squashed_to.unwrap();
+        assert_eq!(git(&index_root, &["rev-list", "--count", "HEAD"]), "1");
+        assert_eq!(git(&index_root, &["rev-parse", "HEAD^{tree}"]), old_tree);
+        assert!(git(&index_root, &["bundle", "list-heads", &bundle]).ends_with("refs/heads/master"));
+
+        // The way cargo fetches: a forced update of whatever HEAD now is
+        git(&clone, &["fetch", "-q", "origin", "+HEAD:refs/remotes/origin/HEAD"]);
+        assert_eq!(git(&clone, &["rev-parse", "refs/remotes/origin/HEAD"]), git(&index_root, &["rev-parse", "HEAD"]));
+
+        std::fs::write(index_root.join("file"), "3\n").unwrap();
+        git(&index_root, &["-c", "user.name=t", "-c", "user.email=t@t", "commit", "-q", "-am", "3"]);
+        config.archive = Archive::Branch;
+        let report = run_repo(git_config, &config, "repo", true).unwrap();
+        let branch = report.squashed_to.unwrap();
+        let branch = branch.strip_prefix("branch ").unwrap();
+        assert_eq!(git(&index_root, &["rev-list", "--count", branch]), "2");
+        assert_eq!(git(&index_root, &["rev-list", "--count", "master"]), "1");
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use smtr::server::{AccessLog, AccessRecord, ServerStats};
+
+use crate::app;
+use crate::blob_store::BlobStore;
+use crate::config::AppConfig;
+
+
+/// How often the size of each repo's index and tarballs is measured for
+/// `rotterdam_index_store_bytes` and `rotterdam_blob_store_bytes`.
+const STORE_SIZE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Per-repo events counted in `rotterdam_crate_events_total`.
+pub(crate) const REPO_EVENTS: [&str; 4] = ["publish", "yank", "unyank", "download"];
+
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+struct Family {
+    name: &'static str,
+    kind: Kind,
+    help: &'static str,
+}
+
+const FAMILIES: &[Family] = &[
+    Family { name: "rotterdam_http_requests_total", kind: Kind::Counter, help: "HTTP requests handled, by route and status" },
+    Family { name: "rotterdam_http_request_duration_seconds", kind: Kind::Histogram, help: "Time from accepting a request to finishing its response" },
+    Family { name: "rotterdam_git_backend_spawns_total", kind: Kind::Counter, help: "git http-backend processes spawned, by repo and outcome" },
+    Family { name: "rotterdam_git_backend_duration_seconds", kind: Kind::Histogram, help: "Time spent running git http-backend" },
+    Family { name: "rotterdam_upload_pack_requests_total", kind: Kind::Counter, help: "Fetches and ref advertisements served natively, by repo and command" },
+    Family { name: "rotterdam_upload_pack_duration_seconds", kind: Kind::Histogram, help: "Time spent serving native upload-pack requests" },
+    Family { name: "rotterdam_crate_events_total", kind: Kind::Counter, help: "Crate publishes, yanks, unyanks and downloads, by repo" },
+    Family { name: "rotterdam_connections_accepted_total", kind: Kind::Counter, help: "Connections accepted" },
+    Family { name: "rotterdam_connections_in_flight", kind: Kind::Gauge, help: "Connections accepted but not yet responded to" },
+    Family { name: "rotterdam_index_store_bytes", kind: Kind::Gauge, help: "Size on disk of each repo's git index" },
+    Family { name: "rotterdam_blob_store_bytes", kind: Kind::Gauge, help: "Total size of each repo's stored crate tarballs" },
+    Family { name: "rotterdam_blob_checksum_failures_total", kind: Kind::Counter, help: "Stored tarballs found not to match their checksum, on download or by the scrubber" },
+    Family { name: "rotterdam_blob_scrubbed_total", kind: Kind::Counter, help: "Stored tarballs re-hashed by the scrubber" },
+    Family { name: "rotterdam_index_maintenance_total", kind: Kind::Counter, help: "Scheduled index maintenance runs, by repo and task (gc, squash or failed)" },
+    Family { name: "rotterdam_mirror_pushes_total", kind: Kind::Counter, help: "Pushes of each repo's index to its mirror, by outcome" },
+];
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct Series {
+    counters: BTreeMap<(&'static str, Labels), u64>,
+    gauges: BTreeMap<(&'static str, Labels), f64>,
+    histograms: BTreeMap<(&'static str, Labels), Histogram>,
+}
+
+/// Metrics registry, rendered in the Prometheus text format at `/metrics`.
+pub(crate) struct Metrics {
+    series: Mutex<Series>,
+    server_stats: Arc<ServerStats>,
+    repos: Vec<String>,
+}
+
+impl Metrics {
+    pub(crate) fn new(config: &AppConfig, server_stats: Arc<ServerStats>) -> Self {
+        let mut repos: Vec<String> = config.repos.keys().map(|r| r.to_string()).collect();
+        repos.sort();
+
+        let metrics = Metrics {
+            series: Mutex::default(),
+            server_stats,
+            repos,
+        };
+
+        // Report zeroes up front so that rate() works from the first event
+        for repo in metrics.repos.iter() {
+            for event in REPO_EVENTS.iter() {
+                metrics.add("rotterdam_crate_events_total", labels(&[("repo", repo), ("event", event)]), 0);
+            }
+        }
+
+        metrics
+    }
+
+    pub(crate) fn inc(&self, name: &'static str, labels: Labels) {
+        self.add(name, labels, 1);
+    }
+
+    pub(crate) fn add(&self, name: &'static str, labels: Labels, n: u64) {
+        let mut series = self.series.lock().unwrap_or_else(|p| p.into_inner());
+        *series.counters.entry((name, labels)).or_insert(0) += n;
+    }
+
+    pub(crate) fn set(&self, name: &'static str, labels: Labels, value: f64) {
+        let mut series = self.series.lock().unwrap_or_else(|p| p.into_inner());
+        series.gauges.insert((name, labels), value);
+    }
+
+    /// Measures how much space each repo's index and tarballs take up. Listing a remote blob
+    /// store can be slow, so this runs in the background (see `spawn_store_sizes`) rather than
+    /// on each scrape.
+    pub(crate) fn measure_store_sizes(&self, git_path: &Path, blobs: &dyn BlobStore) {
+        for repo in self.repos.iter() {
+            let size = dir_size(&git_path.join(repo));
+            self.set("rotterdam_index_store_bytes", labels(&[("repo", repo)]), size as f64);
+
+            match blobs.list(&format!("{}/", repo)) {
+                Ok(blobs) => {
+                    let size: u64 = blobs.iter().map(|b| b.size).sum();
+                    self.set("rotterdam_blob_store_bytes", labels(&[("repo", repo)]), size as f64);
+                }
+                Err(e) => log::warn!("Unable to list blobs for {}: {}", repo, e),
+            }
+        }
+    }
+
+    pub(crate) fn observe(&self, name: &'static str, labels: Labels, duration: Duration) {
+        let mut series = self.series.lock().unwrap_or_else(|p| p.into_inner());
+        series.histograms.entry((name, labels)).or_default().observe(duration.as_secs_f64());
+    }
+
+    pub(crate) fn render(&self) -> String {
+        let mut gauges: BTreeMap<(&'static str, Labels), f64> = BTreeMap::new();
+        let mut counters: BTreeMap<(&'static str, Labels), u64> = BTreeMap::new();
+
+        counters.insert(("rotterdam_connections_accepted_total", Vec::new()), self.server_stats.accepted());
+        gauges.insert(("rotterdam_connections_in_flight", Vec::new()), self.server_stats.in_flight() as f64);
+
+        let mut out = String::new();
+        let series = self.series.lock().unwrap_or_else(|p| p.into_inner());
+        counters.extend(series.counters.iter().map(|(k, v)| (k.clone(), *v)));
+        gauges.extend(series.gauges.iter().map(|(k, v)| (k.clone(), *v)));
+
+        for family in FAMILIES {
+            let kind = match family.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+                Kind::Histogram => "histogram",
+            };
+            let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+            let _ = writeln!(out, "# TYPE {} {}", family.name, kind);
+
+            for ((_, l), v) in counters.iter().filter(|((n, _), _)| *n == family.name) {
+                let _ = writeln!(out, "{}{} {}", family.name, format_labels(l, None), v);
+            }
+            for ((_, l), v) in gauges.iter().filter(|((n, _), _)| *n == family.name) {
+                let _ = writeln!(out, "{}{} {}", family.name, format_labels(l, None), v);
+            }
+            for ((_, l), h) in series.histograms.iter().filter(|((n, _), _)| *n == family.name) {
+                for (bound, count) in LATENCY_BUCKETS.iter().zip(h.buckets.iter()) {
+                    let le = bound.to_string();
+                    let _ = writeln!(out, "{}_bucket{} {}", family.name, format_labels(l, Some(&le)), count);
+                }
+                let _ = writeln!(out, "{}_bucket{} {}", family.name, format_labels(l, Some("+Inf")), h.count);
+                let _ = writeln!(out, "{}_sum{} {}", family.name, format_labels(l, None), h.sum);
+                let _ = writeln!(out, "{}_count{} {}", family.name, format_labels(l, None), h.count);
+            }
+        }
+
+        out
+    }
+}
+
+/// Request counts and latencies come from the access log hook, so they also cover
+/// requests that smtr turned away before rotterdam saw them.
+impl AccessLog for Metrics {
+    fn log(&self, record: &AccessRecord) {
+        let route = match &record.path {
+            Some(path) => app::route_name(path),
+            None => "unparsed",
+        };
+        let status = record.status.map(|s| s.to_string()).unwrap_or_else(|| String::from("none"));
+
+        self.inc("rotterdam_http_requests_total", labels(&[("route", route), ("status", &status)]));
+        self.observe("rotterdam_http_request_duration_seconds", labels(&[("route", route)]), record.duration);
+    }
+}
+
+/// Measures each repo's store sizes now and every `STORE_SIZE_INTERVAL` after, on a background
+/// thread.
+pub(crate) fn spawn_store_sizes(metrics: Arc<Metrics>, git_path: PathBuf, blobs: Arc<dyn BlobStore>) {
+    let measure = move || loop {
+        metrics.measure_store_sizes(&git_path, blobs.as_ref());
+        std::thread::sleep(STORE_SIZE_INTERVAL);
+    };
+
+    std::thread::Builder::new()
+        .name(String::from("store-sizes"))
+        .spawn(measure)
+        .expect("Unable to start store size thread");
+}
+
+pub(crate) fn labels(pairs: &[(&'static str, &str)]) -> Labels {
+    pairs.iter().map(|(k, v)| (*k, v.to_string())).collect()
+}
+
+fn format_labels(labels: &[(&'static str, String)], le: Option<&str>) -> String {
+    let mut parts: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect();
+    if let Some(le) = le {
+        parts.push(format!("le=\"{}\"", le));
+    }
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", parts.join(","))
+    }
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| match e.file_type() {
+            Ok(t) if t.is_dir() => dir_size(&e.path()),
+            Ok(t) if t.is_file() => e.metadata().map(|m| m.len()).unwrap_or(0),
+            _ => 0,
+        })
+        .sum()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn renders_counters_and_histograms() {
+        let registry = crate::app::test::TestRegistry::new();
+        let config = AppConfig { repos: HashMap::new(), ..registry.config.clone() };
+        let m = Metrics::new(&config, Arc::default());
+        m.inc("rotterdam_git_backend_spawns_total", labels(&[("repo", "testrepo"), ("outcome", "success")]));
+        m.observe("rotterdam_git_backend_duration_seconds", labels(&[("repo", "testrepo")]), Duration::from_millis(30));
+
+        let out = m.render();
+
+        assert!(out.contains("# TYPE rotterdam_git_backend_spawns_total counter\n"));
+        assert!(out.contains("rotterdam_git_backend_spawns_total{repo=\"testrepo\",outcome=\"success\"} 1\n"));
+        assert!(out.contains("rotterdam_git_backend_duration_seconds_bucket{repo=\"testrepo\",le=\"0.025\"} 0\n"));
+        assert!(out.contains("rotterdam_git_backend_duration_seconds_bucket{repo=\"testrepo\",le=\"0.05\"} 1\n"));
+        assert!(out.contains("rotterdam_git_backend_duration_seconds_count{repo=\"testrepo\"} 1\n"));
+        assert!(out.contains("rotterdam_connections_in_flight 0\n"));
+    }
+
+    #[test]
+    fn reports_store_sizes_once_measured() {
+        let registry = crate::app::test::TestRegistry::new();
+        let _writer = registry.writer();
+        registry.blobs.put("repo/sha256/ab/abc", &mut &b"tarball"[..]).unwrap();
+        let m = Metrics::new(&registry.config, Arc::default());
+        assert!(! m.render().contains("rotterdam_blob_store_bytes{"));
+
+        m.measure_store_sizes(&registry.config.git.path, &registry.blobs);
+        let out = m.render();
+        assert!(out.contains("rotterdam_blob_store_bytes{repo=\"repo\"} 7\n"), "{}", out);
+        assert!(! out.contains("rotterdam_index_store_bytes{repo=\"repo\"} 0\n"));
+
+        // Scrapes report what was last measured
+        registry.blobs.put("repo/sha256/de/def", &mut &b"another"[..]).unwrap();
+        assert!(m.render().contains("rotterdam_blob_store_bytes{repo=\"repo\"} 7\n"));
+    }
+}
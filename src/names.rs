@@ -0,0 +1,110 @@
+use crate::auth::Identity;
+
+
+/// Longest crate (or repo) name accepted, as on crates.io.
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum InvalidName {
+    #[error("names must not be empty")]
+    Empty,
+    #[error("names must be at most {} characters", MAX_NAME_LEN)]
+    TooLong,
+    #[error("names must start with a letter, not {0:?}")]
+    BadStart(char),
+    #[error("names may only contain letters, numbers, - and _, not {0:?}")]
+    BadChar(char),
+}
+
+/// Checks `name` against crates.io's rules for crate names, which repo names follow too.
+pub(crate) fn validate(name: &str) -> Result<(), InvalidName> {
+    let first = name.chars().next().ok_or(InvalidName::Empty)?;
+    if name.len() > MAX_NAME_LEN {
+        return Err(InvalidName::TooLong);
+    }
+    if ! first.is_ascii_alphabetic() {
+        return Err(InvalidName::BadStart(first));
+    }
+    match name.chars().find(|c| ! (c.is_ascii_alphanumeric() || *c == '-' || *c == '_')) {
+        Some(c) => Err(InvalidName::BadChar(c)),
+        None => Ok(()),
+    }
+}
+
+/// The form names are compared in: two names are the same name if they differ only in case
+/// or in `-` versus `_`.
+pub(crate) fn normalize(name: &str) -> String {
+    name.to_ascii_lowercase().replace('-', "_")
+}
+
+/// Names and prefixes in a repo that only some people (or nobody) may publish under.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Reservations {
+    /// Names, with the users and teams allowed to publish them.
+    pub names: Vec<(String, Vec<String>)>,
+    /// Prefixes, with the users and teams allowed to publish under them.
+    pub prefixes: Vec<(String, Vec<String>)>,
+}
+
+impl Reservations {
+    /// Whether `publisher` (`None` when the request carried no known token) may publish `name`.
+    pub(crate) fn check(&self, name: &str, publisher: Option<&Identity>) -> Result<(), String> {
+        let normalized = normalize(name);
+        let allowed = |who: &[String]| publisher.is_some_and(|p| who.iter().any(|w| p.is(w)));
+        let who_may = |who: &[String]| match who {
+            [] => String::from("nobody may publish"),
+            who => format!("only {} may publish", who.join(", ")),
+        };
+
+        if let Some((_, who)) = self.names.iter().find(|(reserved, _)| normalize(reserved) == normalized) {
+            if ! allowed(who) {
+                return Err(format!("{} is a reserved name in this registry; {} it", name, who_may(who)));
+            }
+        }
+        for (prefix, who) in self.prefixes.iter().filter(|(prefix, _)| normalized.starts_with(&normalize(prefix))) {
+            if ! allowed(who) {
+                return Err(format!("Names starting with {} are reserved in this registry; {} them", prefix, who_may(who)));
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validates_names_like_crates_io() {
+        assert_eq!(validate("serde_json"), Ok(()));
+        assert_eq!(validate("Foo-Bar2"), Ok(()));
+        assert_eq!(validate(""), Err(InvalidName::Empty));
+        assert_eq!(validate("1foo"), Err(InvalidName::BadStart('1')));
+        assert_eq!(validate("_foo"), Err(InvalidName::BadStart('_')));
+        assert_eq!(validate("foo.bar"), Err(InvalidName::BadChar('.')));
+        assert_eq!(validate("fóo"), Err(InvalidName::BadChar('ó')));
+        assert_eq!(validate(&"a".repeat(65)), Err(InvalidName::TooLong));
+        assert_eq!(normalize("Foo-Bar_baz"), "foo_bar_baz");
+    }
+
+    #[test]
+    fn enforces_reservations() {
+        let reservations = Reservations {
+            names: vec![(String::from("std"), vec![]), (String::from("payments"), vec![String::from("payments")])],
+            prefixes: vec![(String::from("payments-"), vec![String::from("payments"), String::from("alice")])],
+        };
+        let alice = Identity { name: String::from("alice"), teams: vec![], admin: false };
+        let bob = Identity { name: String::from("bob"), teams: vec![String::from("payments")], admin: false };
+        let eve = Identity { name: String::from("eve"), teams: vec![String::from("web")], admin: false };
+
+        assert!(reservations.check("STD", Some(&bob)).unwrap_err().contains("nobody may publish it"));
+        assert!(reservations.check("payments", Some(&bob)).is_ok());
+        assert!(reservations.check("payments", Some(&alice)).unwrap_err().contains("only payments may publish it"));
+        assert!(reservations.check("payments_api", Some(&alice)).is_ok());
+        assert!(reservations.check("Payments-API", Some(&bob)).is_ok());
+        assert_eq!(reservations.check("payments-api", Some(&eve)).unwrap_err(), "Names starting with payments- are reserved in this registry; only payments, alice may publish them");
+        assert!(reservations.check("payments-api", None).is_err());
+        assert!(reservations.check("paymentsapi", None).is_ok());
+    }
+}
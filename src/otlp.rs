@@ -0,0 +1,193 @@
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use json::JsonValue;
+
+use crate::trace::{FinishedSpan, SpanExporter, SpanKind};
+
+
+/// Spans are sent once this many have queued up...
+const MAX_BATCH: usize = 64;
+/// ...or once the oldest queued span has waited this long.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug)]
+pub(crate) struct TracingConfig {
+    pub otlp_endpoint: String,
+    pub service_name: String,
+}
+
+/// Exports spans to an OpenTelemetry collector as OTLP/JSON over HTTP. Requests never wait
+/// on the collector: spans are handed to a background thread, which batches them up and
+/// drops them (with a warning) if the collector can't be reached.
+pub(crate) struct OtlpExporter {
+    spans: Mutex<Sender<FinishedSpan>>,
+}
+
+impl OtlpExporter {
+    pub(crate) fn start(config: &TracingConfig) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let config = config.clone();
+        std::thread::Builder::new()
+            .name(String::from("otlp-exporter"))
+            .spawn(move || export_loop(&config, rx))
+            .expect("Unable to start trace exporter thread");
+        OtlpExporter { spans: Mutex::new(tx) }
+    }
+}
+
+impl SpanExporter for OtlpExporter {
+    fn export(&self, span: FinishedSpan) {
+        let spans = self.spans.lock().unwrap_or_else(|p| p.into_inner());
+        let _ = spans.send(span);
+    }
+}
+
+fn export_loop(config: &TracingConfig, spans: Receiver<FinishedSpan>) {
+    let mut batch = Vec::new();
+    loop {
+        let next = if batch.is_empty() {
+            spans.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            spans.recv_timeout(FLUSH_INTERVAL)
+        };
+
+        let disconnected = match next {
+            Ok(span) => {
+                batch.push(span);
+                if batch.len() < MAX_BATCH {
+                    continue;
+                }
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+
+        if ! batch.is_empty() {
+            let body = encode(&config.service_name, &batch).dump();
+            let sent = ureq::post(&config.otlp_endpoint)
+                .set("Content-Type", "application/json")
+                .timeout(Duration::from_secs(10))
+                .send_string(&body);
+            if let Err(e) = sent {
+                log::warn!("Dropping {} spans; unable to export to {}: {}", batch.len(), config.otlp_endpoint, e);
+            }
+            batch.clear();
+        }
+
+        if disconnected {
+            return;
+        }
+    }
+}
+
+/// Builds an `ExportTraceServiceRequest` in the OTLP/JSON encoding.
+fn encode(service_name: &str, spans: &[FinishedSpan]) -> JsonValue {
+    let spans: Vec<JsonValue> = spans.iter().map(|span| {
+        let mut s = json::object! {
+            traceId: format!("{:032x}", span.trace_id),
+            spanId: format!("{:016x}", span.span_id),
+            name: span.name.as_str(),
+            kind: match span.kind {
+                SpanKind::Internal => 1,
+                SpanKind::Server => 2,
+            },
+            startTimeUnixNano: unix_nanos(span.start),
+            endTimeUnixNano: unix_nanos(span.end),
+            attributes: attributes(&span.attributes),
+        };
+        if let Some(parent) = span.parent_span_id {
+            s["parentSpanId"] = format!("{:016x}", parent).into();
+        }
+        s
+    }).collect();
+
+    let mut scope_spans = json::object! {
+        scope: { name: "rotterdam", version: env!("CARGO_PKG_VERSION") },
+    };
+    scope_spans["spans"] = spans.into();
+
+    let mut resource_spans = json::object! {
+        resource: { attributes: attributes(&[("service.name", service_name.to_string())]) },
+    };
+    resource_spans["scopeSpans"] = json::array![scope_spans];
+
+    json::object! { resourceSpans: [resource_spans] }
+}
+
+fn attributes(pairs: &[(&'static str, String)]) -> JsonValue {
+    pairs.iter()
+        .map(|(key, value)| json::object! { key: *key, value: { stringValue: value.as_str() } })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+/// OTLP/JSON carries 64-bit integers as strings.
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0).to_string()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /// Accepts one OTLP export and returns its body.
+    fn collector() -> (String, std::thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(len) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    content_length = len.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}").unwrap();
+            String::from_utf8(body).unwrap()
+        });
+        (endpoint, handle)
+    }
+
+    #[test]
+    fn exports_batches_to_collector() {
+        let (endpoint, collector) = collector();
+        let exporter = OtlpExporter::start(&TracingConfig { otlp_endpoint: endpoint, service_name: String::from("rotterdam-test") });
+
+        let now = SystemTime::now();
+        exporter.export(FinishedSpan {
+            trace_id: 0x4bf92f3577b34da6a3ce929d0e0e4736,
+            span_id: 0x1,
+            parent_span_id: Some(0x00f067aa0ba902b7),
+            name: String::from("request"),
+            kind: SpanKind::Server,
+            start: now,
+            end: now + Duration::from_millis(5),
+            attributes: vec![("rotterdam.request_id", String::from("abc"))],
+        });
+        drop(exporter);
+
+        let body = json::parse(&collector.join().unwrap()).unwrap();
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(resource["resource"]["attributes"][0]["value"]["stringValue"], "rotterdam-test");
+        let span = &resource["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span["spanId"], "0000000000000001");
+        assert_eq!(span["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(span["kind"], 2);
+        assert_eq!(span["attributes"][0]["key"], "rotterdam.request_id");
+    }
+}
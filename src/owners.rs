@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use json::JsonValue;
+use smtr::{server::{Response, ConnectionResponseWriter}, Method, Request};
+
+use crate::auth::{Identity, NotAllowed};
+use crate::config::{AppConfig, AppGitConfig};
+use crate::index::{self, NotFound};
+use crate::index_writer::IndexWriter;
+use crate::names;
+use crate::publish;
+
+
+/// A malformed owners request, in words fit to show whoever made it.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub(crate) struct InvalidRequest(pub String);
+
+/// Who owns each crate in a repo, as a JSON object of normalized crate names to users and
+/// teams. It's kept beside the repo's index checkout rather than in it, so it's never served
+/// or mirrored, and only changed on the repo's index writer, so changes can't race publishes.
+pub(crate) fn path(git: &AppGitConfig, repo: &str) -> PathBuf {
+    git.path.join(format!("{}.owners.json", repo))
+}
+
+fn load(path: &Path) -> Result<BTreeMap<String, Vec<String>>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).with_context(|| format!("Reading {}", path.to_string_lossy())),
+    };
+    let owners = json::parse(&contents).with_context(|| format!("{} is not valid JSON", path.to_string_lossy()))?;
+    Ok(owners.entries()
+        .map(|(name, owners)| (name.to_string(), owners.members().filter_map(|o| o.as_str()).map(str::to_string).collect()))
+        .collect())
+}
+
+fn save(path: &Path, owners: &BTreeMap<String, Vec<String>>) -> Result<()> {
+    let mut contents = JsonValue::new_object();
+    for (name, owners) in owners {
+        contents[name.as_str()] = owners.clone().into();
+    }
+    // Written aside and renamed into place, so a crash never leaves half a file
+    let partial = path.with_extension("json.part");
+    std::fs::write(&partial, contents.pretty(2)).with_context(|| format!("Writing {}", partial.to_string_lossy()))?;
+    std::fs::rename(&partial, path).with_context(|| format!("Replacing {}", path.to_string_lossy()))
+}
+
+/// The owners of `name`, empty if nobody owns it (it was published before owners were kept,
+/// or without a token that belongs to anyone). Only admins may change crates nobody owns.
+pub(crate) fn owners_of(path: &Path, name: &str) -> Result<Vec<String>> {
+    Ok(load(path)?.remove(&names::normalize(name)).unwrap_or_default())
+}
+
+/// Refuses unless `who` may `action` (say, "yank it") on `name`: they're one of its owners or
+/// an admin. A crate nobody owns is left to admins.
+pub(crate) fn check_owner(name: &str, owners: &[String], who: Option<&Identity>, action: &str) -> Result<(), NotAllowed> {
+    let allowed = who.is_some_and(|who| who.admin || owners.iter().any(|o| who.is(o)));
+    if allowed {
+        Ok(())
+    } else if owners.is_empty() {
+        Err(NotAllowed(format!("Nobody owns {}, so only an admin may {}", name, action)))
+    } else {
+        Err(NotAllowed(format!("Only {}'s owners ({}) may {}", name, owners.join(", "), action)))
+    }
+}
+
+/// Refuses unless `publisher` may publish a version of `name`. New crates (`is_new`, none of
+/// their versions in the index yet) are open to anyone; any owners on record for them are
+/// left over from a publish that didn't make it into the index.
+pub(crate) fn check_publisher(path: &Path, name: &str, is_new: bool, publisher: Option<&Identity>) -> Result<()> {
+    if ! is_new {
+        check_owner(name, &owners_of(path, name)?, publisher, "publish new versions of it")?;
+    }
+    Ok(())
+}
+
+/// Makes `publisher` the owner of `name` if it's new. Call this on the repo's index writer,
+/// after `check_publisher`.
+pub(crate) fn record_publisher(path: &Path, name: &str, is_new: bool, publisher: Option<&Identity>) -> Result<()> {
+    let mut owners = load(path)?;
+    let normalized = names::normalize(name);
+    match publisher {
+        Some(publisher) if is_new => {
+            owners.insert(normalized, vec![publisher.name.clone()]);
+        }
+        // Published anonymously, nobody owns it, whatever a failed publish left behind
+        None if is_new && owners.remove(&normalized).is_some() => {}
+        _ => return Ok(()),
+    }
+    save(path, &owners)
+}
+
+/// Handles `cargo owner` (`GET`, `PUT` and `DELETE` on
+/// `/repo/<repo>/api/v1/crates/<name>/owners`).
+pub(crate) fn handle(
+    config: &AppConfig, writer: &IndexWriter, repo: &str, name: &str, req: &mut dyn Request, mut resp: ConnectionResponseWriter,
+) -> Result<()> {
+    let result = match req.method() {
+        Method::Get => list(config, repo, name),
+        Method::Put | Method::Delete => {
+            let who = config.users.identify(req).cloned();
+            if let Some(who) = &who {
+                resp.set_principal(&who.name);
+            }
+            let add = matches!(req.method(), Method::Put);
+            publish::read_body(req).map_err(anyhow::Error::from)
+                .and_then(|body| change(config, writer, repo, name, who, add, &body))
+        }
+        _ => {
+            resp.send_response(Response::err(405))?;
+            return Ok(());
+        }
+    };
+
+    match result {
+        Ok(body) => {
+            let r = Response::builder(200)
+                .content_type("application/json")
+                .body_from_string(&body.dump())
+                .build();
+            resp.send_response(r)?;
+            Ok(())
+        }
+        Err(e) => match publish::client_error(&e) {
+            Some((status, details)) => {
+                log::info!("Refusing owners request for {} in {}: {}", name, repo, e);
+                publish::send_errors(resp, status, &details)
+            }
+            None => {
+                publish::send_error(resp, 500, "Internal error while handling owners")?;
+                Err(e).context("Handling owners request")
+            }
+        },
+    }
+}
+
+fn list(config: &AppConfig, repo: &str, name: &str) -> Result<JsonValue> {
+    let index_root = config.git.path.join(repo);
+    if index::entries(&index_root, name)?.is_empty() {
+        anyhow::bail!(NotFound(format!("There's no crate called {} in {}", name, repo)));
+    }
+    let owners = owners_of(&path(&config.git, repo), name)?;
+    let users: Vec<JsonValue> = owners.iter().enumerate().map(|(i, owner)| json::object! {
+        id: i + 1,
+        login: owner.as_str(),
+        kind: if config.users.is_user(owner) { "user" } else { "team" },
+    }).collect();
+    Ok(json::object! { users: users })
+}
+
+fn change(config: &AppConfig, writer: &IndexWriter, repo: &str, name: &str, who: Option<Identity>, add: bool, body: &[u8]) -> Result<JsonValue> {
+    let who = who.ok_or_else(|| NotAllowed(String::from("Changing owners needs a token that belongs to a user of this registry")))?;
+    let logins: Vec<String> = std::str::from_utf8(body).ok().and_then(|b| json::parse(b).ok())
+        .map(|b| b["users"].members().filter_map(|u| u.as_str()).map(str::to_string).collect())
+        .unwrap_or_default();
+    if logins.is_empty() {
+        anyhow::bail!(InvalidRequest(String::from("The request should list the owners to change as {\"users\": [...]}")));
+    }
+    if let Some(unknown) = logins.iter().find(|login| add && ! config.users.knows(login)) {
+        anyhow::bail!(InvalidRequest(format!("There's no user or team called {} in this registry", unknown)));
+    }
+
+    let path = path(&config.git, repo);
+    let name = name.to_string();
+    let message = logins.join(", ");
+    writer.exclusive(move |index_root| {
+        let published = index::entries(index_root, &name)?;
+        let published_name = match published.first() {
+            Some(entry) => entry.name.clone(),
+            None => anyhow::bail!(NotFound(format!("There's no crate called {}", name))),
+        };
+        let mut owners = load(&path)?;
+        let crate_owners = owners.entry(names::normalize(&name)).or_default();
+        check_owner(&published_name, crate_owners, Some(&who), "change its owners")?;
+
+        if add {
+            for login in logins.iter() {
+                if ! crate_owners.contains(login) {
+                    crate_owners.push(login.clone());
+                }
+            }
+        } else {
+            crate_owners.retain(|o| ! logins.contains(o));
+            if crate_owners.is_empty() {
+                anyhow::bail!(NotAllowed(format!("{} must keep at least one owner", published_name)));
+            }
+        }
+        log::info!("{} {} {} as owners of {}", who.name, if add { "added" } else { "removed" }, message, published_name);
+        save(&path, &owners)?;
+
+        let msg = if add {
+            format!("{} added as owners of {}", message, published_name)
+        } else {
+            format!("{} removed as owners of {}", message, published_name)
+        };
+        Ok(json::object! { ok: true, msg: msg })
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_and_checks_owners() {
+        let registry = crate::app::test::TestRegistry::new();
+        let path = path(&registry.config.git, "repo");
+        let identity = |name: &str, teams: &[&str], admin: bool| Identity { name: name.to_string(), teams: teams.iter().map(|t| t.to_string()).collect(), admin };
+        let (alice, bob, root_user) = (identity("alice", &[], false), identity("bob", &["payments"], false), identity("root", &[], true));
+
+        check_publisher(&path, "Foo-Bar", true, Some(&alice)).unwrap();
+        record_publisher(&path, "Foo-Bar", true, Some(&alice)).unwrap();
+        assert_eq!(owners_of(&path, "foo_bar").unwrap(), ["alice"]);
+
+        check_publisher(&path, "Foo-Bar", false, Some(&alice)).unwrap();
+        let refused = check_publisher(&path, "Foo-Bar", false, Some(&bob)).unwrap_err();
+        assert_eq!(refused.downcast::<NotAllowed>().unwrap().0, "Only Foo-Bar's owners (alice) may publish new versions of it");
+        assert!(check_publisher(&path, "Foo-Bar", false, None).is_err());
+        check_publisher(&path, "Foo-Bar", false, Some(&root_user)).unwrap();
+        record_publisher(&path, "Foo-Bar", false, Some(&root_user)).unwrap();
+        assert_eq!(owners_of(&path, "foo-bar").unwrap(), ["alice"]);
+
+        // Crates published without a known token are left to admins, whoever publishes next
+        record_publisher(&path, "Legacy", true, Some(&bob)).unwrap();
+        record_publisher(&path, "legacy", true, None).unwrap();
+        assert!(owners_of(&path, "legacy").unwrap().is_empty());
+        let refused = check_publisher(&path, "legacy", false, None).unwrap_err();
+        assert_eq!(refused.downcast::<NotAllowed>().unwrap().0, "Nobody owns legacy, so only an admin may publish new versions of it");
+        assert!(check_publisher(&path, "legacy", false, Some(&bob)).is_err());
+        check_publisher(&path, "legacy", false, Some(&root_user)).unwrap();
+        record_publisher(&path, "legacy", false, Some(&bob)).unwrap();
+        assert!(owners_of(&path, "legacy").unwrap().is_empty());
+        assert!(check_owner("legacy", &[], None, "yank it").is_err());
+        assert!(check_owner("legacy", &[], Some(&root_user), "yank it").is_ok());
+
+        assert!(check_owner("pay", &[String::from("payments")], Some(&bob), "yank it").is_ok());
+        assert!(check_owner("pay", &[String::from("payments")], Some(&alice), "yank it").is_err());
+    }
+}
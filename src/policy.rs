@@ -0,0 +1,335 @@
+use std::path::Path;
+
+use anyhow::Result;
+use json::JsonValue;
+
+use crate::crate_file::{Registries, Unpacked, CRATES_IO_INDEX};
+use crate::index;
+
+
+/// `[package]` fields a policy can require.
+pub(crate) const METADATA_FIELDS: &[&str] = &["license", "description", "repository", "homepage", "documentation", "readme", "keywords", "categories", "authors"];
+
+/// Rules a repo applies to every publish, from `[rotterdam.repos.<name>.policy]`. The default
+/// allows anything.
+#[derive(Clone, Debug)]
+pub(crate) struct PublishPolicy {
+    /// `[package]` fields that must be set. `license` is satisfied by `license-file` too,
+    /// unless `allowed_licenses` is set, which requires `license` anyway.
+    pub required_metadata: Vec<String>,
+    /// SPDX license ids; a crate's license expression must be satisfiable using only these.
+    pub allowed_licenses: Option<Vec<String>>,
+    pub forbid_build_scripts: bool,
+    pub forbid_proc_macros: bool,
+    /// Each version must be greater than every version of the crate published before it.
+    pub monotonic_versions: bool,
+    pub allow_prerelease: bool,
+    /// Largest `.crate` accepted, in bytes.
+    pub max_crate_size: Option<u64>,
+    /// Index URLs dependencies may come from, besides this repo. `crates.io` is short for
+    /// crates.io's index.
+    pub allowed_registries: Option<Vec<String>>,
+    /// Dependencies on this server's repos must match a version that's been published (and
+    /// not yanked) there. crates.io's can't be checked from here.
+    pub resolvable_dependencies: bool,
+}
+
+impl Default for PublishPolicy {
+    fn default() -> Self {
+        PublishPolicy {
+            required_metadata: Vec::new(),
+            allowed_licenses: None,
+            forbid_build_scripts: false,
+            forbid_proc_macros: false,
+            monotonic_versions: false,
+            allow_prerelease: true,
+            max_crate_size: None,
+            allowed_registries: None,
+            resolvable_dependencies: false,
+        }
+    }
+}
+
+/// Every way a crate breaks its repo's policy, each in words fit to show whoever published it.
+#[derive(Debug, thiserror::Error)]
+#[error("{}", .0.join("; "))]
+pub(crate) struct PolicyViolations(pub Vec<String>);
+
+impl PublishPolicy {
+    /// Checks everything that depends only on the crate itself: `unpacked` is its contents,
+    /// `line` the index line it would get and `size` the size of the `.crate`.
+    pub(crate) fn check(&self, unpacked: &Unpacked, line: &JsonValue, size: u64) -> Result<(), PolicyViolations> {
+        let mut violations = Vec::new();
+        let package = unpacked.manifest.get("package");
+        let field = |key: &str| package.and_then(|p| p.get(key)).filter(|v| match v {
+            toml::Value::String(s) => ! s.trim().is_empty(),
+            toml::Value::Array(a) => ! a.is_empty(),
+            _ => true,
+        });
+
+        for required in self.required_metadata.iter() {
+            if required == "license" && self.allowed_licenses.is_some() {
+                continue; // Reported below, along with what's allowed
+            }
+            let present = field(required).is_some() || (required == "license" && field("license-file").is_some());
+            if ! present {
+                violations.push(format!("This registry requires package.{} to be set in Cargo.toml", required));
+            }
+        }
+
+        if let Some(allowed) = &self.allowed_licenses {
+            let list = allowed.join(", ");
+            match field("license").and_then(|l| l.as_str()) {
+                None => violations.push(format!("This registry requires package.license to be an SPDX expression using only {}", list)),
+                Some(license) => match license_allowed(license, allowed) {
+                    None => violations.push(format!("package.license ({:?}) is not a valid SPDX license expression", license)),
+                    Some(false) => violations.push(format!("The license {:?} isn't allowed in this registry, which accepts only {}", license, list)),
+                    Some(true) => {}
+                },
+            }
+        }
+
+        if self.forbid_build_scripts {
+            if let Some(script) = build_script(unpacked) {
+                violations.push(format!("Crates in this registry may not have build scripts, and this one has {}", script));
+            }
+        }
+
+        if self.forbid_proc_macros {
+            let lib = unpacked.manifest.get("lib");
+            let flag = |key: &str| lib.and_then(|l| l.get(key)).and_then(|v| v.as_bool()).unwrap_or(false);
+            if flag("proc-macro") || flag("proc_macro") {
+                violations.push(String::from("Crates in this registry may not be proc-macros"));
+            }
+        }
+
+        let vers = line["vers"].as_str().unwrap_or_default();
+        if ! self.allow_prerelease && vers.split('+').next().unwrap_or_default().contains('-') {
+            violations.push(format!("This registry only accepts stable releases, and {} is a pre-release", vers));
+        }
+
+        if let Some(max) = self.max_crate_size {
+            if size > max {
+                violations.push(format!("The .crate file is {} bytes, more than this registry's limit of {} bytes", size, max));
+            }
+        }
+
+        if let Some(allowed) = &self.allowed_registries {
+            let same = |a: &str, b: &str| a.trim_end_matches('/') == b.trim_end_matches('/');
+            for dep in line["deps"].members() {
+                let registry = match dep["registry"].as_str() {
+                    Some(registry) => registry,
+                    None => continue, // This registry
+                };
+                let is_allowed = allowed.iter().any(|a| same(if a == "crates.io" { CRATES_IO_INDEX } else { a }, registry));
+                if ! is_allowed {
+                    violations.push(format!("The dependency {} comes from {}, which isn't a registry this one allows dependencies from", dep["name"], registry));
+                }
+            }
+        }
+
+        if violations.is_empty() { Ok(()) } else { Err(PolicyViolations(violations)) }
+    }
+
+    /// Checks that the dependencies in the index line `line` can be resolved, when the policy
+    /// asks for that. Dev-dependencies are left out, as nobody using the crate needs them.
+    pub(crate) fn check_dependencies(&self, line: &JsonValue, registries: &Registries, git_path: &Path) -> Result<()> {
+        if ! self.resolvable_dependencies {
+            return Ok(());
+        }
+        let mut violations = Vec::new();
+        for dep in line["deps"].members().filter(|dep| dep["kind"] != "dev") {
+            let repo = match registries.repo_of(dep["registry"].as_str()) {
+                Some(repo) => repo,
+                None => continue,
+            };
+            let package = dep["package"].as_str().or(dep["name"].as_str()).unwrap_or_default();
+            let req = dep["req"].as_str().unwrap_or("*");
+            let published = index::entries(&git_path.join(repo), package)?;
+            if ! published.iter().any(|entry| ! entry.yanked && index::version_matches(req, &entry.vers)) {
+                violations.push(format!("The dependency {} {} doesn't match any version of {} published in {}", dep["name"], req, package, repo));
+            }
+        }
+        if violations.is_empty() { Ok(()) } else { Err(PolicyViolations(violations).into()) }
+    }
+
+    /// Checks `vers` against the versions of the crate already published.
+    pub(crate) fn check_version_order(&self, name: &str, vers: &str, published: &[String]) -> Result<(), PolicyViolations> {
+        if ! self.monotonic_versions {
+            return Ok(());
+        }
+        match published.iter().max_by(|a, b| index::cmp_versions(a, b)) {
+            Some(latest) if index::cmp_versions(vers, latest) != std::cmp::Ordering::Greater => Err(PolicyViolations(vec![
+                format!("This registry only accepts versions newer than the latest, and {} {} is not newer than {}", name, vers, latest),
+            ])),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The crate's build script, if it has one: wherever `package.build` says, or `build.rs`.
+fn build_script(unpacked: &Unpacked) -> Option<String> {
+    let build = unpacked.manifest.get("package").and_then(|p| p.get("build"));
+    match build {
+        Some(toml::Value::Boolean(false)) => None,
+        Some(toml::Value::String(path)) => Some(path.clone()),
+        _ => unpacked.files.iter().any(|f| f == Path::new("build.rs")).then(|| String::from("build.rs")),
+    }
+}
+
+/// Whether the SPDX license `expression` can be met using only `allowed` licenses: both sides
+/// of an `AND`, either side of an `OR`. An exception (`WITH ...`) only adds permissions, so
+/// it's the license that counts. `None` if the expression doesn't parse.
+fn license_allowed(expression: &str, allowed: &[String]) -> Option<bool> {
+    // Cargo still accepts the old `MIT/Apache-2.0` form, meaning OR
+    let spaced = expression.replace('(', " ( ").replace(')', " ) ").replace('/', " OR ");
+    let mut parser = LicenseParser { tokens: spaced.split_whitespace().collect(), pos: 0, allowed };
+    let result = parser.or()?;
+    if parser.pos == parser.tokens.len() { Some(result) } else { None }
+}
+
+struct LicenseParser<'a> {
+    tokens: Vec<&'a str>,
+    pos: usize,
+    allowed: &'a [String],
+}
+
+impl LicenseParser<'_> {
+    fn eat(&mut self, keyword: &str) -> bool {
+        let found = self.tokens.get(self.pos).is_some_and(|t| t.eq_ignore_ascii_case(keyword));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Option<bool> {
+        let mut result = self.and()?;
+        while self.eat("OR") {
+            let rhs = self.and()?;
+            result = result || rhs;
+        }
+        Some(result)
+    }
+
+    fn and(&mut self) -> Option<bool> {
+        let mut result = self.license()?;
+        while self.eat("AND") {
+            let rhs = self.license()?;
+            result = result && rhs;
+        }
+        Some(result)
+    }
+
+    fn license(&mut self) -> Option<bool> {
+        if self.eat("(") {
+            let result = self.or()?;
+            return if self.eat(")") { Some(result) } else { None };
+        }
+        let id = *self.tokens.get(self.pos)?;
+        if [")", "AND", "OR", "WITH"].iter().any(|k| id.eq_ignore_ascii_case(k)) {
+            return None;
+        }
+        self.pos += 1;
+        if self.eat("WITH") {
+            let exception = *self.tokens.get(self.pos)?;
+            if ["(", ")", "AND", "OR", "WITH"].iter().any(|k| exception.eq_ignore_ascii_case(k)) {
+                return None;
+            }
+            self.pos += 1;
+        }
+        Some(self.allowed.iter().any(|a| a.eq_ignore_ascii_case(id)))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn unpacked(manifest: &str, files: &[&str]) -> Unpacked {
+        Unpacked { manifest: manifest.parse().unwrap(), files: files.iter().map(PathBuf::from).collect() }
+    }
+
+    #[test]
+    fn evaluates_license_expressions() {
+        let allowed = [String::from("MIT"), String::from("Apache-2.0")];
+        assert_eq!(license_allowed("MIT", &allowed), Some(true));
+        assert_eq!(license_allowed("mit OR GPL-3.0", &allowed), Some(true));
+        assert_eq!(license_allowed("MIT AND GPL-3.0", &allowed), Some(false));
+        assert_eq!(license_allowed("(MIT OR GPL-3.0) AND Apache-2.0 WITH LLVM-exception", &allowed), Some(true));
+        assert_eq!(license_allowed("MIT/Apache-2.0", &allowed), Some(true));
+        assert_eq!(license_allowed("GPL-3.0/LGPL-3.0", &allowed), Some(false));
+        assert_eq!(license_allowed("MIT OR", &allowed), None);
+        assert_eq!(license_allowed("(MIT", &allowed), None);
+        assert_eq!(license_allowed("MIT Apache-2.0", &allowed), None);
+        assert_eq!(license_allowed("", &allowed), None);
+    }
+
+    #[test]
+    fn reports_each_violation() {
+        let policy = PublishPolicy {
+            required_metadata: vec![String::from("description"), String::from("repository")],
+            allowed_licenses: Some(vec![String::from("MIT")]),
+            forbid_build_scripts: true,
+            forbid_proc_macros: true,
+            allow_prerelease: false,
+            max_crate_size: Some(100),
+            allowed_registries: Some(vec![String::from("crates.io")]),
+            ..PublishPolicy::default()
+        };
+        let crate_file = unpacked(r#"
+            [package]
+            name = "foo"
+            version = "1.0.0-rc.1"
+            description = ""
+            license = "GPL-3.0"
+            [lib]
+            proc-macro = true
+        "#, &["Cargo.toml", "build.rs"]);
+        let line = json::object! {
+            vers: "1.0.0-rc.1",
+            deps: [
+                { name: "serde", registry: CRATES_IO_INDEX },
+                { name: "local", registry: null },
+                { name: "elsewhere", registry: "https://example.com/index" },
+            ],
+        };
+
+        let violations = policy.check(&crate_file, &line, 1000).unwrap_err().0;
+        assert_eq!(violations, [
+            "This registry requires package.description to be set in Cargo.toml",
+            "This registry requires package.repository to be set in Cargo.toml",
+            "The license \"GPL-3.0\" isn't allowed in this registry, which accepts only MIT",
+            "Crates in this registry may not have build scripts, and this one has build.rs",
+            "Crates in this registry may not be proc-macros",
+            "This registry only accepts stable releases, and 1.0.0-rc.1 is a pre-release",
+            "The .crate file is 1000 bytes, more than this registry's limit of 100 bytes",
+            "The dependency elsewhere comes from https://example.com/index, which isn't a registry this one allows dependencies from",
+        ]);
+
+        let crate_file = unpacked(r#"
+            [package]
+            name = "foo"
+            version = "1.0.0"
+            description = "Foo"
+            repository = "https://example.com/foo"
+            license = "MIT OR GPL-3.0"
+            build = false
+        "#, &["Cargo.toml", "build.rs"]);
+        policy.check(&crate_file, &json::object! { vers: "1.0.0", deps: [] }, 100).unwrap();
+        assert!(PublishPolicy::default().check(&crate_file, &line, 1000).is_ok());
+    }
+
+    #[test]
+    fn checks_version_order() {
+        let policy = PublishPolicy { monotonic_versions: true, ..PublishPolicy::default() };
+        let published = [String::from("1.0.0"), String::from("1.2.0"), String::from("1.1.0")];
+        assert!(policy.check_version_order("foo", "1.2.1", &published).is_ok());
+        assert!(policy.check_version_order("foo", "2.0.0-alpha", &published).is_ok());
+        let violation = policy.check_version_order("foo", "1.1.1", &published).unwrap_err().0;
+        assert_eq!(violation, ["This registry only accepts versions newer than the latest, and foo 1.1.1 is not newer than 1.2.0"]);
+        assert!(PublishPolicy::default().check_version_order("foo", "1.1.1", &published).is_ok());
+    }
+}
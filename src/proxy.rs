@@ -0,0 +1,320 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use smtr::{Header, Headers, Request};
+
+
+/// An address range (`10.0.0.0/8`, `::1`) from which forwarding headers are believed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => self.contains(IpAddr::V4(ip)),
+                None => false,
+            },
+            (IpAddr::V6(_), IpAddr::V4(ip)) => self.contains(IpAddr::V6(ip.to_ipv6_mapped())),
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    let rest_bits = prefix_len % 8;
+
+    if net[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - rest_bits);
+    net[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct InvalidIpRange;
+
+impl FromStr for IpRange {
+    type Err = InvalidIpRange;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| InvalidIpRange)?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.trim().parse::<u8>().map_err(|_| InvalidIpRange)?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(InvalidIpRange);
+        }
+
+        Ok(IpRange { addr, prefix_len })
+    }
+}
+
+
+/// The set of reverse proxies whose `Forwarded` / `X-Forwarded-*` headers we believe. With
+/// no trusted proxies configured, forwarding headers are ignored entirely.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TrustedProxies {
+    ranges: Vec<IpRange>,
+}
+
+impl TrustedProxies {
+    pub(crate) fn new(ranges: Vec<IpRange>) -> Self {
+        TrustedProxies { ranges }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.ranges.iter().any(|r| r.contains(ip))
+    }
+
+    /// Works out who the client really is, taking forwarding headers into account when
+    /// the request reached us through a trusted proxy.
+    pub(crate) fn client_info(&self, req: &dyn Request) -> ClientInfo {
+        self.resolve(req.remote_addr(), req.headers())
+    }
+
+    fn resolve(&self, peer: Option<SocketAddr>, headers: &Headers) -> ClientInfo {
+        let direct = ClientInfo {
+            addr: peer.map(|p| p.ip()),
+            scheme: String::from("http"),
+            host: header_str(headers, Header::Host).map(String::from),
+            proxied: false,
+        };
+
+        match direct.addr {
+            Some(ip) if self.is_trusted(ip) => {}
+            _ => return direct,
+        }
+
+        let hops = if let Some(forwarded) = header_str(headers, Header::Forwarded) {
+            parse_forwarded(forwarded)
+        } else if let Some(xff) = header_str(headers, Header::XForwardedFor) {
+            parse_x_forwarded(
+                xff,
+                header_str(headers, Header::XForwardedProto),
+                header_str(headers, Header::XForwardedHost),
+            )
+        } else {
+            return direct;
+        };
+
+        // Walk back from the hop nearest to us; the first address we don't trust is the client.
+        // If every hop is trusted, the client is the furthest one we know about.
+        let client_hop = hops
+            .iter()
+            .rev()
+            .find(|hop| !matches!(hop.addr, Some(ip) if self.is_trusted(ip)))
+            .or_else(|| hops.first());
+
+        match client_hop {
+            Some(hop) => ClientInfo {
+                addr: hop.addr,
+                scheme: hop.proto.clone().unwrap_or(direct.scheme),
+                host: hop.host.clone().or(direct.host),
+                proxied: true,
+            },
+            None => direct,
+        }
+    }
+}
+
+
+/// The client as seen from rotterdam once any trusted proxies have been accounted for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ClientInfo {
+    pub addr: Option<IpAddr>,
+    pub scheme: String,
+    pub host: Option<String>,
+    /// Whether this came from a trusted proxy's forwarding headers.
+    pub proxied: bool,
+}
+
+impl ClientInfo {
+    /// The URL the client asked a trusted proxy for (`https://crates.example.com`), if it came
+    /// through one that said which host it asked for.
+    pub(crate) fn base_url(&self) -> Option<String> {
+        let host = self.host.as_deref().filter(|_| self.proxied)?;
+        let plausible = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']');
+        if host.is_empty() || ! host.chars().all(plausible) || ! matches!(self.scheme.as_str(), "http" | "https") {
+            return None;
+        }
+        Some(format!("{}://{}", self.scheme, host))
+    }
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.addr {
+            Some(addr) => write!(f, "{}", addr)?,
+            None => write!(f, "-")?,
+        }
+        write!(f, " ({}://{})", self.scheme, self.host.as_deref().unwrap_or("-"))
+    }
+}
+
+
+#[derive(Debug, Default)]
+struct Hop {
+    addr: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+fn header_str(headers: &Headers, header: Header) -> Option<&str> {
+    headers.get(header).and_then(|v| std::str::from_utf8(v).ok())
+}
+
+/// RFC 7239: `for=192.0.2.60;proto=http, for="[2001:db8::1]:4711"`
+fn parse_forwarded(value: &str) -> Vec<Hop> {
+    value
+        .split(',')
+        .map(|element| {
+            let mut hop = Hop::default();
+            for pair in element.split(';') {
+                let (key, val) = match pair.split_once('=') {
+                    Some(kv) => kv,
+                    None => continue,
+                };
+                let val = val.trim().trim_matches('"');
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "for" => hop.addr = parse_node(val),
+                    "proto" => hop.proto = Some(val.to_ascii_lowercase()),
+                    "host" => hop.host = Some(val.to_string()),
+                    _ => {}
+                }
+            }
+            hop
+        })
+        .collect()
+}
+
+fn parse_x_forwarded(xff: &str, proto: Option<&str>, host: Option<&str>) -> Vec<Hop> {
+    let mut hops: Vec<_> = xff
+        .split(',')
+        .map(|node| Hop {
+            addr: parse_node(node.trim()),
+            ..Hop::default()
+        })
+        .collect();
+
+    // X-Forwarded-Proto/Host don't line up with X-Forwarded-For entries, so they describe
+    // the request as first seen by the outermost proxy, whichever hop turns out to be the client.
+    let proto = proto.and_then(|p| p.split(',').next()).map(|p| p.trim().to_ascii_lowercase());
+    let host = host.and_then(|h| h.split(',').next()).map(|h| h.trim().to_string());
+    for hop in hops.iter_mut() {
+        hop.proto = proto.clone();
+        hop.host = host.clone();
+    }
+
+    hops
+}
+
+/// Accepts `1.2.3.4`, `1.2.3.4:80`, `::1`, `[::1]` and `[::1]:80`. Obfuscated identifiers
+/// and `unknown` yield `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(sock) = node.parse::<SocketAddr>() {
+        return Some(sock.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|n| n.strip_suffix(']'))
+        .and_then(|n| n.parse().ok())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn proxies(ranges: &[&str]) -> TrustedProxies {
+        TrustedProxies::new(ranges.iter().map(|r| r.parse().unwrap()).collect())
+    }
+
+    fn peer(addr: &str) -> Option<SocketAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_ranges() {
+        let r: IpRange = "10.0.0.0/8".parse().unwrap();
+        assert!(r.contains("10.1.2.3".parse().unwrap()));
+        assert!(!r.contains("11.1.2.3".parse().unwrap()));
+
+        let r: IpRange = "192.168.4.0/22".parse().unwrap();
+        assert!(r.contains("192.168.7.255".parse().unwrap()));
+        assert!(!r.contains("192.168.8.0".parse().unwrap()));
+
+        let r: IpRange = "::1".parse().unwrap();
+        assert!(r.contains("::1".parse().unwrap()));
+
+        assert_eq!("10.0.0.0/33".parse::<IpRange>(), Err(InvalidIpRange));
+        assert_eq!("localhost".parse::<IpRange>(), Err(InvalidIpRange));
+    }
+
+    #[test]
+    fn ignores_forwarding_headers_from_untrusted_peers() {
+        let mut headers = Headers::default();
+        headers.set(Header::XForwardedFor, b"203.0.113.7".to_vec());
+        headers.set(Header::XForwardedProto, b"https".to_vec());
+
+        let client = proxies(&["127.0.0.1"]).resolve(peer("198.51.100.1:4000"), &headers);
+
+        assert_eq!(client.addr, Some("198.51.100.1".parse().unwrap()));
+        assert_eq!(client.scheme, "http");
+        assert_eq!(client.base_url(), None);
+    }
+
+    #[test]
+    fn uses_x_forwarded_headers_from_trusted_peer() {
+        let mut headers = Headers::default();
+        headers.set(Header::Host, b"127.0.0.1:8080".to_vec());
+        headers.set(Header::XForwardedFor, b"6.6.6.6, 203.0.113.7, 10.0.0.2".to_vec());
+        headers.set(Header::XForwardedProto, b"https".to_vec());
+        headers.set(Header::XForwardedHost, b"crates.example.com".to_vec());
+
+        let client = proxies(&["127.0.0.1", "10.0.0.0/8"]).resolve(peer("127.0.0.1:4000"), &headers);
+
+        assert_eq!(client.addr, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(client.scheme, "https");
+        assert_eq!(client.host.as_deref(), Some("crates.example.com"));
+        assert_eq!(client.base_url().as_deref(), Some("https://crates.example.com"));
+    }
+
+    #[test]
+    fn prefers_forwarded_header() {
+        let mut headers = Headers::default();
+        headers.set(Header::XForwardedFor, b"6.6.6.6".to_vec());
+        headers.set(
+            Header::Forwarded,
+            br#"for="[2001:db8::1]:4711";proto=https;host=crates.example.com, for=10.0.0.2;proto=http"#.to_vec(),
+        );
+
+        let client = proxies(&["::1", "10.0.0.0/8"]).resolve(peer("[::1]:4000"), &headers);
+
+        assert_eq!(client.addr, Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(client.scheme, "https");
+        assert_eq!(client.host.as_deref(), Some("crates.example.com"));
+    }
+}
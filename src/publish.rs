@@ -0,0 +1,321 @@
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use json::JsonValue;
+use smtr::{server::{Response, ConnectionResponseWriter}, Request};
+
+use crate::auth::{Identity, NotAllowed};
+use crate::blob_store::{self, BlobStore};
+use crate::config::AppConfig;
+use crate::crate_file::{self, InvalidCrate, Registries};
+use crate::index::{self, NotFound};
+use crate::index_writer::{AfterCommit, Edit, IndexWriter};
+use crate::metrics::{labels, Metrics};
+use crate::names;
+use crate::owners::{self, InvalidRequest};
+use crate::policy::{PolicyViolations, PublishPolicy};
+use crate::search;
+use crate::trace;
+
+
+#[derive(Clone, Debug)]
+pub(crate) struct PublishConfig {
+    /// The most a `.crate` may decompress to. The upload itself is bounded by
+    /// `rotterdam.server.max_body_bytes`.
+    pub max_unpacked_bytes: u64,
+}
+
+impl Default for PublishConfig {
+    fn default() -> Self {
+        PublishConfig { max_unpacked_bytes: 512 << 20 }
+    }
+}
+
+/// Handles `cargo publish` (`PUT /repo/<repo>/api/v1/crates/new`). Anything wrong with the
+/// upload goes back to cargo as a JSON error, and nothing reaches the blob store or index.
+pub(crate) fn handle(
+    config: &AppConfig, metrics: &Metrics, blobs: &dyn BlobStore, writer: &IndexWriter,
+    repo: &str, req: &mut dyn Request, mut resp: ConnectionResponseWriter,
+) -> Result<()> {
+    let publisher = config.users.identify(req).cloned();
+    if let Some(publisher) = &publisher {
+        resp.set_principal(&publisher.name);
+    }
+    let published = read_body(req).map_err(anyhow::Error::from)
+        .and_then(|body| publish(config, blobs, writer, repo, publisher.as_ref(), &body));
+    match published {
+        Ok(line) => {
+            log::info!("Published {} {} to {}", line["name"], line["vers"], repo);
+            metrics.inc("rotterdam_crate_events_total", labels(&[("repo", repo), ("event", "publish")]));
+            let r = Response::builder(200)
+                .content_type("application/json")
+                .body_from_string(&json::object! { warnings: { invalid_categories: [], invalid_badges: [], other: [] } }.dump())
+                .build();
+            resp.send_response(r)?;
+            Ok(())
+        }
+        Err(e) => match client_error(&e) {
+            Some((status, details)) => {
+                log::info!("Refusing publish to {}: {}", repo, e);
+                send_errors(resp, status, &details)
+            }
+            None => {
+                send_error(resp, 500, "Internal error while publishing")?;
+                Err(e).context("Publishing crate")
+            }
+        },
+    }
+}
+
+/// A request body that couldn't be read. One that stopped arriving in time (smtr's body idle
+/// timeout or request deadline) gets a 408, so the client knows it may try again.
+#[derive(Debug, thiserror::Error)]
+#[error("Unable to read the request body: {0}")]
+pub(crate) struct UnreadableBody(#[from] pub std::io::Error);
+
+impl UnreadableBody {
+    pub(crate) fn status(&self) -> u16 {
+        if self.0.kind() == std::io::ErrorKind::TimedOut { 408 } else { 400 }
+    }
+}
+
+/// Reads all of `req`'s body, if it has one.
+pub(crate) fn read_body(req: &mut dyn Request) -> Result<Vec<u8>, UnreadableBody> {
+    Ok(req.read_body()?.unwrap_or_default())
+}
+
+/// The status and error details to send back for `e`, if it's the client's doing.
+pub(crate) fn client_error(e: &anyhow::Error) -> Option<(u16, Vec<String>)> {
+    if let Some(violations) = e.downcast_ref::<PolicyViolations>() {
+        return Some((400, violations.0.clone()));
+    }
+    if let Some(e) = e.downcast_ref::<UnreadableBody>() {
+        return Some((e.status(), vec![e.to_string()]));
+    }
+    let (status, detail) = if let Some(e) = e.downcast_ref::<InvalidCrate>() {
+        (400, &e.0)
+    } else if let Some(e) = e.downcast_ref::<InvalidRequest>() {
+        (400, &e.0)
+    } else if let Some(e) = e.downcast_ref::<NotAllowed>() {
+        (403, &e.0)
+    } else if let Some(e) = e.downcast_ref::<NotFound>() {
+        (404, &e.0)
+    } else {
+        return None;
+    };
+    Some((status, vec![detail.clone()]))
+}
+
+pub(crate) fn send_error(resp: ConnectionResponseWriter, status: u16, detail: &str) -> Result<()> {
+    send_errors(resp, status, &[detail.to_string()])
+}
+
+/// Cargo shows the `detail` of each error to whoever ran it.
+pub(crate) fn send_errors(mut resp: ConnectionResponseWriter, status: u16, details: &[String]) -> Result<()> {
+    let errors: Vec<JsonValue> = details.iter().map(|detail| json::object! { detail: detail.as_str() }).collect();
+    let r = Response::builder(status)
+        .content_type("application/json")
+        .body_from_string(&json::object! { errors: errors }.dump())
+        .build();
+    resp.send_response(r)?;
+    Ok(())
+}
+
+fn publish(config: &AppConfig, blobs: &dyn BlobStore, writer: &IndexWriter, repo: &str, publisher: Option<&Identity>, body: &[u8]) -> Result<JsonValue> {
+    let (metadata, tarball) = parse_body(body)?;
+
+    let _span = trace::span("validate");
+    let registries = Registries::load(config, repo);
+    let unpacked = crate_file::unpack(tarball, config.publish.max_unpacked_bytes)?;
+    crate_file::check_metadata(&metadata, &unpacked.manifest, &registries)?;
+    let cksum = blob_store::sha256_hex(&mut &tarball[..])?;
+    let line = crate_file::index_line(&unpacked.manifest, &cksum, &registries)?;
+    drop(_span);
+
+    let (name, vers) = (line["name"].as_str().unwrap_or_default().to_string(), line["vers"].as_str().unwrap_or_default().to_string());
+    names::validate(&name).map_err(|e| InvalidCrate(format!("Invalid crate name {:?}: {}", name, e)))?;
+    let policy = match config.repos.get(repo) {
+        Some(repo) => {
+            repo.reserved.check(&name, publisher).map_err(InvalidCrate)?;
+            repo.policy.clone()
+        }
+        None => PublishPolicy::default(),
+    };
+    policy.check(&unpacked, &line, tarball.len() as u64)?;
+    policy.check_dependencies(&line, &registries, &config.git.path)?;
+    let index_root = config.git.path.join(repo);
+    check_published(&index_root, &name, &vers, &policy)?;
+    let owners_file = owners::path(&config.git, repo);
+    owners::check_publisher(&owners_file, &name, index::entries(&index_root, &name)?.is_empty(), publisher)?;
+
+    // The tarball goes in first, so the index never names one that isn't there
+    let _span = trace::span("storage");
+    blobs.put(&blob_store::tarball_key(repo, &cksum), &mut &tarball[..]).context("Storing crate tarball")?;
+    drop(_span);
+
+    let _span = trace::span("index");
+    let path = index::index_path(&name);
+    let contents = line.dump();
+    let publisher = publisher.cloned();
+    let (metadata_file, manifest) = (search::metadata_path(&config.git, repo), unpacked.manifest.clone());
+    writer.mutate_then(format!("Publish {} {}", name, vers), Box::new(move |index_root: &Path| {
+        // Checked again here, now nothing else can be publishing it or changing its owners
+        check_published(index_root, &name, &vers, &policy)?;
+        let is_new = index::entries(index_root, &name)?.is_empty();
+        owners::check_publisher(&owners_file, &name, is_new, publisher.as_ref())?;
+        let mut existing = std::fs::read(index_root.join(&path)).unwrap_or_default();
+        existing.extend_from_slice(contents.as_bytes());
+        existing.push(b'\n');
+        // Owners and search only hear of the version once the index has it
+        let record: AfterCommit = Box::new(move || {
+            owners::record_publisher(&owners_file, &name, is_new, publisher.as_ref())?;
+            search::record_metadata(&metadata_file, &name, &vers, &manifest)
+        });
+        Ok((vec![Edit { path, contents: Some(existing) }], record))
+    }))?;
+
+    Ok(line)
+}
+
+/// Refuses `name` `vers` if it clashes with what's in the index: the same crate under another
+/// spelling, the same version again, or an older version than the policy allows.
+fn check_published(index_root: &Path, name: &str, vers: &str, policy: &PublishPolicy) -> Result<()> {
+    if let Some(published) = index::published_name(index_root, name)?.filter(|published| published != name) {
+        anyhow::bail!(InvalidCrate(format!("{} has already been published as {}; names that differ only in case or -/_ are the same crate", name, published)));
+    }
+    let published: Vec<String> = index::entries(index_root, name)?.into_iter().map(|entry| entry.vers).collect();
+    if published.iter().any(|v| v == vers) {
+        anyhow::bail!(InvalidCrate(format!("{} {} has already been published", name, vers)));
+    }
+    policy.check_version_order(name, vers, &published)?;
+    Ok(())
+}
+
+/// Splits a publish body into its JSON metadata and `.crate`: each is preceded by its
+/// length, as a little-endian u32.
+fn parse_body(body: &[u8]) -> Result<(JsonValue, &[u8])> {
+    let mut rest = body;
+    let mut take = |what: &str| -> Result<&[u8]> {
+        let mut len = [0; 4];
+        rest.read_exact(&mut len).map_err(|_| InvalidCrate(format!("The publish request ends before the {}", what)))?;
+        let len = u32::from_le_bytes(len) as usize;
+        if rest.len() < len {
+            anyhow::bail!(InvalidCrate(format!("The publish request ends part way through the {}", what)));
+        }
+        let (taken, remaining) = rest.split_at(len);
+        rest = remaining;
+        Ok(taken)
+    };
+
+    let metadata = take("metadata")?;
+    let metadata = std::str::from_utf8(metadata).ok().and_then(|m| json::parse(m).ok())
+        .filter(JsonValue::is_object)
+        .ok_or_else(|| InvalidCrate(String::from("The publish metadata is not a JSON object")))?;
+    let tarball = take(".crate file")?;
+    Ok((metadata, tarball))
+}
+
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    pub(crate) fn body(metadata: &str, tarball: &[u8]) -> Vec<u8> {
+        let mut body = (metadata.len() as u32).to_le_bytes().to_vec();
+        body.extend_from_slice(metadata.as_bytes());
+        body.extend_from_slice(&(tarball.len() as u32).to_le_bytes());
+        body.extend_from_slice(tarball);
+        body
+    }
+
+    #[test]
+    fn parses_publish_bodies() {
+        let full = body(r#"{"name":"foo"}"#, b"tarball");
+        let (metadata, tarball) = parse_body(&full).unwrap();
+        assert_eq!(metadata["name"], "foo");
+        assert_eq!(tarball, b"tarball");
+
+        for truncated in [&full[..2], &full[..10], &full[..full.len() - 1]] {
+            assert!(parse_body(truncated).unwrap_err().is::<InvalidCrate>());
+        }
+        assert!(parse_body(&body("[]", b"")).unwrap_err().is::<InvalidCrate>());
+    }
+
+    #[test]
+    fn publishes_valid_crates_once() {
+        let registry = crate::app::test::TestRegistry::new();
+        let (config, blobs, writer) = (&registry.config, &registry.blobs, registry.writer());
+
+        let manifest = "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n\n[dependencies]\nlog = \"0.4\"\n";
+        let tarball = crate_file::test::dot_crate(&[("foo-0.1.0/Cargo.toml", manifest), ("foo-0.1.0/src/lib.rs", "")]);
+        let metadata = r#"{"name":"foo","vers":"0.1.0","deps":[{"name":"log","version_req":"^0.4","features":[],"optional":false,"default_features":true,"target":null,"kind":"normal","registry":"https://github.com/rust-lang/crates.io-index"}]}"#;
+
+        let line = publish(config, blobs, &writer, "repo", None, &body(metadata, &tarball)).unwrap();
+        let entry = index::find_version(&config.git.path.join("repo"), "foo", "0.1.0").unwrap().unwrap();
+        assert_eq!(entry.cksum, line["cksum"].as_str().unwrap());
+        assert!(blobs.exists(&blob_store::tarball_key("repo", &entry.cksum)).unwrap());
+
+        let again = publish(config, blobs, &writer, "repo", None, &body(metadata, &tarball)).unwrap_err();
+        assert!(again.downcast::<InvalidCrate>().unwrap().0.contains("already been published"));
+
+        let lying = metadata.replace("^0.4", "^0.3");
+        assert!(publish(config, blobs, &writer, "repo", None, &body(&lying, &tarball)).unwrap_err().is::<InvalidCrate>());
+
+        let manifest = manifest.replace("foo", "FOO").replace("0.1.0", "0.2.0");
+        let tarball = crate_file::test::dot_crate(&[("FOO-0.2.0/Cargo.toml", &manifest)]);
+        let metadata = metadata.replace("foo", "FOO").replace("0.1.0", "0.2.0");
+        let confusable = publish(config, blobs, &writer, "repo", None, &body(&metadata, &tarball)).unwrap_err();
+        assert!(confusable.downcast::<InvalidCrate>().unwrap().0.contains("already been published as foo"));
+    }
+
+    #[test]
+    fn records_owners_and_metadata_only_once_committed() {
+        let registry = crate::app::test::TestRegistry::new();
+        let (config, blobs, writer) = (&registry.config, &registry.blobs, registry.writer());
+        let alice = Identity { name: String::from("alice"), teams: vec![], admin: false };
+        let manifest = "[package]\nname = \"foo\"\nversion = \"0.1.0\"\ndescription = \"Foo\"\n";
+        let foo = body(r#"{"name":"foo","vers":"0.1.0","deps":[]}"#, &crate_file::test::dot_crate(&[("foo-0.1.0/Cargo.toml", manifest)]));
+        let (owners_file, metadata_file) = (owners::path(&config.git, "repo"), search::metadata_path(&config.git, "repo"));
+
+        // Something else holds master, so the commit fails
+        let lock = registry.index_root().join(".git/refs/heads/master.lock");
+        std::fs::write(&lock, "").unwrap();
+        assert!(publish(config, blobs, &writer, "repo", Some(&alice), &foo).is_err());
+        assert!(index::entries(&registry.index_root(), "foo").unwrap().is_empty());
+        assert!(owners::owners_of(&owners_file, "foo").unwrap().is_empty());
+        assert!(! metadata_file.exists());
+
+        std::fs::remove_file(&lock).unwrap();
+        publish(config, blobs, &writer, "repo", Some(&alice), &foo).unwrap();
+        assert_eq!(owners::owners_of(&owners_file, "foo").unwrap(), ["alice"]);
+        assert!(std::fs::read_to_string(&metadata_file).unwrap().contains("Foo"));
+    }
+
+    #[test]
+    fn checks_dependencies_resolve_when_asked() {
+        let mut registry = crate::app::test::TestRegistry::new();
+        let policy = PublishPolicy { resolvable_dependencies: true, ..PublishPolicy::default() };
+        registry.config.repos.insert("repo".into(), crate::config::Repo { name: "repo".into(), policy, ..Default::default() });
+        let (config, blobs, writer) = (&registry.config, &registry.blobs, registry.writer());
+
+        let publish_crate = |name: &str, dependencies: &str, deps: &str| {
+            let manifest = format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\n\n[dependencies]\n{}", name, dependencies);
+            let tarball = crate_file::test::dot_crate(&[(&format!("{}-0.1.0/Cargo.toml", name), &manifest)]);
+            let metadata = format!(r#"{{"name":"{}","vers":"0.1.0","deps":[{}]}}"#, name, deps);
+            publish(config, blobs, &writer, "repo", None, &body(&metadata, &tarball))
+        };
+        let bar = |req: &str| publish_crate(
+            "bar",
+            &format!("foo = {{ version = \"{}\", registry-index = \"http://127.0.0.1:8080/repo/repo/index\" }}\nlog = \"0.4\"\n", req),
+            &format!(r#"{{"name":"foo","version_req":"{}","features":[],"optional":false,"default_features":true,"target":null,"kind":"normal","registry":null}},{{"name":"log","version_req":"^0.4","features":[],"optional":false,"default_features":true,"target":null,"kind":"normal","registry":"https://github.com/rust-lang/crates.io-index"}}"#, req),
+        );
+
+        let unresolvable = bar("^0.1").unwrap_err().downcast::<PolicyViolations>().unwrap().0;
+        assert_eq!(unresolvable, ["The dependency foo ^0.1 doesn't match any version of foo published in repo"]);
+        publish_crate("foo", "", "").unwrap();
+        assert!(bar("^0.2").unwrap_err().is::<PolicyViolations>());
+        let line = bar("^0.1").unwrap();
+        assert!(line["deps"][0]["registry"].is_null());
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, anyhow, bail};
+use json::JsonValue;
+
+use crate::blob_store::{self, BlobStore};
+use crate::config::AppConfig;
+use crate::crate_file;
+use crate::index;
+use crate::index_writer::{Edit, IndexWriter};
+use crate::names;
+use crate::search;
+
+
+/// A version recovered from a stored tarball, ready to go into the index.
+struct Rebuilt {
+    name: String,
+    vers: String,
+    line: JsonValue,
+    manifest: toml::Value,
+}
+
+/// Recreates `repo`'s index from the tarballs in the blob store, committing one version at a
+/// time in semver order. Yanks are replayed from `audit_log` (a JSON access log) when given.
+/// An existing index is only replaced with `force`, and is moved aside rather than deleted.
+pub(crate) fn run(config: &AppConfig, blobs: &dyn BlobStore, repo: &str, audit_log: Option<&Path>, force: bool) -> Result<JsonValue> {
+    if ! config.repos.contains_key(repo) {
+        bail!("{} is not a configured repo", repo);
+    }
+
+    let index_root = config.git.path.join(repo);
+    let mut moved_aside = None;
+    if index_root.exists() {
+        if ! force {
+            bail!("{} already exists; pass --force to move it aside and rebuild", index_root.to_string_lossy());
+        }
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let aside = config.git.path.join(format!("{}.before-rebuild-{}", repo, secs));
+        std::fs::rename(&index_root, &aside).context("Moving the existing index aside")?;
+        log::info!("Moved the existing {} index to {}", repo, aside.to_string_lossy());
+        moved_aside = Some(aside);
+    }
+    std::fs::create_dir_all(&config.git.path)?;
+    let writer = crate::app::ensure_index_setup(&config.git, config.base_url(), repo)?;
+
+    let yanked = match audit_log {
+        Some(path) => yanked_versions(path, repo)?,
+        None => HashSet::new(),
+    };
+
+    let registries = crate_file::Registries::load(config, repo);
+    let mut skipped = Vec::new();
+    let mut crates: BTreeMap<String, Vec<Rebuilt>> = BTreeMap::new();
+    for blob in blobs.list(&format!("{}/sha256/", repo))? {
+        let cksum = match blob_store::tarball_cksum(&blob.key) {
+            Some(cksum) => cksum.to_string(),
+            None => continue,
+        };
+        match read_tarball(blobs, &blob.key, &cksum, &registries, config.publish.max_unpacked_bytes) {
+            Ok(mut rebuilt) => {
+                let versions = crates.entry(rebuilt.name.clone()).or_default();
+                if versions.iter().any(|v| v.vers == rebuilt.vers) {
+                    skipped.push(json::object! { key: blob.key.as_str(), reason: format!("Another tarball is already {} {}", rebuilt.name, rebuilt.vers) });
+                    continue;
+                }
+                rebuilt.line["yanked"] = yanked.contains(&(names::normalize(&rebuilt.name), rebuilt.vers.clone())).into();
+                versions.push(rebuilt);
+            }
+            Err(e) => {
+                log::warn!("Skipping {}: {:#}", blob.key, e);
+                skipped.push(json::object! { key: blob.key.as_str(), reason: format!("{:#}", e) });
+            }
+        }
+    }
+
+    let metadata_file = search::metadata_path(&config.git, repo);
+    let mut versions = 0;
+    let mut yanked_count = 0;
+    for rebuilt in crates.values_mut() {
+        rebuilt.sort_by(|a, b| index::cmp_versions(&a.vers, &b.vers));
+        for version in rebuilt.iter() {
+            append_version(&writer, &metadata_file, version)
+                .with_context(|| format!("Committing {} {}", version.name, version.vers))?;
+            versions += 1;
+            if version.line["yanked"] == true {
+                yanked_count += 1;
+            }
+        }
+    }
+
+    let mut report = json::object! {
+        repo: repo,
+        crates: crates.len(),
+        versions: versions,
+        yanked: yanked_count,
+    };
+    report["skipped"] = skipped.into();
+    report["moved_aside"] = moved_aside.map(|p| p.to_string_lossy().to_string()).into();
+    Ok(report)
+}
+
+/// Tarballs go through the same checks as a publish would; anything that wouldn't be accepted
+/// now is skipped.
+fn read_tarball(blobs: &dyn BlobStore, key: &str, cksum: &str, registries: &crate_file::Registries, max_unpacked: u64) -> Result<Rebuilt> {
+    let mut blob = blobs.get(key)?.ok_or_else(|| anyhow!("Tarball disappeared"))?;
+    let mut contents = Vec::new();
+    blob.reader.read_to_end(&mut contents)?;
+    let actual = blob_store::sha256_hex(&mut &contents[..])?;
+    if actual != cksum {
+        bail!("Tarball is corrupt (hashes to {})", actual);
+    }
+
+    let manifest = crate_file::unpack(&contents, max_unpacked)?.manifest;
+    let line = crate_file::index_line(&manifest, cksum, registries)?;
+    Ok(Rebuilt {
+        name: line["name"].as_str().unwrap_or_default().to_string(),
+        vers: line["vers"].as_str().unwrap_or_default().to_string(),
+        line,
+        manifest,
+    })
+}
+
+/// `(name, version)` pairs left yanked by the successful yank/unyank requests in a JSON
+/// access log, replayed in order. Requests may spell a crate's name any way that normalizes
+/// to it, so names come back normalized.
+fn yanked_versions(path: &Path, repo: &str) -> Result<HashSet<(String, String)>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Opening audit log at {}", path.to_string_lossy()))?;
+    let mut yanked = HashSet::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = match json::parse(&line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::warn!("Ignoring line {} of {}: {}", n + 1, path.to_string_lossy(), e);
+                continue;
+            }
+        };
+        if entry["status"].as_u16() != Some(200) {
+            continue;
+        }
+        let path = entry["path"].as_str().unwrap_or_default();
+        let parts: Vec<_> = path.split('?').next().unwrap_or_default().split('/').collect();
+        match (entry["method"].as_str(), parts.as_slice()) {
+            (Some("DELETE"), ["", "repo", r, "api", "v1", "crates", name, version, "yank"]) if *r == repo => {
+                yanked.insert((names::normalize(name), version.to_string()));
+            }
+            (Some("PUT"), ["", "repo", r, "api", "v1", "crates", name, version, "unyank"]) if *r == repo => {
+                yanked.remove(&(names::normalize(name), version.to_string()));
+            }
+            _ => {}
+        }
+    }
+    Ok(yanked)
+}
+
+fn append_version(writer: &IndexWriter, metadata_file: &Path, version: &Rebuilt) -> Result<()> {
+    let path = index::index_path(&version.name);
+    let line = version.line.dump();
+    let (metadata_file, name, vers, manifest) = (metadata_file.to_path_buf(), version.name.clone(), version.vers.clone(), version.manifest.clone());
+    writer.mutate(format!("Rebuild {} {}", version.name, version.vers), Box::new(move |index_root: &Path| {
+        search::record_metadata(&metadata_file, &name, &vers, &manifest)?;
+        let mut contents = std::fs::read(index_root.join(&path)).unwrap_or_default();
+        contents.extend_from_slice(line.as_bytes());
+        contents.push(b'\n');
+        Ok(vec![Edit { path, contents: Some(contents) }])
+    }))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crate_file::CRATES_IO_INDEX;
+    use crate::crate_file::test::dot_crate;
+    use std::collections::HashMap;
+    use std::process::Command;
+
+    #[test]
+    fn rebuilds_index_from_tarballs() {
+        let registry = crate::app::test::TestRegistry::new();
+        let (config, blobs) = (&registry.config, &registry.blobs);
+
+        let mut cksums = HashMap::new();
+        for vers in ["1.0.0", "0.2.0", "1.0.0-rc.1"] {
+            let manifest = format!(r#"
+                [package]
+                name = "foo"
+                version = "{}"
+                links = "z"
+                rust-version = "1.56"
+
+                [dependencies]
+                serde = {{ version = "1", features = ["derive"], optional = true }}
+                bar = {{ version = "0.1", registry-index = "http://localhost:8080/repo/repo/index" }}
+                baz2 = {{ version = "2", package = "baz" }}
+
+                [target.'cfg(unix)'.dev-dependencies]
+                libc = "0.2"
+
+                [features]
+                default = ["std"]
+                std = []
+                derive = ["dep:serde"]
+            "#, vers);
+            let contents = dot_crate(&[(&format!("foo-{}/Cargo.toml", vers), &manifest)]);
+            let cksum = blob_store::sha256_hex(&mut &contents[..]).unwrap();
+            blobs.put(&blob_store::tarball_key("repo", &cksum), &mut &contents[..]).unwrap();
+            cksums.insert(vers, cksum);
+        }
+        blobs.put(&blob_store::tarball_key("repo", &"0".repeat(64)), &mut &b"not a tarball"[..]).unwrap();
+
+        let audit_log = registry.dir.path().join("access.log");
+        std::fs::write(&audit_log, concat!(
+            r#"{"method":"DELETE","path":"/repo/repo/api/v1/crates/Foo/0.2.0/yank","status":200}"#, "\n",
+            r#"{"method":"DELETE","path":"/repo/repo/api/v1/crates/foo/1.0.0/yank","status":200}"#, "\n",
+            r#"{"method":"PUT","path":"/repo/repo/api/v1/crates/FOO/1.0.0/unyank","status":200}"#, "\n",
+            r#"{"method":"PUT","path":"/repo/repo/api/v1/crates/foo/0.2.0/unyank","status":403}"#, "\n",
+        )).unwrap();
+
+        let report = run(config, blobs, "repo", Some(&audit_log), false).unwrap();
+        assert_eq!(report["versions"], 3);
+        assert_eq!(report["yanked"], 1);
+        assert_eq!(report["skipped"].len(), 1);
+
+        let contents = std::fs::read_to_string(registry.index_root().join("3/f/foo")).unwrap();
+        let lines: Vec<_> = contents.lines().map(|l| json::parse(l).unwrap()).collect();
+        let versions: Vec<_> = lines.iter().map(|l| l["vers"].as_str().unwrap()).collect();
+        assert_eq!(versions, ["0.2.0", "1.0.0-rc.1", "1.0.0"]);
+        assert_eq!(lines[0]["yanked"], true);
+        assert_eq!(lines[2]["yanked"], false);
+
+        let line = &lines[2];
+        assert_eq!(line["cksum"], cksums["1.0.0"].as_str());
+        assert_eq!(line["links"], "z");
+        assert_eq!(line["rust_version"], "1.56");
+        assert_eq!(line["v"], 2);
+        assert_eq!(line["features2"]["derive"][0], "dep:serde");
+        assert!(line["features"]["derive"].is_null());
+        let dep = |name: &str| line["deps"].members().find(|d| d["name"] == name).unwrap().clone();
+        assert_eq!(dep("serde")["optional"], true);
+        assert_eq!(dep("serde")["registry"], CRATES_IO_INDEX);
+        assert!(dep("bar")["registry"].is_null());
+        assert_eq!(dep("baz2")["package"], "baz");
+        assert_eq!(dep("libc")["kind"], "dev");
+        assert_eq!(dep("libc")["target"], "cfg(unix)");
+
+        let log = Command::new("git").current_dir(registry.index_root()).args(["log", "--format=%s"]).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&log.stdout).lines().count(), 4);
+
+        assert!(run(config, blobs, "repo", None, false).is_err());
+        let report = run(config, blobs, "repo", None, true).unwrap();
+        assert!(report["moved_aside"].is_string());
+    }
+}
@@ -0,0 +1,122 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::blob_store::{self, BlobStore, Result};
+use crate::metrics::{labels, Metrics};
+
+
+#[derive(Clone, Debug)]
+pub(crate) struct ScrubConfig {
+    /// How long to wait between passes over the blob store.
+    pub interval: Duration,
+    /// Move corrupt tarballs aside (to `<repo>/quarantine/`) rather than only reporting them.
+    pub quarantine: bool,
+}
+
+/// What one pass over a repo's tarballs found.
+#[derive(Debug, Default)]
+pub(crate) struct ScrubReport {
+    pub checked: u64,
+    /// Keys whose contents don't hash to the checksum they're stored under, with the hash
+    /// they do have.
+    pub mismatched: Vec<(String, String)>,
+}
+
+/// Re-hashes every tarball stored for `repo`, comparing each with the checksum in its key.
+pub(crate) fn scrub_repo(blobs: &dyn BlobStore, repo: &str, quarantine: bool) -> Result<ScrubReport> {
+    let mut report = ScrubReport::default();
+
+    for blob in blobs.list(&format!("{}/sha256/", repo))? {
+        let expected = match blob_store::tarball_cksum(&blob.key) {
+            Some(cksum) => cksum,
+            None => {
+                log::warn!("Scrubber skipping {}: not named for its checksum", blob.key);
+                continue;
+            }
+        };
+        let mut contents = match blobs.get(&blob.key)? {
+            Some(contents) => contents,
+            None => continue, // Deleted since we listed it
+        };
+
+        let actual = blob_store::sha256_hex(&mut contents.reader)?;
+        report.checked += 1;
+        if actual == expected {
+            continue;
+        }
+
+        log::error!("Blob {} is corrupt: its contents hash to {}", blob.key, actual);
+        if quarantine {
+            let quarantined = move_aside(blobs, &blob.key, &format!("{}/quarantine/{}", repo, expected))?;
+            log::warn!("Quarantined {} as {}", blob.key, quarantined);
+        }
+        report.mismatched.push((blob.key, actual));
+    }
+
+    Ok(report)
+}
+
+/// Moves the blob at `key` to `to`, returning where it went. If something is already
+/// there, the first copy is kept; later ones add nothing.
+pub(crate) fn move_aside(blobs: &dyn BlobStore, key: &str, to: &str) -> Result<String> {
+    if ! blobs.exists(to)? {
+        if let Some(mut contents) = blobs.get(key)? {
+            blobs.put(to, &mut contents.reader)?;
+        }
+    }
+    blobs.delete(key)?;
+    Ok(to.to_string())
+}
+
+/// Scrubs every repo's tarballs, every `config.interval`, on a background thread.
+pub(crate) fn spawn(config: ScrubConfig, repos: Vec<String>, blobs: Arc<dyn BlobStore>, metrics: Arc<Metrics>) {
+    let scrubber = move || loop {
+        std::thread::sleep(config.interval);
+        for repo in repos.iter() {
+            match scrub_repo(blobs.as_ref(), repo, config.quarantine) {
+                Ok(report) => {
+                    log::info!("Scrubbed {} tarballs in {}: {} corrupt", report.checked, repo, report.mismatched.len());
+                    metrics.add("rotterdam_blob_scrubbed_total", labels(&[("repo", repo)]), report.checked);
+                    metrics.add("rotterdam_blob_checksum_failures_total", labels(&[("repo", repo)]), report.mismatched.len() as u64);
+                }
+                Err(e) => log::error!("Unable to scrub tarballs in {}: {}", repo, e),
+            }
+        }
+    };
+
+    std::thread::Builder::new()
+        .name(String::from("blob-scrubber"))
+        .spawn(scrubber)
+        .expect("Unable to start blob scrubber thread");
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_and_quarantines_corrupt_tarballs() {
+        let registry = crate::app::test::TestRegistry::new();
+        let blobs = &registry.blobs;
+
+        let good = blob_store::sha256_hex(&mut &b"good tarball"[..]).unwrap();
+        let bad = blob_store::sha256_hex(&mut &b"bad tarball"[..]).unwrap();
+        blobs.put(&blob_store::tarball_key("repo", &good), &mut &b"good tarball"[..]).unwrap();
+        blobs.put(&blob_store::tarball_key("repo", &bad), &mut &b"bad tarbalL"[..]).unwrap();
+
+        let report = scrub_repo(blobs, "repo", false).unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.mismatched.len(), 1);
+        assert_eq!(report.mismatched[0].0, blob_store::tarball_key("repo", &bad));
+        assert!(blobs.exists(&blob_store::tarball_key("repo", &bad)).unwrap());
+
+        let report = scrub_repo(blobs, "repo", true).unwrap();
+        assert_eq!(report.mismatched.len(), 1);
+        assert!(!blobs.exists(&blob_store::tarball_key("repo", &bad)).unwrap());
+        assert!(blobs.exists(&format!("repo/quarantine/{}", bad)).unwrap());
+
+        let report = scrub_repo(blobs, "repo", true).unwrap();
+        assert_eq!((report.checked, report.mismatched.len()), (1, 0));
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, mpsc::Receiver};
+
+use anyhow::{Context, Result};
+use git2::{ObjectType, Repository, TreeWalkMode, TreeWalkResult};
+use json::JsonValue;
+
+use crate::config::{AppConfig, AppGitConfig};
+use crate::index::{self, IndexEntry};
+use crate::index_writer::IndexWriters;
+use crate::names;
+
+
+/// Most results `cargo search` can ask for at once, as on crates.io.
+const MAX_PER_PAGE: usize = 100;
+
+/// What the index doesn't say about a repo's crates, kept beside its checkout for search: the
+/// description and keywords of each crate's newest version, by normalized name.
+pub(crate) fn metadata_path(git: &AppGitConfig, repo: &str) -> PathBuf {
+    git.path.join(format!("{}.crates.json", repo))
+}
+
+fn load_metadata(path: &Path) -> Result<JsonValue> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => json::parse(&contents).with_context(|| format!("{} is not valid JSON", path.to_string_lossy())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(JsonValue::new_object()),
+        Err(e) => Err(e).with_context(|| format!("Reading {}", path.to_string_lossy())),
+    }
+}
+
+/// Records the description and keywords in `manifest`, unless a newer version than `vers` has
+/// already recorded its own. Call this on the repo's index writer, so publishes don't race.
+pub(crate) fn record_metadata(path: &Path, name: &str, vers: &str, manifest: &toml::Value) -> Result<()> {
+    let mut metadata = load_metadata(path)?;
+    let key = names::normalize(name);
+    if metadata[key.as_str()]["vers"].as_str().is_some_and(|recorded| index::cmp_versions(recorded, vers).is_gt()) {
+        return Ok(());
+    }
+
+    let package = manifest.get("package");
+    let keywords: Vec<&str> = package.and_then(|p| p.get("keywords")).and_then(|k| k.as_array())
+        .map(|k| k.iter().filter_map(|k| k.as_str()).collect())
+        .unwrap_or_default();
+    metadata[key.as_str()] = json::object! {
+        vers: vers,
+        description: package.and_then(|p| p.get("description")).and_then(|d| d.as_str()),
+        keywords: keywords,
+    };
+
+    // Written aside and renamed into place, so searches never see half a file
+    let partial = path.with_extension("json.part");
+    std::fs::write(&partial, metadata.dump()).with_context(|| format!("Writing {}", partial.to_string_lossy()))?;
+    std::fs::rename(&partial, path).with_context(|| format!("Replacing {}", path.to_string_lossy()))
+}
+
+/// A crate as search results show it.
+#[derive(Clone, Debug)]
+struct Crate {
+    name: String,
+    /// The newest version that isn't yanked.
+    max_version: String,
+    description: Option<String>,
+    keywords: Vec<String>,
+}
+
+/// One repo's crates, as of the commit `head`, by normalized name.
+#[derive(Default)]
+struct Crates {
+    head: Option<git2::Oid>,
+    crates: BTreeMap<String, Crate>,
+}
+
+/// The crates in every repo, kept up to date as their indexes change.
+#[derive(Default)]
+pub(crate) struct Search(BTreeMap<String, Arc<RwLock<Crates>>>);
+
+impl Search {
+    /// Up to `per_page` of `repo`'s crates matching `query`, in crates.io's format, or `None`
+    /// for a repo there isn't. Every word of the query has to appear in the crate's name,
+    /// description or keywords; exact name matches come first, then crates matching more words
+    /// by name, then by keyword. An empty query lists every crate.
+    pub(crate) fn query(&self, repo: &str, query: &str, per_page: usize) -> Option<JsonValue> {
+        let crates = self.0.get(repo)?.read().unwrap_or_else(|p| p.into_inner());
+        let query = query.to_lowercase();
+        // Names and keywords are compared normalized; descriptions are prose, so they're
+        // searched for each word as typed
+        let words: Vec<(&str, String)> = query.split_whitespace().map(|w| (w, names::normalize(w))).collect();
+        let exact = names::normalize(query.trim());
+
+        let mut matches: Vec<(bool, usize, usize, &Crate)> = Vec::new();
+        for (normalized, krate) in crates.crates.iter() {
+            let description = krate.description.as_deref().unwrap_or_default().to_lowercase();
+            let keywords: Vec<String> = krate.keywords.iter().map(|k| names::normalize(k)).collect();
+            let (mut by_name, mut by_keyword) = (0, 0);
+            let mut all_match = true;
+            for (typed, word) in words.iter() {
+                let in_name = normalized.contains(word.as_str());
+                let in_keywords = keywords.contains(word);
+                let in_description = description.contains(typed);
+                by_name += in_name as usize;
+                by_keyword += in_keywords as usize;
+                all_match &= in_name || in_keywords || in_description;
+            }
+            if all_match {
+                matches.push((*normalized == exact, by_name, by_keyword, krate));
+            }
+        }
+        matches.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.cmp(&a.1)).then(b.2.cmp(&a.2)).then_with(|| a.3.name.cmp(&b.3.name)));
+
+        let total = matches.len();
+        let results: Vec<JsonValue> = matches.into_iter().take(per_page.clamp(1, MAX_PER_PAGE)).map(|(_, _, _, krate)| json::object! {
+            name: krate.name.as_str(),
+            max_version: krate.max_version.as_str(),
+            description: krate.description.as_deref(),
+        }).collect();
+        Some(json::object! { crates: results, meta: { total: total } })
+    }
+}
+
+/// Starts a thread per repo that reads its crates from the index, then keeps them up to date
+/// after every change the repo's writer makes: publishes, yanks, and anything else.
+pub(crate) fn start(config: &AppConfig, writers: &IndexWriters) -> Search {
+    let mut search = Search::default();
+    for (repo, writer) in writers.iter() {
+        let crates = Arc::new(RwLock::new(Crates::default()));
+        search.0.insert(repo.clone(), crates.clone());
+
+        let changes = writer.subscribe();
+        let (index_root, metadata) = (config.git.path.join(repo), metadata_path(&config.git, repo));
+        let name = repo.clone();
+        std::thread::Builder::new()
+            .name(format!("index-search-{}", repo))
+            .spawn(move || search_loop(&index_root, &metadata, &name, &crates, changes))
+            .expect("Unable to start index search thread");
+    }
+    search
+}
+
+fn search_loop(index_root: &Path, metadata: &Path, repo: &str, crates: &RwLock<Crates>, changes: Receiver<()>) {
+    loop {
+        // Changes that arrived while we were busy are covered by this refresh
+        while changes.try_recv().is_ok() {}
+        if let Err(e) = refresh(index_root, metadata, crates) {
+            log::warn!("Unable to update search for {}: {:#}", repo, e);
+        }
+        if changes.recv().is_err() {
+            return; // The writer has gone away
+        }
+    }
+}
+
+/// Brings `crates` up to date with the index's `master`, rereading only the crates that have
+/// changed since the commit it was last updated to, when that commit's still around.
+fn refresh(index_root: &Path, metadata: &Path, crates: &RwLock<Crates>) -> Result<()> {
+    let repo = Repository::open(index_root).context("Opening index")?;
+    let head = match repo.find_reference("refs/heads/master") {
+        Ok(master) => master.peel_to_commit()?,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let last = crates.read().unwrap_or_else(|p| p.into_inner()).head;
+    if last == Some(head.id()) {
+        return Ok(());
+    }
+
+    let tree = head.tree()?;
+    let old_tree = last.and_then(|last| repo.find_commit(last).and_then(|c| c.tree()).ok());
+    let changed: Option<Vec<PathBuf>> = match &old_tree {
+        Some(old_tree) => {
+            let diff = repo.diff_tree_to_tree(Some(old_tree), Some(&tree), None)?;
+            Some(diff.deltas().filter_map(|d| d.new_file().path().or_else(|| d.old_file().path()).map(Path::to_path_buf)).collect())
+        }
+        None => None,
+    };
+    let paths = match changed {
+        Some(paths) => paths,
+        None => {
+            let mut paths = Vec::new();
+            tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+                if entry.kind() == Some(ObjectType::Blob) && dir.contains('/') {
+                    paths.push(Path::new(dir).join(entry.name().unwrap_or_default()));
+                }
+                TreeWalkResult::Ok
+            })?;
+            paths
+        }
+    };
+
+    let metadata = load_metadata(metadata)?;
+    let mut updated = Vec::new();
+    for path in paths.iter().filter(|p| p.components().count() > 1) {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let entries = match tree.get_path(path) {
+            Ok(entry) => {
+                let blob = entry.to_object(&repo)?.peel_to_blob()?;
+                String::from_utf8_lossy(blob.content()).lines()
+                    .filter(|l| ! l.trim().is_empty())
+                    .map(IndexEntry::parse)
+                    .collect::<Result<Vec<_>>>()
+                    .with_context(|| format!("Reading {}", path.to_string_lossy()))?
+            }
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let key = names::normalize(&file_name);
+        let max_version = entries.iter().filter(|e| ! e.yanked).map(|e| e.vers.as_str()).max_by(|a, b| index::cmp_versions(a, b));
+        let krate = max_version.map(|max_version| Crate {
+            name: entries[0].name.clone(),
+            max_version: max_version.to_string(),
+            description: metadata[key.as_str()]["description"].as_str().map(str::to_string),
+            keywords: metadata[key.as_str()]["keywords"].members().filter_map(|k| k.as_str()).map(str::to_string).collect(),
+        });
+        updated.push((key, krate));
+    }
+
+    let mut crates = crates.write().unwrap_or_else(|p| p.into_inner());
+    if old_tree.is_none() {
+        crates.crates.clear();
+    }
+    for (key, krate) in updated {
+        match krate {
+            Some(krate) => crates.crates.insert(key, krate),
+            None => crates.crates.remove(&key),
+        };
+    }
+    crates.head = Some(head.id());
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::index_writer::Edit;
+
+    #[test]
+    fn searches_what_the_index_holds() {
+        let registry = crate::app::test::TestRegistry::new();
+        let writer = registry.writer();
+        let index_root = registry.index_root();
+        let metadata = metadata_path(&registry.config.git, "repo");
+        let crates = Arc::new(RwLock::new(Crates::default()));
+        let search = Search(vec![(String::from("repo"), crates.clone())].into_iter().collect());
+
+        let publish = |name: &str, vers: &str, yanked: bool, manifest: &str| {
+            let line = format!(r#"{{"name":"{}","vers":"{}","deps":[],"cksum":"{}","features":{{}},"yanked":{}}}"#, name, vers, "0".repeat(64), yanked);
+            let path = index::index_path(name);
+            let (metadata_file, name, vers, manifest) = (metadata.clone(), name.to_string(), vers.to_string(), manifest.parse().unwrap());
+            writer.mutate("Publish", Box::new(move |index_root: &Path| {
+                record_metadata(&metadata_file, &name, &vers, &manifest)?;
+                let mut contents = std::fs::read(index_root.join(&path)).unwrap_or_default();
+                contents.extend_from_slice(format!("{}\n", line).as_bytes());
+                Ok(vec![Edit { path, contents: Some(contents) }])
+            })).unwrap();
+            refresh(&index_root, &metadata, &crates).unwrap();
+        };
+        let found = |query: &str| -> Vec<String> {
+            search.query("repo", query, 10).unwrap()["crates"].members().map(|c| format!("{} {}", c["name"], c["max_version"])).collect()
+        };
+
+        publish("serde", "1.0.0", false, "[package]\ndescription = \"A serialization framework\"\nkeywords = [\"encoding\"]");
+        publish("serde_json", "1.0.0", false, "[package]\ndescription = \"A JSON serialization file format\"\nkeywords = [\"json\", \"serde\"]");
+        publish("json-tools", "0.1.0", false, "[package]\ndescription = \"Tools for serde_json\"");
+        publish("Tokio", "1.0.0", false, "[package]");
+
+        assert_eq!(found("serde"), ["serde 1.0.0", "serde_json 1.0.0", "json-tools 0.1.0"]);
+        assert_eq!(found("json"), ["serde_json 1.0.0", "json-tools 0.1.0"]);
+        assert_eq!(found("serde-json"), ["serde_json 1.0.0"]);
+        assert_eq!(found("serialization format"), ["serde_json 1.0.0"]);
+        assert_eq!(found("ENCODING"), ["serde 1.0.0"]);
+        assert_eq!(found("tokio"), ["Tokio 1.0.0"]);
+        assert_eq!(found("").len(), 4);
+        assert!(found("nothing").is_empty());
+        let results = search.query("repo", "", 2).unwrap();
+        assert_eq!((results["crates"].len(), results["meta"]["total"].as_usize()), (2, Some(4)));
+        assert!(search.query("other", "serde", 10).is_none());
+
+        // Newer versions are searched by their own metadata, and yanked ones drop out
+        publish("serde", "1.1.0", false, "[package]\ndescription = \"Now with YAML\"");
+        publish("serde", "1.0.1", false, "[package]\ndescription = \"A backport\"");
+        assert_eq!(found("yaml"), ["serde 1.1.0"]);
+        publish("Tokio", "2.0.0", true, "[package]");
+        assert_eq!(found("tokio"), ["Tokio 1.0.0"]);
+        let contents = format!(r#"{{"name":"Tokio","vers":"1.0.0","deps":[],"cksum":"{}","features":{{}},"yanked":true}}"#, "0".repeat(64));
+        writer.mutate("Yank", Box::new(move |_: &Path| Ok(vec![Edit { path: index::index_path("Tokio"), contents: Some(contents.into_bytes()) }]))).unwrap();
+        refresh(&index_root, &metadata, &crates).unwrap();
+        assert!(found("tokio").is_empty());
+
+        // Starting afresh reads the same crates back
+        *crates.write().unwrap() = Crates::default();
+        refresh(&index_root, &metadata, &crates).unwrap();
+        assert_eq!(found("").len(), 3);
+    }
+}
@@ -0,0 +1,58 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+/// A UTC date and time of day, to the second.
+pub(crate) struct CivilTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u64,
+    pub minute: u64,
+    pub second: u64,
+}
+
+/// `t` as a UTC date and time; times before the epoch come out as the epoch.
+pub(crate) fn civil_time(t: SystemTime) -> CivilTime {
+    let secs = t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    CivilTime { year, month, day, hour: rem / 3600, minute: rem % 3600 / 60, second: rem % 60 }
+}
+
+/// RFC 3339 (`2013-05-24T00:00:00Z`).
+pub(crate) fn rfc3339_time(t: SystemTime) -> String {
+    let c = civil_time(t);
+    format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}Z", c.year, c.month, c.day, c.hour, c.minute, c.second)
+}
+
+/// ISO 8601 basic format (`20130524T000000Z`), as AWS request signing wants it.
+pub(crate) fn compact_utc_time(t: SystemTime) -> String {
+    let c = civil_time(t);
+    format!("{}{:02}{:02}T{:02}{:02}{:02}Z", c.year, c.month, c.day, c.hour, c.minute, c.second)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn formats_utc_times() {
+        let t = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!(rfc3339_time(t), "2000-10-10T13:55:36Z");
+        assert_eq!(compact_utc_time(t), "20001010T135536Z");
+        assert_eq!(rfc3339_time(UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29T00:00:00Z");
+    }
+}
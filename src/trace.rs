@@ -0,0 +1,392 @@
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use smtr::{Header, Request};
+
+
+/// What we know about the request currently being handled on this thread.
+struct RequestContext {
+    request_id: String,
+    trace_id: u128,
+    sampled: bool,
+    root: OpenSpan,
+    spans: Vec<OpenSpan>,
+}
+
+struct OpenSpan {
+    name: &'static str,
+    span_id: u64,
+    parent_span_id: Option<u64>,
+    start: SystemTime,
+    started: Instant,
+}
+
+impl OpenSpan {
+    fn new(name: &'static str, parent_span_id: Option<u64>) -> Self {
+        OpenSpan {
+            name,
+            span_id: random_nonzero_u64(),
+            parent_span_id,
+            start: SystemTime::now(),
+            started: Instant::now(),
+        }
+    }
+
+    fn finish(self, ctx: &RequestContext, kind: SpanKind, attributes: Vec<(&'static str, String)>) {
+        if !ctx.sampled {
+            return;
+        }
+        if let Some(exporter) = EXPORTER.get() {
+            exporter.export(FinishedSpan {
+                trace_id: ctx.trace_id,
+                span_id: self.span_id,
+                parent_span_id: self.parent_span_id,
+                name: self.name.to_string(),
+                kind,
+                start: self.start,
+                end: self.start + self.started.elapsed(),
+                attributes,
+            });
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SpanKind {
+    Internal,
+    Server,
+}
+
+/// A completed span, ready to be sent to a collector.
+#[derive(Clone, Debug)]
+pub(crate) struct FinishedSpan {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub parent_span_id: Option<u64>,
+    pub name: String,
+    pub kind: SpanKind,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, String)>,
+}
+
+/// Somewhere to send finished spans.
+pub(crate) trait SpanExporter: Send + Sync {
+    fn export(&self, span: FinishedSpan);
+}
+
+static EXPORTER: OnceLock<Box<dyn SpanExporter>> = OnceLock::new();
+
+/// Sends finished spans to `exporter` from now on. Only the first exporter set is used.
+pub(crate) fn set_exporter(exporter: Box<dyn SpanExporter>) {
+    if EXPORTER.set(exporter).is_err() {
+        log::warn!("Span exporter already configured; ignoring another");
+    }
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Option<RequestContext>> = const { RefCell::new(None) };
+}
+
+
+/// A W3C `traceparent` header: `00-<trace id>-<parent span id>-<flags>`.
+#[derive(Debug, PartialEq, Eq)]
+struct TraceParent {
+    trace_id: u128,
+    parent_span_id: u64,
+    sampled: bool,
+}
+
+fn parse_traceparent(value: &str) -> Option<TraceParent> {
+    let parts: Vec<_> = value.trim().split('-').collect();
+    let (version, trace_id, parent_id, flags) = match parts.as_slice() {
+        [v, t, p, f, ..] => (*v, *t, *p, *f),
+        _ => return None,
+    };
+    let well_formed = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+    if !well_formed(version, 2) || version == "ff" || (version == "00" && parts.len() != 4) {
+        return None;
+    }
+    if !well_formed(trace_id, 32) || !well_formed(parent_id, 16) || !well_formed(flags, 2) {
+        return None;
+    }
+
+    let trace_id = u128::from_str_radix(trace_id, 16).ok().filter(|id| *id != 0)?;
+    let parent_span_id = u64::from_str_radix(parent_id, 16).ok().filter(|id| *id != 0)?;
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+
+    Some(TraceParent { trace_id, parent_span_id, sampled: flags & 1 == 1 })
+}
+
+
+/// Picks the id for a request: the caller's `X-Request-Id` if it looks sane, or a fresh one.
+pub(crate) fn request_id(req: &dyn Request) -> String {
+    let incoming = req.headers().get(Header::XRequestId)
+        .and_then(|id| std::str::from_utf8(id).ok())
+        .filter(|id| is_valid_request_id(id));
+
+    match incoming {
+        Some(id) => id.to_string(),
+        None => format!("{:016x}{:016x}", random_u64(), random_u64()),
+    }
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+fn random_nonzero_u64() -> u64 {
+    loop {
+        let r = random_u64();
+        if r != 0 {
+            return r;
+        }
+    }
+}
+
+pub(crate) fn random_u64() -> u64 {
+    // RandomState is seeded from the OS, and each instance gets fresh keys
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0));
+    hasher.finish()
+}
+
+
+/// Marks this thread as handling `req` until the guard is dropped. The request gets a
+/// root span, continuing the caller's trace if they sent a `traceparent` header.
+pub(crate) fn begin_request(req: &dyn Request, request_id: &str) -> RequestGuard {
+    let parent = req.headers().get(Header::TraceParent)
+        .and_then(|tp| std::str::from_utf8(tp).ok())
+        .and_then(parse_traceparent);
+
+    let (trace_id, parent_span_id, sampled) = match parent {
+        Some(tp) => (tp.trace_id, Some(tp.parent_span_id), tp.sampled),
+        None => ((random_nonzero_u64() as u128) << 64 | random_u64() as u128, None, true),
+    };
+
+    CONTEXT.with(|c| {
+        *c.borrow_mut() = Some(RequestContext {
+            request_id: request_id.to_string(),
+            trace_id,
+            sampled,
+            root: OpenSpan::new("request", parent_span_id),
+            spans: Vec::new(),
+        });
+    });
+
+    RequestGuard {
+        kind: SpanKind::Server,
+        attributes: vec![
+            ("http.method", req.method().as_str().to_string()),
+            ("http.target", req.path().to_string()),
+            ("rotterdam.request_id", request_id.to_string()),
+        ],
+    }
+}
+
+pub(crate) struct RequestGuard {
+    kind: SpanKind,
+    attributes: Vec<(&'static str, String)>,
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        if let Some(mut ctx) = CONTEXT.with(|c| c.borrow_mut().take()) {
+            // Any spans still open (e.g. after an early return) end with the request
+            while let Some(span) = ctx.spans.pop() {
+                span.finish(&ctx, SpanKind::Internal, Vec::new());
+            }
+            let root = std::mem::replace(&mut ctx.root, OpenSpan::new("request", None));
+            root.finish(&ctx, self.kind, std::mem::take(&mut self.attributes));
+        }
+    }
+}
+
+
+/// Enough of the request being handled on one thread to carry on with it on another.
+#[derive(Clone, Debug)]
+pub(crate) struct Handoff {
+    request_id: String,
+    trace_id: u128,
+    sampled: bool,
+    parent_span_id: u64,
+}
+
+#[cfg(test)]
+impl Handoff {
+    pub(crate) fn new(request_id: &str) -> Self {
+        Handoff { request_id: request_id.to_string(), trace_id: 1, sampled: false, parent_span_id: 1 }
+    }
+}
+
+/// The request (and span within it) this thread is working on, if any, for `resume` on
+/// whichever thread does the rest of the work.
+pub(crate) fn handoff() -> Option<Handoff> {
+    CONTEXT.with(|c| {
+        c.borrow().as_ref().map(|ctx| Handoff {
+            request_id: ctx.request_id.clone(),
+            trace_id: ctx.trace_id,
+            sampled: ctx.sampled,
+            parent_span_id: ctx.spans.last().unwrap_or(&ctx.root).span_id,
+        })
+    })
+}
+
+/// Marks this thread as working on `handoff`'s request until the guard is dropped. Log lines
+/// carry its id, and the work is exported as a `name` span under the one that handed it over.
+/// Without a handoff, nothing changes.
+pub(crate) fn resume(handoff: Option<&Handoff>, name: &'static str) -> Option<RequestGuard> {
+    let handoff = handoff?;
+    CONTEXT.with(|c| {
+        *c.borrow_mut() = Some(RequestContext {
+            request_id: handoff.request_id.clone(),
+            trace_id: handoff.trace_id,
+            sampled: handoff.sampled,
+            root: OpenSpan::new(name, Some(handoff.parent_span_id)),
+            spans: Vec::new(),
+        });
+    });
+    Some(RequestGuard { kind: SpanKind::Internal, attributes: Vec::new() })
+}
+
+
+/// Enters a named phase of request handling (parse, auth, git, storage...). Log lines
+/// emitted while the guard is alive are tagged with the phase, its duration is logged
+/// when it ends, and it's exported as a child of whichever span was current.
+pub(crate) fn span(name: &'static str) -> Span {
+    CONTEXT.with(|c| {
+        if let Some(ctx) = c.borrow_mut().as_mut() {
+            let parent = ctx.spans.last().unwrap_or(&ctx.root).span_id;
+            ctx.spans.push(OpenSpan::new(name, Some(parent)));
+        }
+    });
+    Span { name, started: Instant::now() }
+}
+
+pub(crate) struct Span {
+    name: &'static str,
+    started: Instant,
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        log::debug!("{} took {:?}", self.name, self.started.elapsed());
+        CONTEXT.with(|c| {
+            if let Some(ctx) = c.borrow_mut().as_mut() {
+                if let Some(pos) = ctx.spans.iter().rposition(|s| s.name == self.name) {
+                    let mut ended = ctx.spans.split_off(pos);
+                    while let Some(span) = ended.pop() {
+                        span.finish(ctx, SpanKind::Internal, Vec::new());
+                    }
+                }
+            }
+        });
+    }
+}
+
+
+/// What log lines on this thread start with: the current request id and span, if any.
+pub(crate) fn log_prefix() -> Option<String> {
+    CONTEXT.with(|c| {
+        c.borrow().as_ref().map(|ctx| {
+            if ctx.spans.is_empty() {
+                format!("[{}]", ctx.request_id)
+            } else {
+                let names: Vec<_> = ctx.spans.iter().map(|s| s.name).collect();
+                format!("[{} {}]", ctx.request_id, names.join("/"))
+            }
+        })
+    })
+}
+
+/// Wraps another logger, prefixing each line with the current request id and span.
+struct ContextLogger {
+    inner: Box<dyn log::Log>,
+}
+
+impl log::Log for ContextLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        match log_prefix() {
+            None => self.inner.log(record),
+            Some(prefix) => self.inner.log(
+                &log::Record::builder()
+                    .args(format_args!("{} {}", prefix, record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// Sets up logging as `pretty_env_logger::init_timed` would, but with request context
+/// attached to each line.
+pub fn init_logging() {
+    let mut builder = pretty_env_logger::formatted_timed_builder();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    let inner = builder.build();
+    let max_level = inner.filter();
+
+    if log::set_boxed_logger(Box::new(ContextLogger { inner: Box::new(inner) })).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validates_incoming_request_ids() {
+        assert!(is_valid_request_id("4bf92f3577b34da6a3ce929d0e0e4736"));
+        assert!(is_valid_request_id("req-1234_abc.def:1"));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("with space"));
+        assert!(!is_valid_request_id("line\nbreak"));
+        assert!(!is_valid_request_id(&"a".repeat(129)));
+    }
+
+    #[test]
+    fn parses_traceparent() {
+        assert_eq!(
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            Some(TraceParent {
+                trace_id: 0x4bf92f3577b34da6a3ce929d0e0e4736,
+                parent_span_id: 0x00f067aa0ba902b7,
+                sampled: true,
+            })
+        );
+        assert_eq!(
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00").map(|tp| tp.sampled),
+            Some(false)
+        );
+        // All-zero ids, uppercase hex and the reserved version are all invalid
+        assert_eq!(parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01"), None);
+        assert_eq!(parse_traceparent("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01"), None);
+        assert_eq!(parse_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"), None);
+        assert_eq!(parse_traceparent("garbage"), None);
+    }
+
+    #[test]
+    fn random_ids_differ() {
+        assert_ne!(random_u64(), random_u64());
+    }
+}
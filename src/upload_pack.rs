@@ -0,0 +1,617 @@
+use std::io::Read;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use git2::{ObjectType, Oid, Repository};
+use smtr::{
+    server::{ConnectionResponseWriter, Response},
+    Header, Method, Request,
+};
+
+use crate::config::AppConfig;
+use crate::metrics::{labels, Metrics};
+use crate::publish::UnreadableBody;
+
+
+const AGENT: &str = concat!("agent=rotterdam/", env!("CARGO_PKG_VERSION"));
+
+/// What we support of the original protocol. No shallow clones, and no thin packs (asking
+/// for one is fine; you just get a full pack).
+const V0_CAPABILITIES: &str = "multi_ack_detailed no-done side-band-64k ofs-delta no-progress";
+
+/// Negotiation requests are a few KB at most; anything near this is not a git client.
+const MAX_REQUEST: u64 = 16 << 20;
+
+/// Payload sizes for `side-band-64k` and `side-band`.
+const LARGE_BAND: usize = 65515;
+const SMALL_BAND: usize = 995;
+
+/// Something the client asked for that we can't or won't do. Sent back as an `ERR` packet.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct ClientError(String);
+
+/// Whether `rest` (the path after `/repo/<repo>/index/`) is an upload-pack request we serve
+/// ourselves. Everything else, including the dumb protocol, goes to `git http-backend`.
+pub(crate) fn handles(req: &dyn Request, rest: &[&str]) -> bool {
+    match (req.method(), rest) {
+        (Method::Get, ["info", "refs"]) => req.query_first_value("service").is_some_and(|s| s == "git-upload-pack"),
+        (Method::Post, ["git-upload-pack"]) => true,
+        _ => false,
+    }
+}
+
+pub(crate) fn handle(config: &AppConfig, metrics: &Metrics, repo_name: &str, req: &mut dyn Request, mut resp: ConnectionResponseWriter) -> Result<()> {
+    if ! config.repos.contains_key(repo_name) {
+        log::debug!("Repo not found: {}", repo_name);
+        resp.send_response(Response::err(404))?;
+        return Ok(());
+    }
+    let repo = Repository::open(config.git.path.join(repo_name)).context("Opening index")?;
+
+    let v2 = req.headers().get(Header::GitProtocol)
+        .is_some_and(|p| String::from_utf8_lossy(p).split(':').any(|p| p == "version=2"));
+
+    let started = Instant::now();
+    let (command, content_type, result) = match req.method() {
+        Method::Get => ("advertise", "application/x-git-upload-pack-advertisement", advertise(&repo, v2)),
+        _ => {
+            let request = match read_request(req) {
+                Ok(request) => request,
+                Err(e) => {
+                    log::debug!("Unreadable upload-pack request: {}", e);
+                    resp.send_response(Response::err(e.status()))?;
+                    return Ok(());
+                }
+            };
+            let (command, result) = if v2 { serve_v2(&repo, &request) } else { ("fetch", serve_v0(&repo, &request)) };
+            (command, "application/x-git-upload-pack-result", result)
+        }
+    };
+
+    let body = match result {
+        Ok(body) => body,
+        Err(e) => match e.downcast::<ClientError>() {
+            Ok(e) => {
+                log::debug!("Refusing upload-pack request for {}: {}", repo_name, e);
+                let mut body = Vec::new();
+                pkt_line(&mut body, &format!("ERR {}\n", e));
+                body
+            }
+            Err(e) => {
+                resp.send_response(Response::err(500))?;
+                return Err(e).context("Serving upload-pack");
+            }
+        },
+    };
+    metrics.inc("rotterdam_upload_pack_requests_total", labels(&[("repo", repo_name), ("command", command)]));
+    metrics.observe("rotterdam_upload_pack_duration_seconds", labels(&[("repo", repo_name)]), started.elapsed());
+
+    let r = Response::builder(200)
+        .content_type(content_type)
+        .header(Header::CacheControl, &b"no-cache"[..])
+        .body(body)
+        .build();
+    resp.send_response(r)?;
+    Ok(())
+}
+
+fn read_request(req: &mut dyn Request) -> Result<Vec<u8>, UnreadableBody> {
+    let mut request = Vec::new();
+    if let Some(body) = req.take_body() {
+        let body = body.take(MAX_REQUEST);
+        // git itself compresses larger negotiation requests
+        if req.headers().get(Header::ContentEncoding).is_some_and(|e| e == b"gzip" || e == b"x-gzip") {
+            flate2::read::GzDecoder::new(body).read_to_end(&mut request)?;
+        } else {
+            let mut body = body;
+            body.read_to_end(&mut request)?;
+        }
+    }
+    Ok(request)
+}
+
+
+/// A `pkt-line` from the client, with any trailing newline removed.
+#[derive(Debug, PartialEq, Eq)]
+enum Pkt<'a> {
+    Line(&'a str),
+    Flush,
+    Delim,
+}
+
+struct PktReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for PktReader<'a> {
+    type Item = Result<Pkt<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let len = match self.buf.get(..4).and_then(|l| std::str::from_utf8(l).ok()).and_then(|l| usize::from_str_radix(l, 16).ok()) {
+            Some(len) => len,
+            None => return Some(Err(ClientError(String::from("Malformed pkt-line length")).into())),
+        };
+        let pkt = match len {
+            0 => Pkt::Flush,
+            1 => Pkt::Delim,
+            2 | 3 => return Some(Err(ClientError(format!("Unexpected special packet {:04x}", len)).into())),
+            len if len > self.buf.len() => return Some(Err(ClientError(String::from("Truncated pkt-line")).into())),
+            len => match std::str::from_utf8(&self.buf[4..len]) {
+                Ok(line) => Pkt::Line(line.strip_suffix('\n').unwrap_or(line)),
+                Err(_) => return Some(Err(ClientError(String::from("Request lines must be UTF-8")).into())),
+            },
+        };
+        self.buf = &self.buf[len.max(4)..];
+        Some(Ok(pkt))
+    }
+}
+
+fn pkt_line(out: &mut Vec<u8>, line: &str) {
+    pkt_bytes(out, line.as_bytes());
+}
+
+fn pkt_bytes(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(format!("{:04x}", data.len() + 4).as_bytes());
+    out.extend_from_slice(data);
+}
+
+fn flush(out: &mut Vec<u8>) {
+    out.extend_from_slice(b"0000");
+}
+
+fn delim(out: &mut Vec<u8>) {
+    out.extend_from_slice(b"0001");
+}
+
+
+struct Ref {
+    name: String,
+    oid: Oid,
+    /// What an annotated tag points at.
+    peeled: Option<Oid>,
+}
+
+struct Refs {
+    /// Where `HEAD` points, if it's symbolic.
+    head_target: Option<String>,
+    /// `HEAD` first (unless it's unborn), then everything under `refs/` by name.
+    refs: Vec<Ref>,
+}
+
+impl Refs {
+    fn read(repo: &Repository) -> Result<Self> {
+        let head = repo.find_reference("HEAD")?;
+        let head_target = head.symbolic_target().map(String::from);
+
+        let mut refs = Vec::new();
+        if let Ok(oid) = head.resolve().map(|r| r.target()) {
+            refs.extend(oid.map(|oid| Ref { name: String::from("HEAD"), oid, peeled: None }));
+        }
+        let mut named = Vec::new();
+        for reference in repo.references()? {
+            let reference = reference?;
+            let (name, oid) = match (reference.name(), reference.target()) {
+                (Some(name), Some(oid)) if name.starts_with("refs/") => (name.to_string(), oid),
+                _ => continue,
+            };
+            let peeled = reference.peel(ObjectType::Any).ok().map(|o| o.id()).filter(|p| *p != oid);
+            named.push(Ref { name, oid, peeled });
+        }
+        named.sort_by(|a, b| a.name.cmp(&b.name));
+        refs.extend(named);
+
+        Ok(Refs { head_target, refs })
+    }
+
+    /// Clients may only ask for what we advertised.
+    fn check_wants(&self, wants: &[Oid]) -> Result<()> {
+        for want in wants {
+            if ! self.refs.iter().any(|r| r.oid == *want || r.peeled == Some(*want)) {
+                return Err(ClientError(format!("upload-pack: not our ref {}", want)).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn advertise(repo: &Repository, v2: bool) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    if v2 {
+        for line in ["version 2", AGENT, "ls-refs=unborn", "fetch", "object-format=sha1"] {
+            pkt_line(&mut out, &format!("{}\n", line));
+        }
+        flush(&mut out);
+        return Ok(out);
+    }
+
+    pkt_line(&mut out, "# service=git-upload-pack\n");
+    flush(&mut out);
+
+    let refs = Refs::read(repo)?;
+    let mut capabilities = format!("{} {}", V0_CAPABILITIES, AGENT);
+    if let Some(target) = &refs.head_target {
+        capabilities = format!("{} symref=HEAD:{}", capabilities, target);
+    }
+    let mut lines = Vec::new();
+    for r in refs.refs.iter() {
+        lines.push(format!("{} {}", r.oid, r.name));
+        if let Some(peeled) = r.peeled {
+            lines.push(format!("{} {}^{{}}", peeled, r.name));
+        }
+    }
+    if lines.is_empty() {
+        lines.push(format!("{} capabilities^{{}}", Oid::zero()));
+    }
+    for (n, line) in lines.iter().enumerate() {
+        if n == 0 {
+            pkt_line(&mut out, &format!("{}\0{}\n", line, capabilities));
+        } else {
+            pkt_line(&mut out, &format!("{}\n", line));
+        }
+    }
+    flush(&mut out);
+    Ok(out)
+}
+
+fn parse_oid(line: &str, prefix: &str) -> Result<Option<Oid>> {
+    match line.strip_prefix(prefix) {
+        Some(rest) => {
+            let hex = rest.split(' ').next().unwrap_or_default();
+            Oid::from_str(hex).map(Some).map_err(|_| ClientError(format!("Bad object id in {:?}", line)).into())
+        }
+        None => Ok(None),
+    }
+}
+
+/// Stateless (HTTP) protocol v0/v1: wants, a flush, then haves ending in `done` or a flush.
+fn serve_v0(repo: &Repository, request: &[u8]) -> Result<Vec<u8>> {
+    let mut wants = Vec::new();
+    let mut haves = Vec::new();
+    let mut capabilities: Vec<String> = Vec::new();
+    let mut done = false;
+    let mut in_wants = true;
+    for pkt in (PktReader { buf: request }) {
+        match pkt? {
+            Pkt::Line(line) if in_wants => {
+                if let Some(want) = parse_oid(line, "want ")? {
+                    if wants.is_empty() {
+                        capabilities = line.split(' ').skip(2).map(String::from).collect();
+                    }
+                    wants.push(want);
+                } else {
+                    return Err(ClientError(format!("Unsupported request {:?}", line)).into());
+                }
+            }
+            Pkt::Line("done") => {
+                done = true;
+                break;
+            }
+            Pkt::Line(line) => match parse_oid(line, "have ")? {
+                Some(have) => haves.push(have),
+                None => return Err(ClientError(format!("Unexpected {:?} in negotiation", line)).into()),
+            },
+            Pkt::Flush => in_wants = false,
+            Pkt::Delim => return Err(ClientError(String::from("Unexpected delimiter")).into()),
+        }
+    }
+    if wants.is_empty() {
+        return Ok(Vec::new());
+    }
+    Refs::read(repo)?.check_wants(&wants)?;
+
+    let capable = |c: &str| capabilities.iter().any(|have| have == c);
+    let detailed = capable("multi_ack_detailed");
+    let band = if capable("side-band-64k") { Some(LARGE_BAND) } else if capable("side-band") { Some(SMALL_BAND) } else { None };
+
+    let mut out = Vec::new();
+    let common = common_commits(repo, &haves);
+    for (n, oid) in common.iter().enumerate() {
+        if detailed {
+            pkt_line(&mut out, &format!("ACK {} common\n", oid));
+        } else if n == 0 {
+            pkt_line(&mut out, &format!("ACK {}\n", oid));
+        }
+    }
+
+    if done {
+        match common.last() {
+            Some(last) if detailed => pkt_line(&mut out, &format!("ACK {}\n", last)),
+            Some(_) => {}
+            None => pkt_line(&mut out, "NAK\n"),
+        }
+    } else {
+        // Once anything is in common we can build a pack, so we're ready straight away
+        let ready = detailed && ! common.is_empty();
+        if ready {
+            pkt_line(&mut out, &format!("ACK {} ready\n", common[common.len() - 1]));
+        }
+        if haves.is_empty() || detailed {
+            pkt_line(&mut out, "NAK\n");
+        }
+        if ! (ready && capable("no-done")) {
+            return Ok(out); // The client sends more haves, or `done`, in another request
+        }
+        pkt_line(&mut out, &format!("ACK {}\n", common[common.len() - 1]));
+    }
+
+    write_pack(repo, &wants, &common, band, &mut out)?;
+    Ok(out)
+}
+
+/// Protocol v2: `command=<command>`, capabilities, a delimiter, then the command's arguments.
+fn serve_v2(repo: &Repository, request: &[u8]) -> (&'static str, Result<Vec<u8>>) {
+    let mut command = None;
+    let mut args = Vec::new();
+    let mut in_args = false;
+    for pkt in (PktReader { buf: request }) {
+        match pkt {
+            Ok(Pkt::Line(line)) if command.is_none() => command = Some(line.strip_prefix("command=").unwrap_or(line)),
+            Ok(Pkt::Line(line)) if in_args => args.push(line),
+            Ok(Pkt::Line(_capability)) => {}
+            Ok(Pkt::Delim) => in_args = true,
+            Ok(Pkt::Flush) => break,
+            Err(e) => return ("invalid", Err(e)),
+        }
+    }
+
+    match command {
+        None => ("empty", Ok(Vec::new())),
+        Some("ls-refs") => ("ls-refs", ls_refs(repo, &args)),
+        Some("fetch") => ("fetch", fetch_v2(repo, &args)),
+        Some(command) => ("invalid", Err(ClientError(format!("Unknown command {:?}", command)).into())),
+    }
+}
+
+fn ls_refs(repo: &Repository, args: &[&str]) -> Result<Vec<u8>> {
+    let prefixes: Vec<&str> = args.iter().filter_map(|a| a.strip_prefix("ref-prefix ")).collect();
+    let wanted = |name: &str| prefixes.is_empty() || prefixes.iter().any(|p| name.starts_with(p));
+    let (symrefs, peel, unborn) = (args.contains(&"symrefs"), args.contains(&"peel"), args.contains(&"unborn"));
+
+    let refs = Refs::read(repo)?;
+    let head_symref = match (&refs.head_target, symrefs) {
+        (Some(target), true) => format!(" symref-target:{}", target),
+        _ => String::new(),
+    };
+
+    let mut out = Vec::new();
+    if unborn && wanted("HEAD") && refs.refs.first().is_none_or(|r| r.name != "HEAD") {
+        pkt_line(&mut out, &format!("unborn HEAD{}\n", head_symref));
+    }
+    for r in refs.refs.iter().filter(|r| wanted(&r.name)) {
+        let mut line = format!("{} {}", r.oid, r.name);
+        if r.name == "HEAD" {
+            line.push_str(&head_symref);
+        }
+        if let (Some(peeled), true) = (r.peeled, peel) {
+            line.push_str(&format!(" peeled:{}", peeled));
+        }
+        line.push('\n');
+        pkt_line(&mut out, &line);
+    }
+    flush(&mut out);
+    Ok(out)
+}
+
+fn fetch_v2(repo: &Repository, args: &[&str]) -> Result<Vec<u8>> {
+    let mut wants = Vec::new();
+    let mut haves = Vec::new();
+    let mut done = false;
+    for arg in args {
+        if let Some(want) = parse_oid(arg, "want ")? {
+            wants.push(want);
+        } else if let Some(have) = parse_oid(arg, "have ")? {
+            haves.push(have);
+        } else {
+            match *arg {
+                "done" => done = true,
+                "thin-pack" | "no-progress" | "include-tag" | "ofs-delta" => {}
+                arg => return Err(ClientError(format!("Unsupported fetch argument {:?}", arg)).into()),
+            }
+        }
+    }
+    Refs::read(repo)?.check_wants(&wants)?;
+
+    let mut out = Vec::new();
+    let common = common_commits(repo, &haves);
+    if ! done {
+        pkt_line(&mut out, "acknowledgments\n");
+        if common.is_empty() {
+            pkt_line(&mut out, "NAK\n");
+        }
+        for oid in common.iter() {
+            pkt_line(&mut out, &format!("ACK {}\n", oid));
+        }
+        if common.is_empty() {
+            flush(&mut out);
+            return Ok(out);
+        }
+        pkt_line(&mut out, "ready\n");
+        delim(&mut out);
+    }
+
+    pkt_line(&mut out, "packfile\n");
+    write_pack(repo, &wants, &common, Some(LARGE_BAND), &mut out)?;
+    Ok(out)
+}
+
+/// The haves that we have too.
+fn common_commits(repo: &Repository, haves: &[Oid]) -> Vec<Oid> {
+    haves.iter().copied().filter(|oid| repo.find_commit(*oid).is_ok()).collect()
+}
+
+/// Packs everything reachable from `wants` that isn't reachable from `common`, split across
+/// pkt-lines on band 1 if the client asked for side-band.
+fn write_pack(repo: &Repository, wants: &[Oid], common: &[Oid], band: Option<usize>, out: &mut Vec<u8>) -> Result<()> {
+    let mut walk = repo.revwalk()?;
+    let mut builder = repo.packbuilder()?;
+    for want in wants {
+        if repo.find_object(*want, None)?.kind() == Some(ObjectType::Tag) {
+            builder.insert_object(*want, None)?;
+        }
+        walk.push(*want)?;
+    }
+    for oid in common {
+        walk.hide(*oid)?;
+    }
+    builder.insert_walk(&mut walk)?;
+
+    let mut pack = Vec::new();
+    builder.foreach(|chunk| {
+        pack.extend_from_slice(chunk);
+        true
+    })?;
+
+    match band {
+        Some(max) => {
+            let mut packet = Vec::with_capacity(max + 1);
+            for chunk in pack.chunks(max) {
+                packet.clear();
+                packet.push(1);
+                packet.extend_from_slice(chunk);
+                pkt_bytes(out, &packet);
+            }
+            flush(out);
+        }
+        None => out.extend_from_slice(&pack),
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use git2::Signature;
+    use std::path::Path;
+
+    fn repo_with_commits(dir: &Path, n: usize) -> (Repository, Vec<Oid>) {
+        let repo = Repository::init_opts(dir, git2::RepositoryInitOptions::new().initial_head("master")).unwrap();
+        let signature = Signature::now("t", "t@t").unwrap();
+        let mut commits = Vec::new();
+        for i in 0..n {
+            let blob = repo.blob(format!("line {}\n", i).as_bytes()).unwrap();
+            let mut tree = repo.treebuilder(None).unwrap();
+            tree.insert("file", blob, 0o100644).unwrap();
+            let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+            let parents: Vec<_> = commits.last().map(|p| repo.find_commit(*p).unwrap()).into_iter().collect();
+            let parents: Vec<_> = parents.iter().collect();
+            commits.push(repo.commit(Some("HEAD"), &signature, &signature, &i.to_string(), &tree, &parents).unwrap());
+        }
+        (repo, commits)
+    }
+
+    fn request(lines: &[&str]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for line in lines {
+            match *line {
+                "0000" => flush(&mut out),
+                "0001" => delim(&mut out),
+                line => pkt_line(&mut out, &format!("{}\n", line)),
+            }
+        }
+        out
+    }
+
+    /// The lines before the pack, and the pack itself (from band 1).
+    fn split_response(response: &[u8]) -> (Vec<String>, Vec<u8>) {
+        let mut lines = Vec::new();
+        let mut pack = Vec::new();
+        let mut buf = response;
+        while buf.len() >= 4 {
+            let len = usize::from_str_radix(std::str::from_utf8(&buf[..4]).unwrap(), 16).unwrap();
+            if len < 4 {
+                lines.push(format!("{:04x}", len));
+                buf = &buf[4..];
+                continue;
+            }
+            let data = &buf[4..len];
+            if data[0] == 1 {
+                pack.extend_from_slice(&data[1..]);
+            } else {
+                lines.push(String::from_utf8_lossy(data).trim_end().to_string());
+            }
+            buf = &buf[len..];
+        }
+        (lines, pack)
+    }
+
+    /// Indexes `pack` into a fresh repo, returning it.
+    fn unpack(dir: &Path, pack: &[u8]) -> Repository {
+        let repo = Repository::init_bare(dir).unwrap();
+        {
+            let odb = repo.odb().unwrap();
+            let mut writer = odb.packwriter().unwrap();
+            std::io::Write::write_all(&mut writer, pack).unwrap();
+            writer.commit().unwrap();
+        }
+        repo
+    }
+
+    #[test]
+    fn advertises_refs() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let (repo, commits) = repo_with_commits(&root.join("empty"), 0);
+        let (lines, _) = split_response(&advertise(&repo, false).unwrap());
+        assert_eq!(lines[0], "# service=git-upload-pack");
+        assert!(lines[2].starts_with(&format!("{} capabilities^{{}}\0", Oid::zero())));
+        assert!(commits.is_empty());
+
+        let (repo, commits) = repo_with_commits(&root.join("repo"), 2);
+        let (lines, _) = split_response(&advertise(&repo, false).unwrap());
+        assert!(lines[2].starts_with(&format!("{} HEAD\0multi_ack_detailed", commits[1])));
+        assert!(lines[2].ends_with("symref=HEAD:refs/heads/master"));
+        assert_eq!(lines[3], format!("{} refs/heads/master", commits[1]));
+        assert_eq!(lines[4], "0000");
+
+        let (lines, _) = split_response(&advertise(&repo, true).unwrap());
+        assert_eq!(lines[0], "version 2");
+        assert!(lines.contains(&String::from("fetch")));
+
+        let (lines, _) = split_response(&serve_v2(&repo, &request(&["command=ls-refs", "agent=git/2", "0001", "symrefs", "ref-prefix HEAD", "0000"])).1.unwrap());
+        assert_eq!(lines, [format!("{} HEAD symref-target:refs/heads/master", commits[1]), String::from("0000")]);
+    }
+
+    #[test]
+    fn serves_clones_and_incremental_fetches() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let (repo, commits) = repo_with_commits(&root.join("repo"), 3);
+
+        // A v0 clone: nothing to negotiate
+        let want = format!("want {} multi_ack_detailed no-done side-band-64k ofs-delta agent=git/2", commits[2]);
+        let (lines, pack) = split_response(&serve_v0(&repo, &request(&[&want, "0000", "done"])).unwrap());
+        assert_eq!(lines[0], "NAK");
+        let clone = unpack(&root.join("clone"), &pack);
+        assert!(clone.find_commit(commits[0]).is_ok());
+
+        // A v0 fetch that's ready after one round, thanks to no-done
+        let have = format!("have {}", commits[0]);
+        let (lines, pack) = split_response(&serve_v0(&repo, &request(&[&want, "0000", &have, "0000"])).unwrap());
+        assert_eq!(lines[..4], [format!("ACK {} common", commits[0]), format!("ACK {} ready", commits[0]), String::from("NAK"), format!("ACK {}", commits[0])]);
+        let fetched = unpack(&root.join("fetched"), &pack);
+        assert!(fetched.find_commit(commits[2]).is_ok());
+        assert!(fetched.find_commit(commits[0]).is_err());
+
+        // Nothing in common yet: the client has to keep going
+        let unknown = format!("have {}", Oid::hash_object(ObjectType::Blob, b"unknown").unwrap());
+        let (lines, pack) = split_response(&serve_v0(&repo, &request(&[&want, "0000", &unknown, "0000"])).unwrap());
+        assert_eq!(lines, ["NAK"]);
+        assert!(pack.is_empty());
+
+        // A v2 fetch
+        let want = format!("want {}", commits[2]);
+        let response = serve_v2(&repo, &request(&["command=fetch", "agent=git/2", "0001", "ofs-delta", &want, &have, "0000"])).1.unwrap();
+        let (lines, pack) = split_response(&response);
+        assert_eq!(lines, ["acknowledgments", &format!("ACK {}", commits[0]), "ready", "0001", "packfile", "0000"]);
+        assert!(unpack(&root.join("fetched-v2"), &pack).find_commit(commits[1]).is_ok());
+
+        let not_ours = format!("want {}", Oid::hash_object(ObjectType::Blob, b"not ours").unwrap());
+        let refused = serve_v2(&repo, &request(&["command=fetch", "0001", &not_ours, "done", "0000"])).1;
+        assert!(refused.unwrap_err().downcast::<ClientError>().is_ok());
+    }
+}
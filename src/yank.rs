@@ -0,0 +1,78 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::auth::Identity;
+use crate::config::AppConfig;
+use crate::index::{self, NotFound};
+use crate::index_writer::{Edit, IndexWriter};
+use crate::owners;
+
+
+/// Marks `name` `version` yanked (or not) in `repo`'s index, as `cargo yank`
+/// (`DELETE /repo/<repo>/api/v1/crates/<name>/<version>/yank`) and `cargo yank --undo`
+/// (`PUT .../unyank`) ask. Only the crate's owners may do either.
+pub(crate) fn set_yanked(config: &AppConfig, writer: &IndexWriter, repo: &str, name: &str, version: &str, yanked: bool, who: Option<&Identity>) -> Result<()> {
+    let owners_file = owners::path(&config.git, repo);
+    let (name, version, repo, who) = (name.to_string(), version.to_string(), repo.to_string(), who.cloned());
+    let action = if yanked { "Yank" } else { "Unyank" };
+    writer.mutate(format!("{} {} {}", action, name, version), Box::new(move |index_root: &Path| {
+        let path = index::index_path(&name);
+        let not_found = || NotFound(format!("{} {} isn't in {}", name, version, repo));
+        let contents = match std::fs::read_to_string(index_root.join(&path)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => anyhow::bail!(not_found()),
+            Err(e) => return Err(e).context("Reading index file"),
+        };
+
+        let mut published_name = None;
+        let mut lines = Vec::new();
+        for line in contents.lines().filter(|l| ! l.trim().is_empty()) {
+            let mut entry = json::parse(line).context("Index line is not valid JSON")?;
+            if entry["vers"] == version.as_str() {
+                published_name = entry["name"].as_str().map(str::to_string);
+                entry["yanked"] = yanked.into();
+                lines.push(entry.dump());
+            } else {
+                lines.push(line.to_string());
+            }
+        }
+        let published_name = published_name.ok_or_else(not_found)?;
+        let owners = owners::owners_of(&owners_file, &published_name)?;
+        owners::check_owner(&published_name, &owners, who.as_ref(), if yanked { "yank it" } else { "unyank it" })?;
+
+        let mut contents = lines.join("\n");
+        contents.push('\n');
+        Ok(vec![Edit { path, contents: Some(contents.into_bytes()) }])
+    }))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_owners_yank() {
+        let registry = crate::app::test::TestRegistry::new();
+        let (config, writer) = (&registry.config, registry.writer());
+
+        let line = |vers: &str| format!(r#"{{"name":"Foo","vers":"{}","deps":[],"cksum":"{}","features":{{}},"yanked":false}}"#, vers, "0".repeat(64));
+        let contents = format!("{}\n{}\n", line("1.0.0"), line("1.1.0"));
+        writer.mutate("Publish Foo", Box::new(move |_: &Path| Ok(vec![Edit { path: index::index_path("Foo"), contents: Some(contents.into_bytes()) }]))).unwrap();
+        let alice = Identity { name: String::from("alice"), teams: vec![], admin: false };
+        let bob = Identity { name: String::from("bob"), teams: vec![], admin: false };
+        owners::record_publisher(&owners::path(&config.git, "repo"), "Foo", true, Some(&alice)).unwrap();
+
+        let refused = set_yanked(config, &writer, "repo", "foo", "1.0.0", true, Some(&bob)).unwrap_err();
+        assert_eq!(refused.downcast::<crate::auth::NotAllowed>().unwrap().0, "Only Foo's owners (alice) may yank it");
+        set_yanked(config, &writer, "repo", "foo", "1.0.0", true, Some(&alice)).unwrap();
+        let yanked = |vers: &str| index::find_version(&config.git.path.join("repo"), "foo", vers).unwrap().unwrap().yanked;
+        assert!(yanked("1.0.0") && ! yanked("1.1.0"));
+        set_yanked(config, &writer, "repo", "foo", "1.0.0", false, Some(&alice)).unwrap();
+        assert!(! yanked("1.0.0"));
+
+        assert!(set_yanked(config, &writer, "repo", "foo", "2.0.0", true, Some(&alice)).unwrap_err().is::<NotFound>());
+        assert!(set_yanked(config, &writer, "repo", "bar", "1.0.0", true, Some(&alice)).unwrap_err().is::<NotFound>());
+    }
+}
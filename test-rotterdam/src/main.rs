@@ -41,7 +41,7 @@ fn start_server() -> RotterdamServerInstance {
 
     let working_dir = tempfile::tempdir().expect("Setting up temp directory");
     let workdir_path = PathBuf::from("/tmp/rotterdam-runtime-path"); // working_dir.path();
-    if let Err(_) = std::fs::remove_dir_all(&workdir_path) {
+    if std::fs::remove_dir_all(&workdir_path).is_err() {
         assert!(!workdir_path.exists());
     }
     std::fs::create_dir_all(&workdir_path).expect("Setting up runtime path");
@@ -119,11 +119,9 @@ fn test_data_path<P: Into<PathBuf>>(rel_path_from_test_folder: P) -> PathBuf {
         "Source file to copy does not exist: {}",
         source_file.to_string_lossy()
     );
-    let source_file = source_file
-        .canonicalize()
-        .expect("Canonicalizing source file for copy");
-
     source_file
+        .canonicalize()
+        .expect("Canonicalizing source file for copy")
 }
 
 fn lib_project_dir() -> (tempfile::TempDir, PathBuf) {
@@ -205,15 +203,11 @@ fn setup_login_credentials(cargo_home: &Path, library_path: &Path, token: &str)
         .stdin
         .as_ref()
         .expect("stdin of login child")
-        .write_all(&token.as_bytes())
+        .write_all(token.as_bytes())
         .unwrap();
     assert!(login_child.wait().unwrap().success());
 }
 
-fn publish() {
-    unimplemented!("publish");
-}
-
 // #[test]
 fn main() {
     pretty_env_logger::init_timed();
@@ -229,7 +223,5 @@ fn main() {
 
     setup_login_credentials(&p, &p, &token);
 
-    // publish();
-
     log::info!("Server status: {:?}", server.process.try_wait());
 }